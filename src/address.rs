@@ -0,0 +1,103 @@
+//! Typed flash addresses
+//!
+//! The low-level functions in [`crate::flash`] take offsets relative to the
+//! beginning of the flash area, while pointers to data in flash are
+//! addresses in the XIP window. Passing one where the other is expected
+//! compiles fine and writes to the wrong place, or fails the bounds check.
+//!
+//! [`FlashOffset`] and [`XipAddress`] keep the two apart. Functions taking
+//! an [`impl FlashAddress`](FlashAddress) accept both, and translate XIP
+//! addresses internally:
+//!
+//! ```ignore
+//! static DATA: [u8; 4096] = [0xff; 4096];
+//! let addr = XipAddress::from_ptr(DATA.as_ptr());
+//! cortex_m::interrupt::free(|_cs| unsafe {
+//!     flash::flash_range_erase_at(addr, 4096, true)
+//! })?;
+//! ```
+
+use crate::error::FlashError;
+use crate::xip::{XIP_BASE, XIP_NOCACHE_NOALLOC_BASE, XIP_SIZE};
+
+/// Offset relative to the beginning of the flash area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlashOffset(pub u32);
+
+/// Address of flash contents in the XIP window
+///
+/// Both the cached alias at [`XIP_BASE`] and the uncached alias at
+/// [`XIP_NOCACHE_NOALLOC_BASE`] are accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct XipAddress(pub u32);
+
+impl FlashOffset {
+    /// The address of this offset in the cached XIP alias
+    pub const fn to_xip(self) -> XipAddress {
+        XipAddress(XIP_BASE + self.0)
+    }
+}
+
+impl XipAddress {
+    /// The XIP address of data pointed to by `ptr`
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        XipAddress(ptr as u32)
+    }
+
+    /// Pointer to the flash contents at this address
+    pub const fn as_ptr(self) -> *const u8 {
+        self.0 as *const u8
+    }
+
+    /// The flash offset this address maps to
+    ///
+    /// Returns `None` if the address is outside of the XIP aliases
+    /// mapping flash contents.
+    pub const fn to_offset(self) -> Option<FlashOffset> {
+        if self.0 >= XIP_BASE && self.0 - XIP_BASE < XIP_SIZE {
+            Some(FlashOffset(self.0 - XIP_BASE))
+        } else if self.0 >= XIP_NOCACHE_NOALLOC_BASE && self.0 - XIP_NOCACHE_NOALLOC_BASE < XIP_SIZE
+        {
+            Some(FlashOffset(self.0 - XIP_NOCACHE_NOALLOC_BASE))
+        } else {
+            None
+        }
+    }
+}
+
+impl From<FlashOffset> for XipAddress {
+    fn from(offset: FlashOffset) -> Self {
+        offset.to_xip()
+    }
+}
+
+impl TryFrom<XipAddress> for FlashOffset {
+    type Error = FlashError;
+
+    fn try_from(addr: XipAddress) -> Result<Self, FlashError> {
+        addr.to_offset().ok_or(FlashError::OutOfBounds)
+    }
+}
+
+/// A location in flash, either as [`FlashOffset`] or [`XipAddress`]
+pub trait FlashAddress: Copy {
+    /// Translate to an offset relative to the beginning of the flash area
+    ///
+    /// Fails with [`FlashError::OutOfBounds`] for addresses outside of
+    /// the XIP window.
+    fn flash_offset(self) -> Result<FlashOffset, FlashError>;
+}
+
+impl FlashAddress for FlashOffset {
+    fn flash_offset(self) -> Result<FlashOffset, FlashError> {
+        Ok(self)
+    }
+}
+
+impl FlashAddress for XipAddress {
+    fn flash_offset(self) -> Result<FlashOffset, FlashError> {
+        self.try_into()
+    }
+}
@@ -0,0 +1,161 @@
+//! Storage blocks reserved in the program's flash image
+//!
+//! The usual way to reserve flash for data is a sector aligned `static`
+//! which is placed in flash by the linker. Accessing it is subtle: the
+//! compiler assumes that a non-`mut` static never changes, and writes
+//! need the offset relative to the beginning of flash, not the address of
+//! the static.
+//!
+//! [`flash_block!`](crate::flash_block) declares such a static, and
+//! [`FlashBlock`] provides reads and writes which handle both:
+//!
+//! ```ignore
+//! rp2040_flash::flash_block!(static CONFIG: 2);
+//!
+//! let mut buf = [0u8; 16];
+//! CONFIG.read(0, &mut buf)?;
+//! buf[0] = buf[0].wrapping_add(1);
+//! unsafe { CONFIG.write(0, &buf, true)? };
+//! ```
+//!
+//! The block is part of the program image, so flashing a new program
+//! resets its contents to 0xff.
+
+use crate::address::{FlashOffset, XipAddress};
+use crate::error::FlashError;
+use crate::flash::{self, SECTOR_SIZE};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Declare a [`FlashBlock`] of `N` sectors
+///
+/// `flash_block!(static NAME: N);` expands to a static placed in the
+/// `.rodata.flash_block` section, which the `cortex-m-rt` linker script
+/// puts into flash. Use the section name in a custom linker script to
+/// place the blocks at a fixed location instead.
+///
+/// The block is initialized as erased, i.e. to 0xff.
+#[macro_export]
+macro_rules! flash_block {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $sectors:expr) => {
+        $(#[$attr])*
+        #[link_section = ".rodata.flash_block"]
+        $vis static $name: $crate::block::FlashBlock<{ $sectors }> =
+            $crate::block::FlashBlock::new();
+    };
+}
+
+/// `N` sectors of flash, located in the program's flash image
+///
+/// Declare it with [`flash_block!`](crate::flash_block). A `FlashBlock`
+/// stored anywhere but in flash fails all reads and writes with
+/// [`FlashError::OutOfBounds`].
+#[repr(C, align(4096))]
+pub struct FlashBlock<const N: usize> {
+    data: UnsafeCell<[[u8; SECTOR_SIZE as usize]; N]>,
+}
+
+// Safety: the contents are only modified by `write` and `erase`, whose
+// callers guarantee that nothing accesses flash concurrently
+unsafe impl<const N: usize> Sync for FlashBlock<N> {}
+
+impl<const N: usize> FlashBlock<N> {
+    /// Size of the block in bytes
+    pub const LEN: u32 = N as u32 * SECTOR_SIZE;
+
+    /// An erased block
+    ///
+    /// Only useful as initializer of a static in flash, see
+    /// [`flash_block!`](crate::flash_block).
+    pub const fn new() -> Self {
+        FlashBlock {
+            data: UnsafeCell::new([[0xff; SECTOR_SIZE as usize]; N]),
+        }
+    }
+
+    /// Address of the block in the XIP window
+    pub fn xip_address(&self) -> XipAddress {
+        XipAddress::from_ptr(self.data.get())
+    }
+
+    /// Offset of the block, relative to the beginning of the flash area
+    pub fn offset(&self) -> Result<FlashOffset, FlashError> {
+        self.xip_address().try_into()
+    }
+
+    /// Check that `len` bytes at `offset` are part of the block and return
+    /// their flash offset
+    fn range(&self, offset: u32, len: usize) -> Result<u32, FlashError> {
+        match offset.checked_add(len as u32) {
+            Some(end) if len <= Self::LEN as usize && end <= Self::LEN => {
+                Ok(self.offset()?.0 + offset)
+            }
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Read `buf.len()` bytes at `offset` within the block
+    ///
+    /// The bytes are read with volatile loads, so they reflect previous
+    /// writes even though the block is declared as a non-`mut` static.
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.range(offset, buf.len())?;
+        compiler_fence(Ordering::SeqCst);
+        let src = (self.data.get() as *const u8).wrapping_add(offset as usize);
+        for (i, b) in buf.iter_mut().enumerate() {
+            // Safety: the range is part of the block, checked above
+            *b = unsafe { core::ptr::read_volatile(src.add(i)) };
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` within the block, without alignment
+    /// requirements
+    ///
+    /// Other bytes in the affected sectors are preserved, see
+    /// [`flash::flash_write_unaligned`].
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts are disabled on the current core during the write. The
+    /// other core must not access flash and DMA must not access flash
+    /// while this is running.
+    pub unsafe fn write(
+        &self,
+        offset: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let addr = self.range(offset, data.len())?;
+        compiler_fence(Ordering::SeqCst);
+        let result = crate::cs::free(|| flash::flash_write_unaligned(addr, data, use_boot2));
+        compiler_fence(Ordering::SeqCst);
+        result
+    }
+
+    /// Erase the whole block
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`write`](Self::write).
+    pub unsafe fn erase(&self, use_boot2: bool) -> Result<(), FlashError> {
+        let addr = self.range(0, Self::LEN as usize)?;
+        compiler_fence(Ordering::SeqCst);
+        let result =
+            crate::cs::free(|| flash::flash_range_erase_checked(addr, Self::LEN, use_boot2));
+        compiler_fence(Ordering::SeqCst);
+        result
+    }
+}
+
+impl<const N: usize> Default for FlashBlock<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
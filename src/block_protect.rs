@@ -0,0 +1,191 @@
+//! Hardware block protection
+//!
+//! Flash chips can protect part of the array against program and erase
+//! using the block protect bits of the status registers. Unlike the
+//! software protection of [`protect`](crate::protect), this also stops
+//! code not using this crate, and persists across resets.
+//!
+//! The encoding of the protected range differs between vendors:
+//!
+//! - Winbond and GigaDevice: BP0-BP2, TB and SEC in status register 1,
+//!   CMP in status register 2. Ranges from 4 KiB to the whole chip, at the
+//!   top or bottom of the array, or their complement, can be protected.
+//! - Macronix and ISSI: BP0-BP3 in the status register. Ranges of 64 KiB
+//!   blocks at the top of the array can be protected.
+//!
+//! Other chips are reported as [`FlashError::Unsupported`].
+//!
+//! The block protect bits can't be changed while the status register is
+//! locked, see [`status_lock`](crate::status_lock).
+
+use crate::error::FlashError;
+use crate::flash;
+use crate::protect::Region;
+
+const BP_SHIFT: u8 = 2;
+const TB: u8 = 1 << 5;
+const SEC: u8 = 1 << 6;
+const CMP: u8 = 1 << 6;
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    /// BP0-2, TB, SEC in SR1, CMP in SR2
+    Winbond,
+    /// BP0-3 in SR1, top only
+    Macronix,
+}
+
+impl Scheme {
+    fn detect(jedec_id: u32) -> Option<Scheme> {
+        match jedec_id >> 16 {
+            0xef | 0xc8 => Some(Scheme::Winbond),
+            0xc2 | 0x9d => Some(Scheme::Macronix),
+            _ => None,
+        }
+    }
+
+    /// Bits of SR1 and SR2 holding the protection configuration
+    fn masks(self) -> (u8, u8) {
+        match self {
+            Scheme::Winbond => (0b111 << BP_SHIFT | TB | SEC, CMP),
+            Scheme::Macronix => (0b1111 << BP_SHIFT, 0),
+        }
+    }
+
+    /// The range protected by the given status register values
+    fn decode(self, size: u32, sr1: u8, sr2: u8) -> Region {
+        match self {
+            Scheme::Winbond => {
+                let bp = (sr1 >> BP_SHIFT) & 0b111;
+                let len = match bp {
+                    0 => 0,
+                    7 => size,
+                    _ if sr1 & SEC != 0 => (4096 << (bp - 1)).min(32768),
+                    _ => ((size / 64).max(65536) << (bp - 1)).min(size),
+                };
+                let top = sr1 & TB == 0;
+                if sr2 & CMP == 0 {
+                    let start = if top { size - len } else { 0 };
+                    Region { start, len }
+                } else {
+                    let start = if top { 0 } else { len };
+                    Region {
+                        start,
+                        len: size - len,
+                    }
+                }
+            }
+            Scheme::Macronix => {
+                let bp = (sr1 >> BP_SHIFT) & 0b1111;
+                let len = match bp {
+                    0 => 0,
+                    _ => 65536u32
+                        .checked_shl(bp as u32 - 1)
+                        .map_or(size, |len| len.min(size)),
+                };
+                Region {
+                    start: size - len,
+                    len,
+                }
+            }
+        }
+    }
+
+    /// Status register values protecting exactly `region`
+    fn encode(self, size: u32, region: Region) -> Option<(u8, u8)> {
+        let normalize = |region: Region| {
+            if region.len == 0 {
+                Region { start: 0, len: 0 }
+            } else {
+                region
+            }
+        };
+        let (mask1, mask2) = self.masks();
+        // Prefer CMP = 0, and the lowest BP value
+        (0..=mask2)
+            .filter(|sr2| sr2 & !mask2 == 0)
+            .flat_map(|sr2| {
+                (0..=mask1)
+                    .filter(|sr1| sr1 & !mask1 == 0)
+                    .map(move |sr1| (sr1, sr2))
+            })
+            .find(|&(sr1, sr2)| normalize(self.decode(size, sr1, sr2)) == normalize(region))
+    }
+}
+
+unsafe fn detect(use_boot2: bool) -> Result<(Scheme, u32), FlashError> {
+    let scheme = Scheme::detect(flash::flash_jedec_id(use_boot2)).ok_or(FlashError::Unsupported)?;
+    let size = flash::flash_size(use_boot2).ok_or(FlashError::Unsupported)?;
+    Ok((scheme, size))
+}
+
+/// Read the range currently protected by the block protect bits
+///
+/// A `len` of 0 means that nothing is protected.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn protected_range(use_boot2: bool) -> Result<Region, FlashError> {
+    let (scheme, size) = detect(use_boot2)?;
+    let sr1 = flash::read_status_register(1, use_boot2);
+    let sr2 = match scheme {
+        Scheme::Winbond => flash::read_status_register(2, use_boot2),
+        Scheme::Macronix => 0,
+    };
+    Ok(scheme.decode(size, sr1, sr2))
+}
+
+/// Protect exactly `region`, removing any other block protection
+///
+/// Returns [`FlashError::Unsupported`] if the chip can't protect this
+/// range, see the [module documentation](self) for the supported ranges.
+/// The new configuration is read back, and
+/// [`FlashError::StatusWriteFailed`] is returned if it didn't take effect,
+/// e.g. because the status register is locked.
+///
+/// The configuration is non-volatile. Other status register bits,
+/// including QE, are preserved.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn set_protected_range(region: Region, use_boot2: bool) -> Result<(), FlashError> {
+    let (scheme, size) = detect(use_boot2)?;
+    let (bits1, bits2) = scheme.encode(size, region).ok_or(FlashError::Unsupported)?;
+    let (mask1, mask2) = scheme.masks();
+    debug!(
+        "protecting {:#x} len {:#x}: {:#x} {:#x}",
+        region.start, region.len, bits1, bits2
+    );
+    let sr1 = (flash::read_status_register(1, use_boot2) & !mask1) | bits1;
+    match scheme {
+        Scheme::Winbond => {
+            let sr2 = (flash::read_status_register(2, use_boot2) & !mask2) | bits2;
+            flash::write_status(0x01, &[sr1, sr2], use_boot2)?;
+        }
+        Scheme::Macronix => flash::write_status(0x01, &[sr1], use_boot2)?,
+    }
+    let protected = protected_range(use_boot2)?;
+    if protected != region && !(protected.len == 0 && region.len == 0) {
+        return Err(FlashError::StatusWriteFailed);
+    }
+    Ok(())
+}
+
+/// Remove all block protection
+///
+/// # Safety
+///
+/// Same as for [`set_protected_range`].
+pub unsafe fn unprotect(use_boot2: bool) -> Result<(), FlashError> {
+    set_protected_range(Region { start: 0, len: 0 }, use_boot2)
+}
@@ -0,0 +1,82 @@
+//! Copy of the 2nd stage boot loader
+//!
+//! After a flash operation, XIP has to be re-initialized. The boot ROM
+//! only configures a slow, generic read mode, so by default, functions
+//! called with `use_boot2 = true` copy the 2nd stage boot loader from the
+//! start of flash to the stack and call it, for each operation.
+//!
+//! A [`Boot2`] holds a copy made once, either from flash with
+//! [`Boot2::read`] or from a known image like the one linked into the
+//! `.boot2` section. It can be passed by reference to the
+//! `*_with_boot2` functions in [`flash`](crate::flash).
+//!
+//! The copy is executed while XIP is disabled, so it must be located in
+//! RAM, e.g. on the stack or in a `static mut`. Copies in flash, like a
+//! `static` initialized at compile time, are rejected when used.
+//!
+//! Only available on the RP2040, as the RP2350 has no 2nd stage boot loader.
+
+/// Size of the 2nd stage boot loader, including its CRC
+pub const BOOT2_SIZE: usize = 256;
+
+/// A validated copy of the 2nd stage boot loader
+#[derive(Clone)]
+#[repr(C, align(4))]
+pub struct Boot2([u8; BOOT2_SIZE]);
+
+impl Boot2 {
+    /// Copy the 2nd stage boot loader from the start of flash
+    ///
+    /// Returns `None` if the CRC doesn't match, e.g. for programs without
+    /// a 2nd stage boot loader loaded to RAM by a debugger.
+    pub fn read() -> Option<Self> {
+        let mut bytes = [0u8; BOOT2_SIZE];
+        // Safety: the start of flash is inside the XIP window, which is always readable
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                crate::xip::XIP_BASE as *const u8,
+                bytes.as_mut_ptr(),
+                BOOT2_SIZE,
+            )
+        };
+        Self::from_bytes(&bytes)
+    }
+
+    /// Use a 2nd stage boot loader image, e.g. from the `rp2040-boot2` crate
+    ///
+    /// Returns `None` if the CRC in the last 4 bytes doesn't match.
+    pub fn from_bytes(bytes: &[u8; BOOT2_SIZE]) -> Option<Self> {
+        let (code, crc) = bytes.split_at(BOOT2_SIZE - 4);
+        if crc32_mpeg2(code).to_le_bytes() != crc {
+            warn!("2nd stage boot loader CRC mismatch");
+            return None;
+        }
+        Some(Boot2(*bytes))
+    }
+
+    /// The image, as words
+    pub(crate) fn words(&self) -> &[u32; BOOT2_SIZE / 4] {
+        assert!(
+            crate::ram::is_flash_independent(self as *const Self as *const ()),
+            "Boot2 is not located in RAM"
+        );
+        // Safety: `Boot2` is aligned to 4 bytes, and any bit pattern is a valid `u32`
+        unsafe { &*(self.0.as_ptr() as *const [u32; BOOT2_SIZE / 4]) }
+    }
+}
+
+/// CRC-32/MPEG-2, as checked by the boot ROM
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
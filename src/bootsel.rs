@@ -0,0 +1,130 @@
+//! Reboot into the USB boot loader
+//!
+//! Recovery and factory reset paths often wipe the application and then
+//! hand over to the boot ROM's USB boot loader (BOOTSEL mode), so a new
+//! program can be copied to the drive it provides or loaded with
+//! `picotool`. [`erase_and_enter_bootsel`] does both in one step, running
+//! from RAM after the erase, so it works even if the erased range
+//! contains the running program.
+
+use crate::error::FlashError;
+use crate::flash;
+use crate::rom;
+use core::convert::Infallible;
+
+/// Disable the USB mass storage interface of the boot loader
+pub const DISABLE_MASS_STORAGE: u32 = 1;
+/// Disable the USB PICOBOOT interface of the boot loader
+pub const DISABLE_PICOBOOT: u32 = 2;
+
+/// Number of GPIOs usable as activity pin
+#[cfg(not(feature = "rp235x"))]
+const NUM_PINS: u8 = 30;
+/// Number of GPIOs usable as activity pin
+#[cfg(feature = "rp235x")]
+const NUM_PINS: u8 = 48;
+
+/// Errors preventing a reset into the USB boot loader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A flash operation failed
+    Flash(FlashError),
+    /// The activity pin is not a GPIO of this chip
+    InvalidPin(u8),
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Function and arguments entering the USB boot loader
+fn usb_boot_call(
+    activity_pin: Option<u8>,
+    disable_interface_mask: u32,
+) -> Result<(usize, [u32; 4]), Error> {
+    if let Some(pin) = activity_pin.filter(|&pin| pin >= NUM_PINS) {
+        return Err(Error::InvalidPin(pin));
+    }
+    #[cfg(not(feature = "rp235x"))]
+    {
+        let pin_mask = activity_pin.map_or(0, |pin| 1 << pin);
+        Ok((
+            rom::reset_to_usb_boot() as usize,
+            [pin_mask, disable_interface_mask, 0, 0],
+        ))
+    }
+    #[cfg(feature = "rp235x")]
+    {
+        // REBOOT2_FLAG_REBOOT_TYPE_BOOTSEL | REBOOT2_FLAG_NO_RETURN_ON_SUCCESS
+        const FLAGS: u32 = 0x0002 | 0x0100;
+        // BOOTSEL_FLAG_GPIO_PIN_SPECIFIED
+        const PIN_SPECIFIED: u32 = 0x20;
+        let (flags, pin) = match activity_pin {
+            Some(pin) => (disable_interface_mask | PIN_SPECIFIED, pin as u32),
+            None => (disable_interface_mask, 0),
+        };
+        Ok((rom::reboot() as usize, [FLAGS, 10, flags, pin]))
+    }
+}
+
+/// Reset the chip into the USB boot loader
+///
+/// `activity_pin` is a GPIO toggled by the boot loader on USB activity,
+/// e.g. the LED of a board. `disable_interface_mask` is a combination of
+/// [`DISABLE_MASS_STORAGE`] and [`DISABLE_PICOBOOT`], or 0 to enable both
+/// interfaces.
+///
+/// Only returns if `activity_pin` isn't a GPIO of this chip.
+pub fn reset_to_usb_boot(
+    activity_pin: Option<u8>,
+    disable_interface_mask: u32,
+) -> Result<Infallible, Error> {
+    let (f, [a0, a1, a2, a3]) = usb_boot_call(activity_pin, disable_interface_mask)?;
+    // Safety: `f` is the ROM function for this chip, which takes up to
+    // four arguments and resets the chip
+    unsafe {
+        let f: unsafe extern "C" fn(u32, u32, u32, u32) = core::mem::transmute(f);
+        f(a0, a1, a2, a3);
+    }
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Erase `len` bytes at `addr`, then reset into the USB boot loader
+///
+/// `addr` is relative to the beginning of the flash area. `addr` and `len`
+/// must be multiples of 4096. The range may contain the running program,
+/// e.g. to make sure it isn't started again, as nothing returns to flash
+/// after the erase. See [`reset_to_usb_boot`] for the other arguments.
+///
+/// Only returns if the range overlaps a region protected using
+/// [`protect`](crate::protect), or if `activity_pin` isn't a GPIO of this
+/// chip. Nothing is erased then.
+///
+/// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+/// is used to re-initialize the XIP engine after the erase.
+///
+/// # Safety
+///
+/// Interrupts are disabled and nothing but this function may access
+/// flash until the chip is reset:
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+///
+/// # Panics
+///
+/// Panics if the range isn't sector aligned or exceeds 16 MiB.
+pub unsafe fn erase_and_enter_bootsel(
+    addr: u32,
+    len: u32,
+    use_boot2: bool,
+    activity_pin: Option<u8>,
+    disable_interface_mask: u32,
+) -> Result<Infallible, Error> {
+    let (f, args) = usb_boot_call(activity_pin, disable_interface_mask)?;
+    Ok(flash::erase_and_call(addr, len, use_boot2, f, args)?)
+}
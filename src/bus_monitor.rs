@@ -0,0 +1,69 @@
+//! Bus access diagnostics using the BUSCTRL performance counters
+//!
+//! A flash operation fails in confusing ways if another bus master (the
+//! other core, or DMA) accesses flash while XIP is disabled. [`monitor`]
+//! counts the accesses to the XIP/SSI bus port during a flash operation,
+//! turning such problems into a concrete report.
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// BUSCTRL, RP2040 datasheet 2.1.5
+const BUSCTRL_BASE: usize = 0x4003_0000;
+const PERFCTR0: *mut u32 = (BUSCTRL_BASE + 0x08) as *mut u32;
+const PERFSEL0: *mut u32 = (BUSCTRL_BASE + 0x0c) as *mut u32;
+const PERFCTR1: *mut u32 = (BUSCTRL_BASE + 0x10) as *mut u32;
+const PERFSEL1: *mut u32 = (BUSCTRL_BASE + 0x14) as *mut u32;
+
+const EVENT_XIP_MAIN_CONTESTED: u32 = 16;
+const EVENT_XIP_MAIN: u32 = 17;
+
+/// Accesses to the XIP bus port observed during an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusReport {
+    /// All accesses, including the SSI register accesses of the operation itself
+    pub accesses: u32,
+    /// Accesses which had to wait for another master using the port
+    ///
+    /// A nonzero value means that some other master accessed flash or
+    /// the SSI while the operation was running.
+    pub contested: u32,
+}
+
+impl BusReport {
+    /// Returns `true` if another bus master competed for the XIP port
+    pub fn other_master_detected(&self) -> bool {
+        self.contested > 0
+    }
+}
+
+/// Run `f`, counting accesses to the XIP bus port while it executes
+///
+/// As the flash operation's own SSI accesses are counted as well, the
+/// absolute number of `accesses` is only meaningful compared to a
+/// baseline measured while nothing else is active. Concurrent accesses by
+/// other masters show up reliably only as `contested` accesses.
+///
+/// The counters saturate at 2^24 - 1.
+///
+/// # Safety
+///
+/// Uses performance counters 0 and 1, which must not be in use elsewhere.
+pub unsafe fn monitor<R>(f: impl FnOnce() -> R) -> (R, BusReport) {
+    write_volatile(PERFSEL0, EVENT_XIP_MAIN);
+    write_volatile(PERFSEL1, EVENT_XIP_MAIN_CONTESTED);
+    // Any write clears the counters
+    write_volatile(PERFCTR0, 0);
+    write_volatile(PERFCTR1, 0);
+    let result = f();
+    let report = BusReport {
+        accesses: read_volatile(PERFCTR0),
+        contested: read_volatile(PERFCTR1),
+    };
+    if report.other_master_detected() {
+        warn!(
+            "{} contested XIP accesses during flash operation",
+            report.contested
+        );
+    }
+    (result, report)
+}
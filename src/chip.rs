@@ -0,0 +1,233 @@
+//! Database of flash chips commonly found on RP2040 boards
+//!
+//! Chips are identified by their JEDEC ID, as returned by
+//! [`flash_jedec_id`](crate::flash::flash_jedec_id).
+
+use crate::sfdp::BasicFlashParameters;
+use core::time::Duration;
+
+/// Properties of a known flash chip
+#[derive(Debug)]
+pub struct ChipInfo {
+    /// Three-byte JEDEC ID, e.g. 0xEF7015
+    pub jedec_id: u32,
+    /// Part name
+    pub name: &'static str,
+    /// Capacity in bytes
+    pub size: u32,
+    /// Register reporting failed program and erase operations, if any
+    pub fail_flags: Option<FailFlags>,
+    /// Worst-case operation times from the datasheet
+    pub timing: Timing,
+}
+
+impl ChipInfo {
+    /// Worst-case duration of `op` on this chip
+    pub fn timing_for(&self, op: Operation) -> Duration {
+        self.timing.worst_case(op, self.size)
+    }
+}
+
+/// Location of the program/erase failure bits of a flash chip
+#[derive(Debug, Clone, Copy)]
+pub struct FailFlags {
+    /// Command reading the register containing the failure bits
+    pub read_cmd: u8,
+    /// Bit mask of the program failure flag
+    pub program_fail: u8,
+    /// Bit mask of the erase failure flag
+    pub erase_fail: u8,
+    /// Command clearing the failure bits, if they are sticky
+    pub clear_cmd: Option<u8>,
+}
+
+/// Flash operations with a specified worst-case duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Erase a 4 KiB sector
+    SectorErase,
+    /// Erase a 32 KiB block
+    BlockErase32K,
+    /// Erase a 64 KiB block
+    BlockErase64K,
+    /// Program a 256 byte page
+    PageProgram,
+    /// Erase the whole chip
+    ChipErase,
+}
+
+/// Worst-case durations of flash operations
+///
+/// The chip erase time is not stored, but bounded by erasing the whole
+/// chip in 64 KiB blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub sector_erase: Duration,
+    pub block_erase_32k: Duration,
+    pub block_erase_64k: Duration,
+    pub page_program: Duration,
+}
+
+impl Timing {
+    /// Generous figures for unknown chips
+    pub const CONSERVATIVE: Timing = Timing::from_ms(500, 2000, 3000, 5);
+
+    const fn from_ms(sector: u64, block_32k: u64, block_64k: u64, page: u64) -> Timing {
+        Timing {
+            sector_erase: Duration::from_millis(sector),
+            block_erase_32k: Duration::from_millis(block_32k),
+            block_erase_64k: Duration::from_millis(block_64k),
+            page_program: Duration::from_millis(page),
+        }
+    }
+
+    /// Worst-case duration of `op` on a chip with `size` bytes
+    pub fn worst_case(&self, op: Operation, size: u32) -> Duration {
+        match op {
+            Operation::SectorErase => self.sector_erase,
+            Operation::BlockErase32K => self.block_erase_32k,
+            Operation::BlockErase64K => self.block_erase_64k,
+            Operation::PageProgram => self.page_program,
+            Operation::ChipErase => self.block_erase_64k * size.div_ceil(0x10000),
+        }
+    }
+
+    /// Raise the figure for `op` if `measured` exceeds it
+    ///
+    /// Use this to refine the figures with durations measured on the
+    /// device, e.g. for unknown chips or in extreme conditions. Figures are
+    /// never lowered, so they stay an upper bound. Chip erase times are
+    /// derived from block erase times and can't be refined.
+    pub fn refine(&mut self, op: Operation, measured: Duration) {
+        let figure = match op {
+            Operation::SectorErase => &mut self.sector_erase,
+            Operation::BlockErase32K => &mut self.block_erase_32k,
+            Operation::BlockErase64K => &mut self.block_erase_64k,
+            Operation::PageProgram => &mut self.page_program,
+            Operation::ChipErase => return,
+        };
+        if measured > *figure {
+            *figure = measured;
+        }
+    }
+}
+
+/// Macronix security register: P_FAIL and E_FAIL, cleared by the next operation
+const MACRONIX_FAIL: FailFlags = FailFlags {
+    read_cmd: 0x2b,
+    program_fail: 1 << 5,
+    erase_fail: 1 << 6,
+    clear_cmd: None,
+};
+
+/// ISSI extended read register: P_ERR and E_ERR, cleared by CLERP
+const ISSI_FAIL: FailFlags = FailFlags {
+    read_cmd: 0x81,
+    program_fail: 1 << 2,
+    erase_fail: 1 << 3,
+    clear_cmd: Some(0x82),
+};
+
+// Maximum values from the datasheets of the respective part families
+const WINBOND_TIMING: Timing = Timing::from_ms(400, 1600, 2000, 3);
+const MACRONIX_TIMING: Timing = Timing::from_ms(400, 1000, 2000, 3);
+const GIGADEVICE_TIMING: Timing = Timing::from_ms(400, 1600, 2000, 3);
+const ISSI_TIMING: Timing = Timing::from_ms(300, 500, 1000, 1);
+const ZETTA_TIMING: Timing = Timing::from_ms(400, 1600, 2000, 3);
+
+const fn chip(
+    jedec_id: u32,
+    name: &'static str,
+    fail_flags: Option<FailFlags>,
+    timing: Timing,
+) -> ChipInfo {
+    ChipInfo {
+        jedec_id,
+        name,
+        // The low byte of the JEDEC ID encodes the capacity as a power of two
+        size: match 1u32.checked_shl(jedec_id & 0xff) {
+            Some(size) => size,
+            None => panic!("invalid capacity"),
+        },
+        fail_flags,
+        timing,
+    }
+}
+
+static CHIPS: &[ChipInfo] = &[
+    chip(0xef4015, "W25Q16JV-IQ", None, WINBOND_TIMING),
+    chip(0xef7015, "W25Q16JV-IM", None, WINBOND_TIMING),
+    chip(0xef4016, "W25Q32JV-IQ", None, WINBOND_TIMING),
+    chip(0xef7016, "W25Q32JV-IM", None, WINBOND_TIMING),
+    chip(0xef4017, "W25Q64JV-IQ", None, WINBOND_TIMING),
+    chip(0xef7017, "W25Q64JV-IM", None, WINBOND_TIMING),
+    chip(0xef4018, "W25Q128JV-IQ", None, WINBOND_TIMING),
+    chip(0xef7018, "W25Q128JV-IM", None, WINBOND_TIMING),
+    chip(0xc22015, "MX25L1606E", Some(MACRONIX_FAIL), MACRONIX_TIMING),
+    chip(0xc22016, "MX25L3233F", Some(MACRONIX_FAIL), MACRONIX_TIMING),
+    chip(0xc22017, "MX25L6433F", Some(MACRONIX_FAIL), MACRONIX_TIMING),
+    chip(
+        0xc22018,
+        "MX25L12833F",
+        Some(MACRONIX_FAIL),
+        MACRONIX_TIMING,
+    ),
+    chip(0xc84015, "GD25Q16C", None, GIGADEVICE_TIMING),
+    chip(0xc84016, "GD25Q32C", None, GIGADEVICE_TIMING),
+    chip(0xc84017, "GD25Q64C", None, GIGADEVICE_TIMING),
+    chip(0xc84018, "GD25Q128C", None, GIGADEVICE_TIMING),
+    chip(0x9d6015, "IS25LP016D", Some(ISSI_FAIL), ISSI_TIMING),
+    chip(0x9d6016, "IS25LP032D", Some(ISSI_FAIL), ISSI_TIMING),
+    chip(0x9d6017, "IS25LP064A", Some(ISSI_FAIL), ISSI_TIMING),
+    chip(0x9d6018, "IS25LP128F", Some(ISSI_FAIL), ISSI_TIMING),
+    chip(0xba6015, "ZD25Q16B", None, ZETTA_TIMING),
+];
+
+/// Look up a flash chip by its JEDEC ID
+pub fn lookup(jedec_id: u32) -> Option<&'static ChipInfo> {
+    CHIPS.iter().find(|chip| chip.jedec_id == jedec_id)
+}
+
+/// Worst-case duration of `op` on the chip with the given JEDEC ID
+///
+/// For unknown chips, falls back to the maximum times in the chip's SFDP
+/// parameters `sfdp`, e.g. read with
+/// [`read_basic_parameters`](crate::sfdp::read_basic_parameters), and to
+/// [`Timing::CONSERVATIVE`] if they don't specify `op`. The capacity is
+/// taken from `sfdp`, or assumed from the JEDEC ID.
+pub fn timing_for(jedec_id: u32, op: Operation, sfdp: Option<&BasicFlashParameters>) -> Duration {
+    if let Some(chip) = lookup(jedec_id) {
+        return chip.timing_for(op);
+    }
+    let size = match sfdp {
+        Some(params) => u32::try_from(params.size).unwrap_or(u32::MAX),
+        // Invalid capacities are treated as the maximum of 16 MiB
+        None => 1u32.checked_shl(jedec_id & 0xff).unwrap_or(0x100_0000),
+    };
+    sfdp.and_then(|params| sfdp_worst_case(params, op, size))
+        .unwrap_or_else(|| Timing::CONSERVATIVE.worst_case(op, size))
+}
+
+/// Maximum duration of `op` from the SFDP parameters, if specified
+fn sfdp_worst_case(params: &BasicFlashParameters, op: Operation, size: u32) -> Option<Duration> {
+    let erase_time = |erase_size: u32| {
+        params
+            .erase_types
+            .into_iter()
+            .zip(params.erase_times)
+            .find_map(|(erase, time)| {
+                if erase?.size == erase_size {
+                    time
+                } else {
+                    None
+                }
+            })
+    };
+    match op {
+        Operation::SectorErase => erase_time(0x1000),
+        Operation::BlockErase32K => erase_time(0x8000),
+        Operation::BlockErase64K => erase_time(0x10000),
+        Operation::PageProgram => params.page_program_time,
+        Operation::ChipErase => Some(erase_time(0x10000)? * size.div_ceil(0x10000)),
+    }
+}
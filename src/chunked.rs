@@ -0,0 +1,131 @@
+//! Erase and program in small steps
+//!
+//! Erasing and programming a large range in one call keeps interrupts
+//! disabled for the whole operation, which can take seconds. Interrupt
+//! driven peripherals like USB stop working, and a watchdog may bite.
+//!
+//! [`ChunkedWriter`] splits the operation into single sector erases and
+//! page programs. Each call to [`ChunkedWriter::poll`] performs one of
+//! them inside its own critical section, so interrupts are serviced
+//! between chunks:
+//!
+//! ```ignore
+//! let mut writer = unsafe { ChunkedWriter::new(0x100000, &image, true)? };
+//! while writer.poll().is_pending() {
+//!     watchdog.feed();
+//! }
+//! ```
+
+use crate::error::FlashError;
+use crate::flash::{self, PAGE_SIZE, SECTOR_SIZE};
+use core::task::Poll;
+
+/// Erases and programs a flash range one sector or page at a time
+pub struct ChunkedWriter<'a> {
+    addr: u32,
+    data: &'a [u8],
+    use_boot2: bool,
+    /// Bytes of `data` programmed so far
+    written: u32,
+    /// Bytes from `addr` erased so far
+    erased: u32,
+    error: Option<FlashError>,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    /// Prepare writing `data` at `addr`
+    ///
+    /// `addr` is relative to the beginning of the flash area, and must be
+    /// a multiple of 4096. `data` may have any length. All sectors covered
+    /// by `data` are erased, so the rest of the last sector reads as 0xff
+    /// afterwards.
+    ///
+    /// The range is checked with [`flash::validate_range`] before anything
+    /// is written. Pages are copied to a buffer on the stack before
+    /// programming, so `data` may be located in flash.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// Each chunk disables interrupts on the current core. During each
+    /// call to [`poll`](Self::poll), the other core must not access flash
+    /// and DMA must not access flash.
+    ///
+    /// Checking the range may read the flash size from the chip, see
+    /// [`flash::validate_range`].
+    pub unsafe fn new(addr: u32, data: &'a [u8], use_boot2: bool) -> Result<Self, FlashError> {
+        let len = (data.len() as u32).next_multiple_of(SECTOR_SIZE);
+        crate::cs::free(|| flash::validate_range(addr, len, SECTOR_SIZE, use_boot2))?;
+        Ok(ChunkedWriter {
+            addr,
+            data,
+            use_boot2,
+            written: 0,
+            erased: 0,
+            error: None,
+        })
+    }
+
+    /// Perform the next chunk
+    ///
+    /// Erases one sector or programs one page. Returns `Poll::Pending`
+    /// while there is more to do, and the result once all data is written
+    /// or an error occurred. Further calls return the same result again.
+    pub fn poll(&mut self) -> Poll<Result<(), FlashError>> {
+        if let Some(e) = self.error {
+            return Poll::Ready(Err(e));
+        }
+        if self.is_done() {
+            return Poll::Ready(Ok(()));
+        }
+        let result = if self.written == self.erased {
+            self.erase_next()
+        } else {
+            self.program_next()
+        };
+        match result {
+            Err(e) => {
+                self.error = Some(e);
+                Poll::Ready(Err(e))
+            }
+            Ok(()) if self.is_done() => Poll::Ready(Ok(())),
+            Ok(()) => Poll::Pending,
+        }
+    }
+
+    /// Bytes of data written so far, and in total
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            (self.written as usize).min(self.data.len()),
+            self.data.len(),
+        )
+    }
+
+    fn is_done(&self) -> bool {
+        self.written as usize >= self.data.len()
+    }
+
+    fn erase_next(&mut self) -> Result<(), FlashError> {
+        let (addr, use_boot2) = (self.addr + self.erased, self.use_boot2);
+        trace!("chunked erase {:#x}", addr);
+        crate::cs::free(|| unsafe {
+            flash::flash_range_erase_checked(addr, SECTOR_SIZE, use_boot2)
+        })?;
+        self.erased += SECTOR_SIZE;
+        Ok(())
+    }
+
+    fn program_next(&mut self) -> Result<(), FlashError> {
+        let start = self.written as usize;
+        let src = &self.data[start..self.data.len().min(start + PAGE_SIZE as usize)];
+        // Programming 0xff leaves the erased padding unchanged
+        let mut page = [0xffu8; PAGE_SIZE as usize];
+        page[..src.len()].copy_from_slice(src);
+        let (addr, use_boot2) = (self.addr + self.written, self.use_boot2);
+        crate::cs::free(|| unsafe { flash::flash_range_program_checked(addr, &page, use_boot2) })?;
+        self.written += PAGE_SIZE;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Power-loss safe configuration storage
+//!
+//! [`ConfigStore`] keeps a value in two alternating flash sectors (A/B).
+//! Each write goes to the sector not holding the current value, together
+//! with a generation counter one higher than the current one and a CRC.
+//! Reads return the valid copy with the highest generation.
+//!
+//! If power fails while a sector is erased or programmed, its CRC doesn't
+//! match, and the previous value in the other sector is still returned.
+
+use crate::crc::{seq_newer, Crc32};
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::Flash;
+use crate::nor_flash::InternalFlash;
+use crate::storage::Pod;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Marks a sector written by [`ConfigStore::write`]
+const MAGIC: u32 = 0x7e5c_4a02;
+
+/// Magic, generation, payload length, CRC
+const HEADER_SIZE: usize = 16;
+
+/// A value stored in two alternating flash sectors
+///
+/// `T` must be plain data, see [`Pod`]. `F` is the flash holding the
+/// sectors, the internal flash unless given to
+/// [`with_flash`](Self::with_flash).
+pub struct ConfigStore<T, F = InternalFlash> {
+    flash: F,
+    offset: u32,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(target_os = "none")]
+impl<T: Pod> ConfigStore<T> {
+    /// Use the two sectors starting at `offset`
+    ///
+    /// `offset` is relative to the beginning of the flash area and must be
+    /// a multiple of 4096.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The sectors must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each write disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash during writes.
+    pub unsafe fn new(offset: u32, use_boot2: bool) -> Self {
+        Self::with_flash(Flash::new(use_boot2), offset)
+    }
+}
+
+impl<T: Pod, F: NorFlash<Error = FlashError>> ConfigStore<T, F> {
+    const FITS: () = assert!(
+        size_of::<T>() <= SECTOR_SIZE as usize - HEADER_SIZE,
+        "value doesn't fit into a flash sector"
+    );
+
+    /// Use the two sectors of `flash` starting at `offset`
+    ///
+    /// `offset` must be a multiple of 4096.
+    pub fn with_flash(flash: F, offset: u32) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
+        assert!(offset & (SECTOR_SIZE - 1) == 0);
+        assert!(offset
+            .checked_add(2 * SECTOR_SIZE)
+            .is_some_and(|end| end as usize <= flash.capacity()));
+        ConfigStore {
+            flash,
+            offset,
+            phantom: PhantomData,
+        }
+    }
+
+    fn slot_offset(&self, slot: usize) -> u32 {
+        self.offset + slot as u32 * SECTOR_SIZE
+    }
+
+    /// Read and check the copy in `slot`, returning its generation and value
+    fn read_slot(&mut self, slot: usize) -> Result<Option<(u32, T)>, FlashError> {
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        let len = HEADER_SIZE + size_of::<T>();
+        self.flash.read(self.slot_offset(slot), &mut buf[..len])?;
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(8) as usize != size_of::<T>() {
+            return Ok(None);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&buf[4..12]);
+        crc.update(&buf[HEADER_SIZE..len]);
+        if crc.finish() != word(12) {
+            warn!("CRC mismatch in config slot {}", slot);
+            return Ok(None);
+        }
+        // Safety: T is valid for any bit pattern, see `Pod`
+        let value = unsafe { core::ptr::read_unaligned(buf[HEADER_SIZE..].as_ptr() as *const T) };
+        Ok(Some((word(4), value)))
+    }
+
+    /// The slot holding the newest valid copy, with its generation and value
+    fn newest(&mut self) -> Result<Option<(usize, u32, T)>, FlashError> {
+        Ok(match (self.read_slot(0)?, self.read_slot(1)?) {
+            (Some((a, va)), Some((b, vb))) => {
+                if seq_newer(b, a) {
+                    Some((1, b, vb))
+                } else {
+                    Some((0, a, va))
+                }
+            }
+            (Some((a, va)), None) => Some((0, a, va)),
+            (None, Some((b, vb))) => Some((1, b, vb)),
+            (None, None) => None,
+        })
+    }
+
+    /// Read the newest valid value
+    ///
+    /// Returns `None` if neither sector holds a valid copy.
+    pub fn read(&mut self) -> Result<Option<T>, FlashError> {
+        Ok(self.newest()?.map(|(_, _, value)| value))
+    }
+
+    /// Generation counter of the newest valid value
+    pub fn generation(&mut self) -> Result<Option<u32>, FlashError> {
+        Ok(self.newest()?.map(|(_, generation, _)| generation))
+    }
+
+    /// Store `value` in the sector not holding the newest valid copy
+    pub fn write(&mut self, value: &T) -> Result<(), FlashError> {
+        let (slot, generation) = match self.newest()? {
+            Some((slot, generation, _)) => (1 - slot, generation.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let len = size_of::<T>();
+        let mut buf = [0xffu8; SECTOR_SIZE as usize];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&generation.to_le_bytes());
+        buf[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        // Safety: T has no padding bytes, see `Pod`
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                buf[HEADER_SIZE..].as_mut_ptr(),
+                len,
+            )
+        };
+        let mut crc = Crc32::new();
+        crc.update(&buf[4..12]);
+        crc.update(&buf[HEADER_SIZE..HEADER_SIZE + len]);
+        buf[12..16].copy_from_slice(&crc.finish().to_le_bytes());
+
+        debug!("writing config generation {} to slot {}", generation, slot);
+        let used = (HEADER_SIZE + len).next_multiple_of(PAGE_SIZE as usize);
+        let addr = self.slot_offset(slot);
+        self.flash.erase(addr, addr + SECTOR_SIZE)?;
+        self.flash.write(addr, &buf[..used])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    #[test]
+    fn alternates_between_slots() {
+        let mut store = ConfigStore::<u32, _>::with_flash(MockFlash::new(0x3000), 0x1000);
+        assert_eq!(store.read(), Ok(None));
+        for value in 1..=3 {
+            store.write(&value).unwrap();
+            assert_eq!(store.read(), Ok(Some(value)));
+        }
+        assert_eq!(store.generation(), Ok(Some(2)));
+        assert_eq!(store.flash.erase_count(0x1000), 2);
+        assert_eq!(store.flash.erase_count(0x2000), 1);
+    }
+
+    #[test]
+    fn keeps_previous_value_if_write_is_torn() {
+        let mut store = ConfigStore::<[u32; 2], _>::with_flash(MockFlash::new(0x2000), 0);
+        store.write(&[1, 2]).unwrap();
+        store.write(&[3, 4]).unwrap();
+        // Clear a bit of the payload of the newer copy in slot 1
+        let mut flash = store.flash;
+        let mut page = [0xffu8; 256];
+        page[HEADER_SIZE] = 0;
+        NorFlash::write(&mut flash, 0x1000, &page).unwrap();
+        let mut store = ConfigStore::<[u32; 2], _>::with_flash(flash, 0);
+        assert_eq!(store.read(), Ok(Some([1, 2])));
+    }
+}
@@ -0,0 +1,73 @@
+//! CRC-32 checksum
+//!
+//! The common CRC-32 used by Ethernet, zlib and others (reflected,
+//! polynomial 0x04C11DB7). Computed with a 16 entry table to keep the
+//! code small.
+
+const TABLE: [u32; 16] = {
+    let mut table = [0u32; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Incremental CRC-32 computation
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    /// Add `data` to the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            let mut crc = self.state ^ b as u32;
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+            self.state = crc;
+        }
+    }
+
+    /// The checksum of all data added so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Whether sequence number `a` is newer than `b`
+///
+/// Compares as in serial number arithmetic (RFC 1982), so wrapping is
+/// harmless as long as the numbers are less than 2^31 apart.
+pub(crate) fn seq_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
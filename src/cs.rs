@@ -0,0 +1,44 @@
+//! Critical sections around flash operations
+//!
+//! The safe wrappers of this crate disable interrupts while they access
+//! flash. By default, this uses `cortex_m::interrupt::free`. With the
+//! `critical-section` feature, the implementation selected by the
+//! application through the [critical-section] crate is used instead, e.g.
+//! the one provided by the HAL, RTIC or Embassy.
+//!
+//! The implementation must disable all interrupts of the current core,
+//! as interrupt handlers in flash would fault while XIP is disabled.
+//!
+//! [critical-section]: https://crates.io/crates/critical-section
+
+/// Run `f` in a critical section
+#[cfg(feature = "critical-section")]
+pub(crate) fn free<R>(f: impl FnOnce() -> R) -> R {
+    critical_section::with(|_cs| f())
+}
+
+/// Run `f` in a critical section
+#[cfg(not(feature = "critical-section"))]
+pub(crate) fn free<R>(f: impl FnOnce() -> R) -> R {
+    cortex_m::interrupt::free(|_cs| f())
+}
+
+/// Data shared with interrupt handlers, only accessed inside [`free`]
+///
+/// Like `cortex_m::interrupt::Mutex`, but using the critical section
+/// implementation configured for this crate.
+pub(crate) struct Mutex<T>(T);
+
+// Safety: the contents are only accessed in a critical section
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Mutex(value)
+    }
+
+    /// Run `f` with the contents, in a critical section
+    pub(crate) fn lock<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        free(|| f(&self.0))
+    }
+}
@@ -0,0 +1,169 @@
+//! Owned access to the flash
+//!
+//! The free functions in [`flash`](crate::flash) are `unsafe`, as nothing
+//! prevents two parts of a program from accessing flash at the same time.
+//! [`FlashDriver`] can only be obtained once, and its methods take
+//! `&mut self`, so the borrow checker serializes flash operations. It also
+//! keeps the configuration, so it doesn't need to be passed to every call.
+//!
+//! All methods disable interrupts on the current core while accessing
+//! flash, and check ranges with [`flash::validate_range`], so they can't
+//! erase the running program.
+
+use crate::error::FlashError;
+use crate::flash;
+use crate::uid::FlashUid;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once a [`FlashDriver`] was handed out
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The only handle allowed to access flash
+pub struct FlashDriver {
+    use_boot2: bool,
+    #[cfg(feature = "rp2040")]
+    ssi: Option<rp2040_hal::pac::XIP_SSI>,
+}
+
+impl FlashDriver {
+    /// Get the driver, if it wasn't taken before
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The driver can't control the second core and DMA. While a method
+    /// accesses flash, the other core must not access flash, and DMA must
+    /// not access flash.
+    ///
+    /// Only call this from one core, the check isn't atomic across cores.
+    pub unsafe fn take(use_boot2: bool) -> Option<Self> {
+        crate::cs::free(|| {
+            if TAKEN.load(Ordering::Relaxed) {
+                None
+            } else {
+                TAKEN.store(true, Ordering::Relaxed);
+                Some(FlashDriver {
+                    use_boot2,
+                    #[cfg(feature = "rp2040")]
+                    ssi: None,
+                })
+            }
+        })
+    }
+
+    /// Get the driver in exchange for the `XIP_SSI` peripheral
+    ///
+    /// The SSI is the controller used for all flash accesses, so owning
+    /// it proves that no HAL driver uses it. Like [`take`](Self::take),
+    /// this only succeeds once. Use
+    /// [`release`](Self::release) to get it back.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`take`](Self::take).
+    ///
+    /// Returns the peripheral as error if a driver was taken before.
+    #[cfg(feature = "rp2040")]
+    pub unsafe fn from_ssi(
+        ssi: rp2040_hal::pac::XIP_SSI,
+        use_boot2: bool,
+    ) -> Result<Self, rp2040_hal::pac::XIP_SSI> {
+        match Self::take(use_boot2) {
+            Some(mut driver) => {
+                driver.ssi = Some(ssi);
+                Ok(driver)
+            }
+            None => Err(ssi),
+        }
+    }
+
+    /// Give up the driver, returning the `XIP_SSI` peripheral if it was
+    /// created with [`from_ssi`](Self::from_ssi)
+    ///
+    /// A driver obtained with [`take`](Self::take) can't be taken again.
+    #[cfg(feature = "rp2040")]
+    pub fn release(self) -> Option<rp2040_hal::pac::XIP_SSI> {
+        self.ssi
+    }
+
+    /// Whether the 2nd stage boot loader is used to re-initialize XIP
+    pub fn use_boot2(&self) -> bool {
+        self.use_boot2
+    }
+
+    /// Select if the 2nd stage boot loader is used to re-initialize XIP
+    pub fn set_use_boot2(&mut self, use_boot2: bool) {
+        self.use_boot2 = use_boot2;
+    }
+
+    /// Size of the flash, as used to check ranges
+    ///
+    /// See [`flash::validate_range`] for how it is detected.
+    pub fn size(&mut self) -> u32 {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::detected_size(use_boot2) })
+    }
+
+    /// Read `buf.len()` bytes at `addr` through XIP
+    pub fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        match addr.checked_add(buf.len() as u32) {
+            Some(end) if end <= self.size() => {}
+            _ => return Err(FlashError::OutOfBounds),
+        }
+        let src = (crate::xip::XIP_BASE + addr) as *const u8;
+        // Safety: the range is inside the XIP window, which is always readable
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Erase `len` bytes at `addr`, see [`flash::try_flash_range_erase`]
+    pub fn erase(&mut self, addr: u32, len: u32) -> Result<(), FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::try_flash_range_erase(addr, len, use_boot2) })
+    }
+
+    /// Program `data` at `addr`, see [`flash::try_flash_range_program`]
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::try_flash_range_program(addr, data, use_boot2) })
+    }
+
+    /// Erase and program `data` at `addr`, see
+    /// [`flash::try_flash_range_erase_and_program`]
+    pub fn erase_and_program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe {
+            flash::try_flash_range_erase_and_program(addr, data, use_boot2)
+        })
+    }
+
+    /// Write `data` at `addr` without alignment requirements, see
+    /// [`flash::flash_write_unaligned`]
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe {
+            // Check the whole sectors, as they are rewritten
+            let start = addr & !(flash::SECTOR_SIZE - 1);
+            let end = addr
+                .checked_add(data.len() as u32)
+                .ok_or(FlashError::OutOfBounds)?
+                .next_multiple_of(flash::SECTOR_SIZE);
+            flash::validate_range(start, end - start, flash::SECTOR_SIZE, use_boot2)?;
+            flash::flash_write_unaligned(addr, data, use_boot2)
+        })
+    }
+
+    /// Read the JEDEC ID of the flash chip
+    pub fn jedec_id(&mut self) -> u32 {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::flash_jedec_id(use_boot2) })
+    }
+
+    /// Read the unique ID of the flash chip, see [`FlashUid::read`]
+    pub fn unique_id(&mut self) -> Result<FlashUid, FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { FlashUid::read(use_boot2) })
+    }
+}
@@ -0,0 +1,450 @@
+//! EEPROM emulation
+//!
+//! [`Eeprom`] stores `u32` values, addressed by `u16` keys, in a pair of
+//! flash sectors, like the EEPROM emulation of STM32 microcontrollers.
+//! Writing a value appends a fixed-size record to the active sector, and
+//! the newest record of a key holds its value. Only when the active sector
+//! is full, the current values are copied to the other sector, which then
+//! becomes active, and the full one is erased. A boot counter incremented
+//! at each start thus erases a sector once per 511 starts, instead of
+//! once per start:
+//!
+//! ```ignore
+//! let mut eeprom = unsafe { Eeprom::new(0x1fe000, true)? };
+//! let boots = eeprom.read(BOOT_COUNT)?.unwrap_or(0) + 1;
+//! eeprom.write(BOOT_COUNT, boots)?;
+//! ```
+//!
+//! Compared to [`kv`](crate::kv), values have a fixed size, and reading
+//! a value only scans a single sector.
+//!
+//! # Sector layout
+//!
+//! | Offset | Contents                                          |
+//! |--------|---------------------------------------------------|
+//! | 0      | state, 4 bytes                                    |
+//! | 4      | magic, 4 bytes                                    |
+//! | 8      | records, 8 bytes each                             |
+//!
+//! The state is 0xffffffff while the sector is erased, 0xeeeeeeee while
+//! values are copied to it and 0 once it is active, so each transition
+//! only clears bits. Record layout: key (2 bytes), the lower half of the
+//! CRC-32 of key and value (2 bytes), value (4 bytes).
+//!
+//! Records torn by a power failure fail the check and are ignored. A
+//! copy interrupted by a power failure is completed by [`Eeprom::new`].
+
+use crate::crc::Crc32;
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::Flash;
+use crate::nor_flash::InternalFlash;
+use embedded_storage::nor_flash::MultiwriteNorFlash;
+
+/// Marks a sector in use by the emulated EEPROM
+const MAGIC: u32 = 0x4545_5052;
+
+const ERASED: u32 = 0xffff_ffff;
+const RECEIVING: u32 = 0xeeee_eeee;
+const ACTIVE: u32 = 0;
+
+const HEADER_SIZE: u32 = 8;
+const RECORD_SIZE: u32 = 8;
+/// Number of records fitting into a sector
+pub const RECORDS_PER_SECTOR: u32 = (SECTOR_SIZE - HEADER_SIZE) / RECORD_SIZE;
+
+/// Marks an unused record
+const BLANK_KEY: u16 = 0xffff;
+
+/// Errors reported by the emulated EEPROM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A flash operation failed
+    Flash(FlashError),
+    /// The current values of all keys don't fit into a sector
+    Full,
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Emulated EEPROM in two sectors of flash
+///
+/// `F` is the flash holding the sectors, the internal flash unless given
+/// to [`with_flash`](Self::with_flash).
+pub struct Eeprom<F = InternalFlash> {
+    flash: F,
+    offset: u32,
+    active: u32,
+    write_pos: u32,
+}
+
+#[cfg(target_os = "none")]
+impl Eeprom {
+    /// Use the two sectors starting at `flash_offset`
+    ///
+    /// `flash_offset` is relative to the beginning of the flash area and
+    /// must be a multiple of 4096. If neither sector holds valid data, both
+    /// are erased and the EEPROM starts out empty. An interrupted copy
+    /// between the sectors is completed.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The sectors must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each flash operation disables interrupts on the current core. The
+    /// caller must make sure that the other core doesn't access flash and
+    /// that DMA doesn't access flash during writes.
+    pub unsafe fn new(flash_offset: u32, use_boot2: bool) -> Result<Self, Error> {
+        Self::with_flash(Flash::new(use_boot2), flash_offset)
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> Eeprom<F> {
+    /// Use the two sectors of `flash` starting at `offset`
+    ///
+    /// `offset` must be a multiple of 4096. See [`Eeprom::new`] for
+    /// the handling of existing contents.
+    pub fn with_flash(flash: F, offset: u32) -> Result<Self, Error> {
+        assert!(offset & (SECTOR_SIZE - 1) == 0);
+        assert!(offset
+            .checked_add(2 * SECTOR_SIZE)
+            .is_some_and(|end| end as usize <= flash.capacity()));
+        let mut eeprom = Eeprom {
+            flash,
+            offset,
+            active: 0,
+            write_pos: HEADER_SIZE,
+        };
+        eeprom.mount()?;
+        Ok(eeprom)
+    }
+
+    /// Find the active sector, completing an interrupted copy
+    fn mount(&mut self) -> Result<(), Error> {
+        match (self.state(0)?, self.state(1)?) {
+            (Some(ACTIVE), Some(RECEIVING)) => self.resume(0, 1)?,
+            (Some(RECEIVING), Some(ACTIVE)) => self.resume(1, 0)?,
+            // Only after corruption; keep the sector with more records
+            (Some(ACTIVE), Some(ACTIVE)) => {
+                let keep = if self.end(0)? >= self.end(1)? { 0 } else { 1 };
+                self.activate(keep)?
+            }
+            (Some(ACTIVE), _) => self.activate(0)?,
+            (_, Some(ACTIVE)) => self.activate(1)?,
+            // The other sector was erased after a complete copy
+            (Some(RECEIVING), _) => self.finish(0)?,
+            (_, Some(RECEIVING)) => self.finish(1)?,
+            _ => self.format()?,
+        }
+        debug!(
+            "eeprom mounted, active sector {} with {} records",
+            self.active,
+            (self.write_pos - HEADER_SIZE) / RECORD_SIZE
+        );
+        Ok(())
+    }
+
+    /// The state of `sector`, if it is used by the emulated EEPROM
+    fn state(&mut self, sector: u32) -> Result<Option<u32>, FlashError> {
+        let state = self.read_u32(sector, 0)?;
+        let magic = self.read_u32(sector, 4)?;
+        Ok((magic == MAGIC && matches!(state, RECEIVING | ACTIVE)).then_some(state))
+    }
+
+    /// Use `sector` as active sector, erasing the other one if necessary
+    fn activate(&mut self, sector: u32) -> Result<(), Error> {
+        self.active = sector;
+        self.write_pos = self.end(sector)?;
+        if !self.is_blank(1 - sector)? {
+            self.erase(1 - sector)?;
+        }
+        Ok(())
+    }
+
+    /// Erase both sectors and start with an empty EEPROM
+    ///
+    /// All values are lost.
+    pub fn format(&mut self) -> Result<(), Error> {
+        debug!("formatting eeprom at {:#x}", self.offset);
+        self.erase(0)?;
+        self.erase(1)?;
+        self.set_state(0, RECEIVING)?;
+        self.finish(0)
+    }
+
+    /// Read the value of `key`
+    ///
+    /// Returns `None` if the key was never written.
+    pub fn read(&mut self, key: u16) -> Result<Option<u32>, Error> {
+        assert!(key != BLANK_KEY);
+        Ok(self.find(self.active, HEADER_SIZE, self.write_pos, key)?)
+    }
+
+    /// Set `key` to `value`
+    ///
+    /// Key 0xffff is reserved. Nothing is written if `key` already has
+    /// this value. If the active sector is full, the current values are
+    /// copied to the other sector, so the number of distinct keys must be
+    /// less than [`RECORDS_PER_SECTOR`]. If they don't fit, [`Error::Full`]
+    /// is returned before anything is written.
+    pub fn write(&mut self, key: u16, value: u32) -> Result<(), Error> {
+        assert!(key != BLANK_KEY);
+        if self.read(key)? == Some(value) {
+            return Ok(());
+        }
+        if self.write_pos + RECORD_SIZE > SECTOR_SIZE {
+            return self.transfer(key, value);
+        }
+        trace!("eeprom write key {} = {:#x}", key, value);
+        self.append(key, value)
+    }
+
+    /// Copy the current values and `value` for `key` to the other sector
+    fn transfer(&mut self, key: u16, value: u32) -> Result<(), Error> {
+        let (from, to) = (self.active, 1 - self.active);
+        debug!("eeprom sector {} full, moving to {}", from, to);
+        if !self.fits(from, to, HEADER_SIZE, Some(key))? {
+            return Err(Error::Full);
+        }
+        if !self.is_blank(to)? {
+            self.erase(to)?;
+        }
+        self.set_state(to, RECEIVING)?;
+        self.active = to;
+        self.write_pos = HEADER_SIZE;
+        self.append(key, value)?;
+        self.resume(from, to)
+    }
+
+    /// Copy the values missing in `to` from `from`, then make `to` active
+    fn resume(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let to_end = self.end(to)?;
+        if !self.fits(from, to, to_end, None)? {
+            return Err(Error::Full);
+        }
+        self.active = to;
+        self.write_pos = to_end;
+        let end = self.end(from)?;
+        // Newest records first, so older values of a key are skipped
+        for pos in (HEADER_SIZE..end).step_by(RECORD_SIZE as usize).rev() {
+            let Some((key, value)) = self.record(from, pos)? else {
+                continue;
+            };
+            if self.find(to, HEADER_SIZE, self.write_pos, key)?.is_some() {
+                continue;
+            }
+            self.append(key, value)?;
+        }
+        self.erase(from)?;
+        self.finish(to)
+    }
+
+    /// Mark `sector`, whose copy is complete, as active
+    fn finish(&mut self, sector: u32) -> Result<(), Error> {
+        self.set_state(sector, ACTIVE)?;
+        self.active = sector;
+        self.write_pos = self.end(sector)?;
+        Ok(())
+    }
+
+    /// Whether the values missing in `to`, whose records end at `to_end`,
+    /// fit into it when copied from `from`, along with a new value for `extra`
+    fn fits(
+        &mut self,
+        from: u32,
+        to: u32,
+        to_end: u32,
+        extra: Option<u16>,
+    ) -> Result<bool, FlashError> {
+        let mut free = (SECTOR_SIZE - to_end) / RECORD_SIZE;
+        if extra.is_some() {
+            let Some(rest) = free.checked_sub(1) else {
+                return Ok(false);
+            };
+            free = rest;
+        }
+        let end = self.end(from)?;
+        for pos in (HEADER_SIZE..end).step_by(RECORD_SIZE as usize) {
+            let Some((key, _)) = self.record(from, pos)? else {
+                continue;
+            };
+            // Count each key once, at its newest record
+            if Some(key) == extra
+                || self.find(from, pos + RECORD_SIZE, end, key)?.is_some()
+                || self.find(to, HEADER_SIZE, to_end, key)?.is_some()
+            {
+                continue;
+            }
+            let Some(rest) = free.checked_sub(1) else {
+                return Ok(false);
+            };
+            free = rest;
+        }
+        Ok(true)
+    }
+
+    /// The newest value of `key` in `sector`, searching records from `start` to `end`
+    fn find(
+        &mut self,
+        sector: u32,
+        start: u32,
+        end: u32,
+        key: u16,
+    ) -> Result<Option<u32>, FlashError> {
+        for pos in (start..end).step_by(RECORD_SIZE as usize).rev() {
+            match self.record(sector, pos)? {
+                Some((k, value)) if k == key => return Ok(Some(value)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// The key and value of the record at `pos`, if it is valid
+    fn record(&mut self, sector: u32, pos: u32) -> Result<Option<(u16, u32)>, FlashError> {
+        let header = self.read_u32(sector, pos)?;
+        let value = self.read_u32(sector, pos + 4)?;
+        let key = header as u16;
+        Ok(
+            (key != BLANK_KEY && (header >> 16) as u16 == check(key, value))
+                .then_some((key, value)),
+        )
+    }
+
+    /// End of the records in `sector`
+    fn end(&mut self, sector: u32) -> Result<u32, FlashError> {
+        let mut pos = HEADER_SIZE;
+        while pos < SECTOR_SIZE
+            && (self.read_u32(sector, pos)? != ERASED || self.read_u32(sector, pos + 4)? != ERASED)
+        {
+            pos += RECORD_SIZE;
+        }
+        Ok(pos)
+    }
+
+    fn read_u32(&mut self, sector: u32, pos: u32) -> Result<u32, FlashError> {
+        let mut buf = [0u8; 4];
+        self.flash
+            .read(self.offset + sector * SECTOR_SIZE + pos, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn is_blank(&mut self, sector: u32) -> Result<bool, FlashError> {
+        let mut buf = [0u8; PAGE_SIZE as usize];
+        for page in (0..SECTOR_SIZE).step_by(PAGE_SIZE as usize) {
+            self.flash
+                .read(self.offset + sector * SECTOR_SIZE + page, &mut buf)?;
+            if buf.iter().any(|&b| b != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn append(&mut self, key: u16, value: u32) -> Result<(), Error> {
+        let mut record = [0u8; RECORD_SIZE as usize];
+        record[0..2].copy_from_slice(&key.to_le_bytes());
+        record[2..4].copy_from_slice(&check(key, value).to_le_bytes());
+        record[4..8].copy_from_slice(&value.to_le_bytes());
+        let pos = self.write_pos;
+        self.write_pos += RECORD_SIZE;
+        self.program(self.active, pos, &record)?;
+        Ok(())
+    }
+
+    fn set_state(&mut self, sector: u32, state: u32) -> Result<(), FlashError> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&state.to_le_bytes());
+        header[4..8].copy_from_slice(&MAGIC.to_le_bytes());
+        self.program(sector, 0, &header)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), FlashError> {
+        let addr = self.offset + sector * SECTOR_SIZE;
+        self.flash.erase(addr, addr + SECTOR_SIZE)
+    }
+
+    /// Program `bytes` at `pos`, leaving the rest of the page unchanged
+    fn program(&mut self, sector: u32, pos: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let addr = self.offset + sector * SECTOR_SIZE + pos;
+        let page = addr & !(PAGE_SIZE - 1);
+        // Programming 0xff leaves bits unchanged
+        let mut buf = [0xffu8; PAGE_SIZE as usize];
+        let start = (addr - page) as usize;
+        buf[start..start + bytes.len()].copy_from_slice(bytes);
+        self.flash.write(page, &buf)
+    }
+}
+
+/// Lower half of the CRC-32 of `key` and `value`
+fn check(key: u16, value: u32) -> u16 {
+    let mut crc = Crc32::new();
+    crc.update(&key.to_le_bytes());
+    crc.update(&value.to_le_bytes());
+    crc.finish() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    #[test]
+    fn keeps_values_across_transfers() {
+        let mut eeprom = Eeprom::with_flash(MockFlash::new(0x2000), 0).unwrap();
+        assert_eq!(eeprom.read(1), Ok(None));
+        eeprom.write(2, 0x1234).unwrap();
+        for i in 0..2000 {
+            eeprom.write(1, i).unwrap();
+        }
+        let mut eeprom = Eeprom::with_flash(eeprom.flash, 0).unwrap();
+        assert_eq!(eeprom.read(1), Ok(Some(1999)));
+        assert_eq!(eeprom.read(2), Ok(Some(0x1234)));
+        assert!(eeprom.flash.erase_count(0) >= 2 && eeprom.flash.erase_count(0x1000) >= 2);
+    }
+
+    #[test]
+    fn skips_unchanged_values() {
+        let mut eeprom = Eeprom::with_flash(MockFlash::new(0x2000), 0).unwrap();
+        eeprom.write(1, 5).unwrap();
+        let end = eeprom.write_pos;
+        eeprom.write(1, 5).unwrap();
+        assert_eq!(eeprom.write_pos, end);
+    }
+
+    #[test]
+    fn reports_full_before_writing() {
+        let mut eeprom = Eeprom::with_flash(MockFlash::new(0x2000), 0).unwrap();
+        for key in 0..RECORDS_PER_SECTOR as u16 {
+            eeprom.write(key, 1).unwrap();
+        }
+        let erases = eeprom.flash.erase_count(0x1000);
+        assert_eq!(eeprom.write(1000, 1), Err(Error::Full));
+        assert_eq!(eeprom.flash.erase_count(0x1000), erases);
+        assert!(eeprom.is_blank(1).unwrap());
+        // Updating one of the keys still fits
+        eeprom.write(0, 2).unwrap();
+        assert_eq!(eeprom.read(0), Ok(Some(2)));
+        assert_eq!(eeprom.read(7), Ok(Some(1)));
+    }
+
+    #[test]
+    fn keeps_fuller_of_two_active_sectors() {
+        let mut eeprom = Eeprom::with_flash(MockFlash::new(0x2000), 0).unwrap();
+        eeprom.write(1, 5).unwrap();
+        eeprom.set_state(1, ACTIVE).unwrap();
+        let mut eeprom = Eeprom::with_flash(eeprom.flash, 0).unwrap();
+        assert_eq!(eeprom.read(1), Ok(Some(5)));
+        assert!(eeprom.is_blank(1).unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! Adapter for the [`ekv`](https://crates.io/crates/ekv) key-value database
+//!
+//! [`EkvFlash`] implements `ekv::flash::Flash` over a region of the
+//! internal flash. Each ekv page maps to `ekv::config::PAGE_SIZE` bytes of
+//! flash, which must be a multiple of the 4096 byte sector size.
+//!
+//! Only available with the `ekv` feature.
+
+use crate::error::FlashError;
+use crate::flash;
+use ekv::config::{ERASE_VALUE, PAGE_SIZE};
+use ekv::flash::{Flash, PageID};
+
+const SECTOR_SIZE: usize = flash::SECTOR_SIZE as usize;
+/// `flash::PAGE_SIZE`, named differently from the size of ekv pages
+const PROGRAM_SIZE: usize = flash::PAGE_SIZE as usize;
+
+const _: () = assert!(
+    PAGE_SIZE & (SECTOR_SIZE - 1) == 0,
+    "EKV_PAGE_SIZE must be a multiple of 4096"
+);
+const _: () = assert!(ERASE_VALUE == 0xff, "EKV_ERASE_VALUE must be 255");
+
+/// A flash region used as ekv storage
+pub struct EkvFlash {
+    offset: u32,
+    page_count: usize,
+    use_boot2: bool,
+}
+
+impl EkvFlash {
+    /// Use `page_count` ekv pages of flash starting at `offset`
+    ///
+    /// `offset` is relative to the beginning of the flash area and must be
+    /// a multiple of 4096.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The region must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each operation disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash while ekv
+    /// operations are running.
+    pub unsafe fn new(offset: u32, page_count: usize, use_boot2: bool) -> Self {
+        assert!(offset & 0xfff == 0);
+        assert!(offset as usize + page_count * PAGE_SIZE <= 0x1000000);
+        EkvFlash {
+            offset,
+            page_count,
+            use_boot2,
+        }
+    }
+
+    fn addr(&self, page_id: PageID, offset: usize) -> u32 {
+        self.offset + (page_id.index() * PAGE_SIZE + offset) as u32
+    }
+}
+
+impl Flash for EkvFlash {
+    type Error = FlashError;
+
+    fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    async fn erase(&mut self, page_id: PageID) -> Result<(), FlashError> {
+        let addr = self.addr(page_id, 0);
+        crate::cs::free(|| unsafe {
+            flash::flash_range_erase_checked(addr, PAGE_SIZE as u32, self.use_boot2)
+        })
+    }
+
+    async fn read(
+        &mut self,
+        page_id: PageID,
+        offset: usize,
+        data: &mut [u8],
+    ) -> Result<(), FlashError> {
+        if offset + data.len() > PAGE_SIZE {
+            return Err(FlashError::OutOfBounds);
+        }
+        let addr = self.addr(page_id, offset);
+        let src = (crate::xip::XIP_BASE + addr) as *const u8;
+        // Safety: the region is inside the XIP window, which is always readable
+        unsafe { core::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len()) };
+        Ok(())
+    }
+
+    async fn write(
+        &mut self,
+        page_id: PageID,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), FlashError> {
+        // ekv writes arbitrary aligned chunks. Program whole 256 byte
+        // pages, padding with 0xff, which leaves the other bytes unchanged.
+        let start = self.addr(page_id, offset) as usize;
+        let end = start + data.len();
+        let mut page_addr = start - start % PROGRAM_SIZE;
+        while page_addr < end {
+            let mut buf = [0xffu8; PROGRAM_SIZE];
+            let from = start.max(page_addr);
+            let to = end.min(page_addr + PROGRAM_SIZE);
+            buf[from - page_addr..to - page_addr].copy_from_slice(&data[from - start..to - start]);
+            crate::cs::free(|| unsafe {
+                flash::flash_range_program_checked(page_addr as u32, &buf, self.use_boot2)
+            })?;
+            page_addr += PROGRAM_SIZE;
+        }
+        Ok(())
+    }
+}
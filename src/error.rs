@@ -0,0 +1,56 @@
+//! Error types
+
+/// Errors reported by flash operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum FlashError {
+    /// The flash chip reported a failed program or erase operation
+    ///
+    /// `status` is the raw value of the chip's failure flag register.
+    HardwareFailure { status: u8 },
+    /// Data read back after writing differs from the data written
+    ///
+    /// `offset` is the flash offset of the first differing byte.
+    VerifyFailed { offset: u32 },
+    /// The range overlaps a region protected using [`protect`](crate::protect)
+    ///
+    /// `offset` is the start of the protected region.
+    WriteProtected { offset: u32 },
+    /// The range exceeds the bounds of the storage
+    OutOfBounds,
+    /// An offset or length isn't a multiple of the required alignment
+    NotAligned,
+    /// The range overlaps the flash image of the running program
+    OverlapsImage,
+    /// The operation is not supported by the detected flash chip
+    Unsupported,
+    /// The flash chip didn't perform a program or erase operation
+    ///
+    /// Detected by the Write Enable Latch still being set afterwards,
+    /// e.g. because the chip rejected the operation as write protected.
+    ProgramFailed,
+    /// The flash chip stayed busy longer than its worst-case operation time
+    Timeout,
+    /// A status register read back after writing it kept its old value
+    ///
+    /// E.g. because the status register is locked.
+    StatusWriteFailed,
+}
+
+/// Data read back after programming differs from the data written
+///
+/// Returned by [`flash_range_program_verified`](crate::flash::flash_range_program_verified)
+/// and [`flash_range_erase_and_program_verified`](crate::flash::flash_range_erase_and_program_verified).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VerifyError {
+    /// Flash offset of the first differing byte
+    pub offset: u32,
+}
+
+impl From<VerifyError> for FlashError {
+    fn from(e: VerifyError) -> Self {
+        FlashError::VerifyFailed { offset: e.offset }
+    }
+}
@@ -0,0 +1,142 @@
+//! Opt-in mode allowing RAM-resident interrupts during flash operations
+//!
+//! By default, interrupts must be disabled while flash is being written,
+//! because an interrupt handler executing from flash would fault. For
+//! firmware that keeps its vector table and all active interrupt handlers
+//! in RAM, this is overly strict and hurts interrupt latency.
+//!
+//! [`RamInterrupts`] checks that the vector table and the handlers of all
+//! enabled exceptions and interrupts are located in RAM or ROM. As long as
+//! that proof holds, flash operations run through [`run_flash_operation`]
+//! leave interrupts enabled.
+
+use crate::ram::is_flash_independent;
+use core::ptr::read_volatile;
+
+const SYST_CSR: *const u32 = 0xe000_e010 as *const u32;
+const NVIC_ISER: *const u32 = 0xe000_e100 as *const u32;
+const SCB_VTOR: *const u32 = 0xe000_ed08 as *const u32;
+#[cfg(feature = "rp235x")]
+const SCB_SHCSR: *const u32 = 0xe000_ed24 as *const u32;
+#[cfg(feature = "rp235x")]
+const DCB_DEMCR: *const u32 = 0xe000_edfc as *const u32;
+
+/// Number of external interrupts
+#[cfg(not(feature = "rp235x"))]
+const NUM_IRQS: usize = 32;
+/// Number of external interrupts
+#[cfg(feature = "rp235x")]
+const NUM_IRQS: usize = 52;
+
+/// Number of the SysTick exception in the vector table
+const SYSTICK: usize = 15;
+/// Exceptions which can't be disabled: NMI, HardFault, SVCall and PendSV
+const ALWAYS_ENABLED: [usize; 4] = [2, 3, 11, 14];
+/// MemManage, BusFault, UsageFault and SecureFault, with their SHCSR enable bits
+///
+/// While disabled, they escalate to HardFault.
+#[cfg(feature = "rp235x")]
+const CONFIGURABLE_FAULTS: [(usize, u32); 4] =
+    [(4, 1 << 16), (5, 1 << 17), (6, 1 << 18), (7, 1 << 19)];
+/// Number of the DebugMonitor exception, enabled by DEMCR.MON_EN
+#[cfg(feature = "rp235x")]
+const DEBUG_MONITOR: usize = 12;
+
+/// Reasons why interrupts can't stay enabled during flash operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InterruptCheckError {
+    /// The vector table is located in flash
+    VectorTableInFlash,
+    /// The handler for the given exception number is located in flash
+    ///
+    /// Exception numbers 16 and above correspond to IRQ `n - 16`.
+    HandlerInFlash(u8),
+}
+
+/// Proof that all active interrupt handlers of the current core execute from RAM
+///
+/// The check covers the vector table, the exceptions which are always
+/// enabled, SysTick if its interrupt is enabled, and all IRQs enabled in the
+/// NVIC. On the RP2350, it also covers the configurable faults enabled in
+/// SHCSR and DebugMonitor if enabled in DEMCR. It is repeated before each flash operation, so interrupts enabled
+/// after constructing this value are covered as well.
+pub struct RamInterrupts {
+    _private: (),
+}
+
+impl RamInterrupts {
+    /// Check that interrupts can stay enabled during flash operations
+    ///
+    /// # Safety
+    ///
+    /// Only the location of the handlers can be checked. The caller must
+    /// make sure that the handlers don't call functions located in flash,
+    /// and don't read constants or other data from flash.
+    pub unsafe fn new() -> Result<Self, InterruptCheckError> {
+        let proof = RamInterrupts { _private: () };
+        proof.check()?;
+        Ok(proof)
+    }
+
+    /// Repeat the check performed by [`RamInterrupts::new`]
+    pub fn check(&self) -> Result<(), InterruptCheckError> {
+        // Safety: these are read-only accesses to core registers, and to
+        // the vector table they point to
+        unsafe {
+            let vtor = read_volatile(SCB_VTOR) as *const usize;
+            if !is_flash_independent(vtor as *const ()) {
+                return Err(InterruptCheckError::VectorTableInFlash);
+            }
+            let handler_in_ram = |exception: usize| {
+                is_flash_independent(read_volatile(vtor.add(exception)) as *const ())
+            };
+            for exception in ALWAYS_ENABLED {
+                if !handler_in_ram(exception) {
+                    return Err(InterruptCheckError::HandlerInFlash(exception as u8));
+                }
+            }
+            #[cfg(feature = "rp235x")]
+            {
+                let shcsr = read_volatile(SCB_SHCSR);
+                for (exception, enable) in CONFIGURABLE_FAULTS {
+                    if shcsr & enable != 0 && !handler_in_ram(exception) {
+                        return Err(InterruptCheckError::HandlerInFlash(exception as u8));
+                    }
+                }
+                // MON_EN
+                if read_volatile(DCB_DEMCR) & (1 << 16) != 0 && !handler_in_ram(DEBUG_MONITOR) {
+                    return Err(InterruptCheckError::HandlerInFlash(DEBUG_MONITOR as u8));
+                }
+            }
+            // TICKINT
+            if read_volatile(SYST_CSR) & 0b10 != 0 && !handler_in_ram(SYSTICK) {
+                return Err(InterruptCheckError::HandlerInFlash(SYSTICK as u8));
+            }
+            for irq in 0..NUM_IRQS {
+                let enabled = read_volatile(NVIC_ISER.add(irq / 32));
+                if enabled & (1 << (irq % 32)) != 0 && !handler_in_ram(16 + irq) {
+                    return Err(InterruptCheckError::HandlerInFlash(16 + irq as u8));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a flash operation, disabling interrupts unless they are proven to be RAM-resident
+///
+/// If `ram_interrupts` is `Some` and its check still passes, `f` runs with
+/// interrupts left as they are. Otherwise, `f` runs with interrupts disabled.
+///
+/// This only covers interrupts of the current core. The other core must
+/// still be running code from RAM or ROM.
+pub fn run_flash_operation<R>(ram_interrupts: Option<&RamInterrupts>, f: impl FnOnce() -> R) -> R {
+    if let Some(proof) = ram_interrupts {
+        match proof.check() {
+            Ok(()) => return f(),
+            Err(_e) => warn!("interrupts not in RAM, disabling them"),
+        }
+    }
+    crate::cs::free(f)
+}
@@ -0,0 +1,545 @@
+//! Wear-leveling key-value store
+//!
+//! [`Store`] keeps small values, addressed by `u16` keys, in an append-only
+//! log spread over a ring of flash sectors. Updating a value appends a new
+//! record instead of rewriting a sector, so frequently updated values like
+//! counters are spread over all sectors of the store.
+//!
+//! One sector is always kept erased. When the active sector is full, the
+//! store moves on to the erased sector, copies the still current records
+//! of the oldest sector there, and erases the oldest sector. This
+//! compaction happens automatically during [`Store::set`] and
+//! [`Store::delete`], and can be triggered ahead of time with
+//! [`Store::compact`], e.g. while the application is idle.
+//!
+//! All records are protected by a CRC. Records torn by a power failure are
+//! ignored, and an interrupted compaction is resumed by the next write. A
+//! sector is only used once its commit word is written, after the magic
+//! and sequence number, so a torn sector header is ignored as well, and
+//! the sector is erased again before it is used.
+//!
+//! Sector layout:
+//!
+//! | Offset | Contents                                    |
+//! |--------|---------------------------------------------|
+//! | 0      | magic, 4 bytes                              |
+//! | 4      | sequence number of the sector, 4 bytes      |
+//! | 8      | commit word, 0 once the header is complete, 4 bytes |
+//! | 12     | records, each padded to a multiple of 4 bytes |
+//!
+//! Record layout: key (2 bytes), length (2 bytes), CRC-32 of key, length
+//! and value (4 bytes), value.
+
+use crate::crc::{seq_newer, Crc32};
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::Flash;
+use crate::nor_flash::InternalFlash;
+use embedded_storage::nor_flash::MultiwriteNorFlash;
+
+/// Marks a sector in use by the store
+const MAGIC: u32 = 0x7e5c_4a03;
+const SECTOR_HEADER_SIZE: u32 = 12;
+/// Value of the commit word of a complete sector header
+const COMMITTED: u32 = 0;
+const RECORD_HEADER_SIZE: u32 = 8;
+
+/// Maximum length of a value
+pub const MAX_VALUE_LEN: usize = 256;
+
+/// Key of erased flash, marks the end of the log in a sector
+const BLANK_KEY: u16 = 0xffff;
+/// Length marking a deleted key
+const TOMBSTONE: u16 = 0xfffe;
+
+/// Errors reported by the key-value store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A flash operation failed
+    Flash(FlashError),
+    /// The current values don't fit into the store
+    Full,
+    /// The value is longer than [`MAX_VALUE_LEN`]
+    ValueTooLarge,
+    /// The buffer passed to [`Store::get`] is too small for the value
+    BufferTooSmall,
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Position of a record in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    sector: u32,
+    pos: u32,
+    key: u16,
+    len: u16,
+}
+
+impl Record {
+    fn value_len(&self) -> u32 {
+        if self.len == TOMBSTONE {
+            0
+        } else {
+            self.len as u32
+        }
+    }
+
+    fn size(&self) -> u32 {
+        (RECORD_HEADER_SIZE + self.value_len()).next_multiple_of(4)
+    }
+}
+
+/// An append-log key-value store
+///
+/// `F` is the flash holding the store, the internal flash unless given to
+/// [`with_flash`](Self::with_flash).
+pub struct Store<F = InternalFlash> {
+    flash: F,
+    offset: u32,
+    num_sectors: u32,
+    /// Sector receiving new records, `None` if the store isn't formatted
+    active: Option<u32>,
+    seq: u32,
+    /// Offset of the next record in the active sector
+    write_pos: u32,
+}
+
+#[cfg(target_os = "none")]
+impl Store {
+    /// Use `num_sectors` sectors of flash starting at `flash_offset`
+    ///
+    /// `flash_offset` is relative to the beginning of the flash area and
+    /// must be a multiple of 4096. At least 2 sectors are required. The
+    /// capacity for values is a bit less than `num_sectors - 1` sectors.
+    ///
+    /// Existing contents are picked up. If the sectors don't contain a
+    /// store, they are erased by the first write.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The sectors must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each flash operation disables interrupts on the current core. The
+    /// caller must make sure that the other core doesn't access flash and
+    /// that DMA doesn't access flash during writes.
+    pub unsafe fn new(
+        flash_offset: u32,
+        num_sectors: u32,
+        use_boot2: bool,
+    ) -> Result<Self, FlashError> {
+        Self::with_flash(Flash::new(use_boot2), flash_offset, num_sectors)
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> Store<F> {
+    /// Use `num_sectors` sectors of `flash` starting at `offset`
+    ///
+    /// `offset` must be a multiple of 4096. See [`Store::new`] for
+    /// the other requirements.
+    pub fn with_flash(flash: F, offset: u32, num_sectors: u32) -> Result<Self, FlashError> {
+        assert!(offset & (SECTOR_SIZE - 1) == 0);
+        assert!(num_sectors >= 2);
+        assert!(num_sectors
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|len| len.checked_add(offset))
+            .is_some_and(|end| end as usize <= flash.capacity()));
+        let mut store = Store {
+            flash,
+            offset,
+            num_sectors,
+            active: None,
+            seq: 0,
+            write_pos: 0,
+        };
+        store.mount()?;
+        Ok(store)
+    }
+
+    /// Find the active sector and the end of its log
+    fn mount(&mut self) -> Result<(), FlashError> {
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..self.num_sectors {
+            if let Some(seq) = self.sector_seq(sector)? {
+                if newest.is_none_or(|(_, newest)| seq_newer(seq, newest)) {
+                    newest = Some((sector, seq));
+                }
+            }
+        }
+        if let Some((sector, seq)) = newest {
+            self.active = Some(sector);
+            self.seq = seq;
+            self.write_pos = self.scan(sector, |_| {})?;
+            debug!("kv store mounted, active sector {} seq {}", sector, seq);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, sector: u32, pos: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.flash
+            .read(self.offset + sector * SECTOR_SIZE + pos, buf)
+    }
+
+    fn read_u32(&mut self, sector: u32, pos: u32) -> Result<u32, FlashError> {
+        let mut buf = [0u8; 4];
+        self.read(sector, pos, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Sequence number of `sector`, if it is in use
+    fn sector_seq(&mut self, sector: u32) -> Result<Option<u32>, FlashError> {
+        Ok(
+            if self.read_u32(sector, 0)? == MAGIC && self.read_u32(sector, 8)? == COMMITTED {
+                Some(self.read_u32(sector, 4)?)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Whether all of `sector` is erased
+    fn is_blank(&mut self, sector: u32) -> Result<bool, FlashError> {
+        let mut buf = [0u8; PAGE_SIZE as usize];
+        for page in (0..SECTOR_SIZE).step_by(PAGE_SIZE as usize) {
+            self.read(sector, page, &mut buf)?;
+            if buf.iter().any(|&b| b != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The record at `pos` of `sector`, and the position of the next one
+    ///
+    /// Returns `None` at the end of the log, and `Some((None, next))` for
+    /// records which fail the CRC.
+    fn record_at(
+        &mut self,
+        sector: u32,
+        pos: u32,
+    ) -> Result<Option<(Option<Record>, u32)>, FlashError> {
+        if pos + RECORD_HEADER_SIZE > SECTOR_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        self.read(sector, pos, &mut header)?;
+        let key = u16::from_le_bytes([header[0], header[1]]);
+        let len = u16::from_le_bytes([header[2], header[3]]);
+        if key == BLANK_KEY && len == 0xffff {
+            return Ok(None);
+        }
+        let record = Record {
+            sector,
+            pos,
+            key,
+            len,
+        };
+        let value_len = record.value_len();
+        if value_len as usize > MAX_VALUE_LEN || pos + record.size() > SECTOR_SIZE {
+            // Torn header, the rest of the sector can't be used
+            warn!("kv store sector {} corrupted at {}", sector, pos);
+            return Ok(Some((None, SECTOR_SIZE)));
+        }
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let value = &mut value[..value_len as usize];
+        self.read(sector, pos + RECORD_HEADER_SIZE, value)?;
+        let mut crc = Crc32::new();
+        crc.update(&header[..4]);
+        crc.update(value);
+        let valid = crc.finish() == u32::from_le_bytes(header[4..8].try_into().unwrap());
+        Ok(Some((valid.then_some(record), pos + record.size())))
+    }
+
+    /// Call `f` for each valid record of `sector`, returning the end of the log
+    fn scan(&mut self, sector: u32, mut f: impl FnMut(Record)) -> Result<u32, FlashError> {
+        let mut pos = SECTOR_HEADER_SIZE;
+        while let Some((record, next)) = self.record_at(sector, pos)? {
+            if let Some(record) = record {
+                f(record);
+            }
+            pos = next;
+        }
+        Ok(pos)
+    }
+
+    /// Sectors in order of age, starting with the one after the active sector
+    fn sectors_by_age(&self) -> impl Iterator<Item = u32> {
+        let (active, num_sectors) = (self.active.unwrap_or(0), self.num_sectors);
+        (1..=num_sectors).map(move |i| (active + i) % num_sectors)
+    }
+
+    /// The newest record for `key`
+    fn find(&mut self, key: u16) -> Result<Option<Record>, FlashError> {
+        if self.active.is_none() {
+            return Ok(None);
+        }
+        let mut found = None;
+        for sector in self.sectors_by_age() {
+            self.scan(sector, |record| {
+                if record.key == key {
+                    found = Some(record);
+                }
+            })?;
+        }
+        Ok(found)
+    }
+
+    /// Read the value of `key` into `buf`
+    ///
+    /// Returns the length of the value, or `None` if the key doesn't exist.
+    pub fn get(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        match self.find(key)? {
+            Some(record) if record.len != TOMBSTONE => {
+                let len = record.len as usize;
+                if buf.len() < len {
+                    return Err(Error::BufferTooSmall);
+                }
+                self.read(
+                    record.sector,
+                    record.pos + RECORD_HEADER_SIZE,
+                    &mut buf[..len],
+                )?;
+                Ok(Some(len))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Set `key` to `value`
+    ///
+    /// Key 0xffff is reserved.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        assert!(key != BLANK_KEY);
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLarge);
+        }
+        self.append(key, value.len() as u16, value)
+    }
+
+    /// Remove `key`
+    pub fn delete(&mut self, key: u16) -> Result<(), Error> {
+        assert!(key != BLANK_KEY);
+        match self.find(key)? {
+            Some(record) if record.len != TOMBSTONE => self.append(key, TOMBSTONE, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    /// Move to the next sector now, compacting the oldest one
+    ///
+    /// This is done automatically when the active sector is full, but
+    /// takes a sector erase and copying the current records. Calling this
+    /// while the application is idle avoids that delay in a later write.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        match self.active {
+            Some(_) if self.write_pos > SECTOR_HEADER_SIZE => self.advance(),
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, key: u16, len: u16, value: &[u8]) -> Result<(), Error> {
+        self.prepare()?;
+        if !self.try_append(key, len, value)? {
+            self.advance()?;
+            if !self.try_append(key, len, value)? {
+                return Err(Error::Full);
+            }
+        }
+        Ok(())
+    }
+
+    /// Format the store if necessary, and finish an interrupted compaction
+    fn prepare(&mut self) -> Result<(), Error> {
+        let Some(active) = self.active else {
+            debug!("formatting kv store at {:#x}", self.offset);
+            for sector in 0..self.num_sectors {
+                self.erase(sector)?;
+            }
+            self.seq = 0;
+            self.start_sector(0)?;
+            return Ok(());
+        };
+        let spare = (active + 1) % self.num_sectors;
+        if !self.is_blank(spare)? {
+            debug!("resuming compaction of sector {}", spare);
+            self.collect(spare)?;
+        }
+        Ok(())
+    }
+
+    /// Make the erased sector after the active one active, and collect the oldest one
+    fn advance(&mut self) -> Result<(), Error> {
+        let active = self.active.unwrap_or(0);
+        let next = (active + 1) % self.num_sectors;
+        self.seq = self.seq.wrapping_add(1);
+        self.start_sector(next)?;
+        self.collect((next + 1) % self.num_sectors)
+    }
+
+    /// Write the header of `sector`, committing it once it is complete
+    fn start_sector(&mut self, sector: u32) -> Result<(), Error> {
+        let mut header = [0u8; 8];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        self.program(sector, 0, &header)?;
+        self.program(sector, 8, &COMMITTED.to_le_bytes())?;
+        self.active = Some(sector);
+        self.write_pos = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Copy the current records of `sector` to the active sector, and erase it
+    fn collect(&mut self, sector: u32) -> Result<(), Error> {
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let mut pos = SECTOR_HEADER_SIZE;
+        while let Some((record, next)) = self.record_at(sector, pos)? {
+            pos = next;
+            let Some(record) = record else {
+                continue;
+            };
+            // Tombstones in the oldest sector hide nothing older
+            if record.len == TOMBSTONE || self.find(record.key)? != Some(record) {
+                continue;
+            }
+            let value = &mut value[..record.len as usize];
+            self.read(record.sector, record.pos + RECORD_HEADER_SIZE, value)?;
+            if !self.try_append(record.key, record.len, value)? {
+                return Err(Error::Full);
+            }
+        }
+        self.erase(sector)?;
+        Ok(())
+    }
+
+    /// Append a record to the active sector, returning `false` if it doesn't fit
+    fn try_append(&mut self, key: u16, len: u16, value: &[u8]) -> Result<bool, Error> {
+        let Some(active) = self.active else {
+            return Ok(false);
+        };
+        let size = (RECORD_HEADER_SIZE + value.len() as u32).next_multiple_of(4);
+        if self.write_pos + size > SECTOR_SIZE {
+            return Ok(false);
+        }
+        let mut buf = [0u8; RECORD_HEADER_SIZE as usize + MAX_VALUE_LEN];
+        buf[0..2].copy_from_slice(&key.to_le_bytes());
+        buf[2..4].copy_from_slice(&len.to_le_bytes());
+        buf[8..8 + value.len()].copy_from_slice(value);
+        let mut crc = Crc32::new();
+        crc.update(&buf[..4]);
+        crc.update(value);
+        buf[4..8].copy_from_slice(&crc.finish().to_le_bytes());
+        trace!("kv append key {} len {} at {}", key, len, self.write_pos);
+        let pos = self.write_pos;
+        // Advance first, so a failed write isn't overwritten
+        self.write_pos += size;
+        self.program(active, pos, &buf[..8 + value.len()])?;
+        Ok(true)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), FlashError> {
+        let addr = self.offset + sector * SECTOR_SIZE;
+        self.flash.erase(addr, addr + SECTOR_SIZE)
+    }
+
+    /// Program `bytes` at `pos`, leaving the rest of the affected pages unchanged
+    fn program(&mut self, sector: u32, pos: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let addr = self.offset + sector * SECTOR_SIZE + pos;
+        let first = addr & !(PAGE_SIZE - 1);
+        let last = (addr + bytes.len() as u32).next_multiple_of(PAGE_SIZE);
+        // Programming 0xff leaves bits unchanged
+        let mut pages = [0xffu8; 3 * PAGE_SIZE as usize];
+        let start = (addr - first) as usize;
+        pages[start..start + bytes.len()].copy_from_slice(bytes);
+        self.flash.write(first, &pages[..(last - first) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    fn get(store: &mut Store<MockFlash>, key: u16) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        let len = store.get(key, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn sets_and_deletes_values() {
+        let mut store = Store::with_flash(MockFlash::new(0x4000), 0x1000, 3).unwrap();
+        assert_eq!(get(&mut store, 1), None);
+        store.set(1, b"one").unwrap();
+        store.set(2, b"two").unwrap();
+        store.set(1, b"uno").unwrap();
+        assert_eq!(get(&mut store, 1).as_deref(), Some(&b"uno"[..]));
+        store.delete(2).unwrap();
+        assert_eq!(get(&mut store, 2), None);
+        let mut buf = [0u8; 2];
+        assert_eq!(store.get(1, &mut buf), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn keeps_values_across_compaction_and_remount() {
+        let mut store = Store::with_flash(MockFlash::new(0x3000), 0, 3).unwrap();
+        store.set(7, &[7; 100]).unwrap();
+        for i in 0..2000u32 {
+            store.set(1, &i.to_le_bytes()).unwrap();
+        }
+        let mut store = Store::with_flash(store.flash, 0, 3).unwrap();
+        assert_eq!(get(&mut store, 1), Some(1999u32.to_le_bytes().to_vec()));
+        assert_eq!(get(&mut store, 7), Some(vec![7; 100]));
+        // Updates are spread over all sectors
+        assert!((0..3).all(|sector| store.flash.erase_count(sector * 0x1000) > 1));
+    }
+
+    #[test]
+    fn ignores_uncommitted_sector() {
+        let mut store = Store::with_flash(MockFlash::new(0x3000), 0, 3).unwrap();
+        store.set(1, b"old").unwrap();
+        // A header torn before its commit word, with a higher sequence number
+        let mut page = [0xffu8; 256];
+        page[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        page[4..8].copy_from_slice(&5u32.to_le_bytes());
+        store.flash.program(0x1000, &page).unwrap();
+        let mut store = Store::with_flash(store.flash, 0, 3).unwrap();
+        assert_eq!(store.active, Some(0));
+        store.set(2, b"new").unwrap();
+        assert_eq!(get(&mut store, 1).as_deref(), Some(&b"old"[..]));
+        // The spare sector was erased again before use
+        assert_eq!(store.flash.erase_count(0x1000), 2);
+    }
+
+    #[test]
+    fn erases_partially_erased_spare() {
+        let mut store = Store::with_flash(MockFlash::new(0x2000), 0, 2).unwrap();
+        store.set(1, b"value").unwrap();
+        // Garbage left at the end of the spare sector by an interrupted erase
+        store.flash.program(0x1f00, &[0; 256]).unwrap();
+        let mut store = Store::with_flash(store.flash, 0, 2).unwrap();
+        store.set(2, b"other").unwrap();
+        assert_eq!(store.flash.erase_count(0x1000), 2);
+        store.compact().unwrap();
+        assert_eq!(get(&mut store, 1).as_deref(), Some(&b"value"[..]));
+        assert_eq!(get(&mut store, 2).as_deref(), Some(&b"other"[..]));
+    }
+
+    #[test]
+    fn rejects_large_values() {
+        let mut store = Store::with_flash(MockFlash::new(0x2000), 0, 2).unwrap();
+        assert_eq!(
+            store.set(1, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::ValueTooLarge)
+        );
+    }
+}
@@ -1,8 +1,125 @@
-#![no_std]
+#![cfg_attr(not(any(test, feature = "mock")), no_std)]
 
+#[macro_use]
+mod fmt;
+
+#[cfg(all(feature = "rp2040", feature = "rp235x"))]
+compile_error!("You may not enable both `rp2040` and `rp235x` features.");
+#[cfg(all(feature = "no-hal", any(feature = "rp2040", feature = "rp235x")))]
+compile_error!("The `no-hal` feature replaces `rp2040`, use it with `default-features = false`.");
+#[cfg(all(feature = "mock", target_os = "none"))]
+compile_error!("The `mock` feature needs `std` and is only available on the host.");
+#[cfg(all(
+    target_os = "none",
+    not(any(feature = "rp2040", feature = "rp235x", feature = "no-hal"))
+))]
+compile_error!("One of the `rp2040`, `no-hal` or `rp235x` features must be enabled.");
+#[cfg(all(feature = "rp235x", feature = "mpu-guard"))]
+compile_error!("The `mpu-guard` feature is not supported on the RP2350.");
+
+#[cfg(target_os = "none")]
+pub mod address;
+#[cfg(target_os = "none")]
+pub mod block;
+#[cfg(target_os = "none")]
+pub mod block_protect;
+#[cfg(all(target_os = "none", not(feature = "rp235x")))]
+pub mod boot2;
+#[cfg(target_os = "none")]
+pub mod bootsel;
+#[cfg(all(target_os = "none", not(feature = "rp235x")))]
+pub mod bus_monitor;
+pub mod chip;
+#[cfg(target_os = "none")]
+pub mod chunked;
+pub mod config;
+pub mod crc;
+#[cfg(target_os = "none")]
+mod cs;
+#[cfg(target_os = "none")]
+pub mod driver;
+pub mod eeprom;
+#[cfg(all(target_os = "none", feature = "ekv"))]
+pub mod ekv;
+pub mod error;
+#[cfg(target_os = "none")]
+pub mod interrupts;
+pub mod kv;
+pub mod mcuboot;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(all(target_os = "none", feature = "mpu-guard"))]
+pub mod mpu_guard;
+#[cfg(target_os = "none")]
+pub mod multicore;
+pub mod nor_flash;
+#[cfg(all(target_os = "none", feature = "async"))]
+pub mod nor_flash_async;
+pub mod partition;
+#[cfg(target_os = "none")]
+pub mod probe;
+#[cfg(target_os = "none")]
+pub mod protect;
+#[cfg(target_os = "none")]
+pub mod quad_enable;
+#[cfg(target_os = "none")]
+pub mod ram;
+#[cfg(target_os = "none")]
+pub mod region;
+pub mod retry;
+#[cfg(target_os = "none")]
+mod rom;
+#[cfg(target_os = "none")]
+pub mod security_register;
+pub mod sfdp;
+pub mod smp;
+#[cfg(target_os = "none")]
+pub mod status_lock;
+pub mod storage;
+#[cfg(target_os = "none")]
+pub mod stream;
+#[cfg(target_os = "none")]
+pub mod suspend;
+#[cfg(all(target_os = "none", feature = "timing"))]
+pub mod timing;
+#[cfg(target_os = "none")]
+pub mod transaction;
+#[cfg(target_os = "none")]
+pub mod uid;
+#[cfg(target_os = "none")]
+pub mod update;
+pub mod wear;
+#[cfg(target_os = "none")]
+pub mod xip;
+
+/// Sector and page size; the flash operations only build for the RP2040 and RP2350
+#[cfg(not(target_os = "none"))]
+pub mod flash {
+    /// Size of an erasable sector
+    pub const SECTOR_SIZE: u32 = 4096;
+    /// Size of a programmable page
+    pub const PAGE_SIZE: u32 = 256;
+}
+
+#[cfg(target_os = "none")]
 pub mod flash {
+    use crate::address::FlashAddress;
+    #[cfg(not(feature = "rp235x"))]
+    use crate::boot2::Boot2;
+
+    /// The RP2350 has no 2nd stage boot loader, so there is never a `Boot2`
+    #[cfg(feature = "rp235x")]
+    enum Boot2 {}
+    use crate::chip;
+    use crate::error::{FlashError, VerifyError};
+    use crate::probe;
+    use crate::protect;
+    use crate::retry::RetryPolicy;
+    use crate::rom;
+    use crate::sfdp;
+    use crate::xip;
     use core::marker::PhantomData;
-    use rp2040_hal::rom_data;
+    use core::sync::atomic::{AtomicU32, Ordering};
 
     #[repr(C)]
     struct FlashFunctionPointers<'a> {
@@ -16,29 +133,51 @@ pub mod flash {
         flash_flush_cache: unsafe extern "C" fn() -> (),
         flash_enter_cmd_xip: unsafe extern "C" fn() -> (),
         phantom: PhantomData<&'a ()>,
+        /// XIP read configuration restored by `enter_cmd_xip_restore`
+        #[cfg(feature = "rp235x")]
+        xip_restore: XipRestore,
+    }
+
+    /// QMI window 0 configuration, saved before a flash operation
+    #[cfg(feature = "rp235x")]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct XipRestore {
+        flash_enter_cmd_xip: unsafe extern "C" fn() -> (),
+        m0_timing: u32,
+        m0_rfmt: u32,
+        m0_rcmd: u32,
     }
 
     #[allow(unused)]
     fn flash_function_pointers(erase: bool, write: bool) -> FlashFunctionPointers<'static> {
         FlashFunctionPointers {
-            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
-            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            connect_internal_flash: rom::connect_internal_flash(),
+            flash_exit_xip: rom::flash_exit_xip(),
             flash_range_erase: if erase {
-                Some(rom_data::flash_range_erase::ptr())
+                Some(rom::flash_range_erase())
             } else {
                 None
             },
             flash_range_program: if write {
-                Some(rom_data::flash_range_program::ptr())
+                Some(rom::flash_range_program())
             } else {
                 None
             },
-            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
-            flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+            flash_flush_cache: rom::flash_flush_cache(),
+            flash_enter_cmd_xip: rom::flash_enter_cmd_xip(),
             phantom: PhantomData,
+            #[cfg(feature = "rp235x")]
+            xip_restore: XipRestore {
+                flash_enter_cmd_xip: rom::flash_enter_cmd_xip(),
+                m0_timing: 0,
+                m0_rfmt: 0,
+                m0_rcmd: 0,
+            },
         }
     }
 
+    #[cfg(not(feature = "rp235x"))]
     #[allow(unused)]
     /// # Safety
     ///
@@ -47,28 +186,250 @@ pub mod flash {
         erase: bool,
         write: bool,
         boot2: &[u32; 64],
-    ) -> FlashFunctionPointers {
+    ) -> FlashFunctionPointers<'_> {
         let boot2_fn_ptr = (boot2 as *const u32 as *const u8).offset(1);
         let boot2_fn: unsafe extern "C" fn() -> () = core::mem::transmute(boot2_fn_ptr);
         FlashFunctionPointers {
-            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
-            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
-            flash_range_erase: if erase {
-                Some(rom_data::flash_range_erase::ptr())
-            } else {
-                None
-            },
-            flash_range_program: if write {
-                Some(rom_data::flash_range_program::ptr())
-            } else {
-                None
-            },
-            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
             flash_enter_cmd_xip: boot2_fn,
-            phantom: PhantomData,
+            ..flash_function_pointers(erase, write)
+        }
+    }
+
+    /// Like `flash_function_pointers`, but restores the current XIP read
+    /// configuration after `flash_enter_cmd_xip`
+    ///
+    /// The RP2350 has no 2nd stage boot loader in flash. Instead, the QMI
+    /// registers configured by the boot ROM or the application are saved
+    /// and written back.
+    #[cfg(feature = "rp235x")]
+    fn flash_function_pointers_restoring_xip(
+        erase: bool,
+        write: bool,
+    ) -> FlashFunctionPointers<'static> {
+        let mut ptrs = flash_function_pointers(erase, write);
+        // Safety: reads of the QMI window 0 configuration registers
+        unsafe {
+            ptrs.xip_restore.m0_timing = core::ptr::read_volatile((QMI_BASE + 0x0c) as *const u32);
+            ptrs.xip_restore.m0_rfmt = core::ptr::read_volatile((QMI_BASE + 0x10) as *const u32);
+            ptrs.xip_restore.m0_rcmd = core::ptr::read_volatile((QMI_BASE + 0x14) as *const u32);
+            // Called with the pointer table in r0, see `enter_cmd_xip_restore`
+            ptrs.flash_enter_cmd_xip = core::mem::transmute::<
+                unsafe extern "C" fn(*const FlashFunctionPointers),
+                unsafe extern "C" fn(),
+            >(enter_cmd_xip_restore);
+        }
+        ptrs
+    }
+
+    #[cfg(feature = "rp235x")]
+    const QMI_BASE: u32 = 0x400d_0000;
+
+    /// Call `flash_enter_cmd_xip` and restore the saved QMI window 0 configuration
+    ///
+    /// All callers of `flash_enter_cmd_xip` pass the pointer table in r0.
+    #[cfg(feature = "rp235x")]
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe extern "C" fn enter_cmd_xip_restore(ptrs: *const FlashFunctionPointers) {
+        core::arch::asm!(
+            "mov r4, r0",
+            "ldr r1, [r4, #24]",
+            "blx r1", // flash_enter_cmd_xip()
+
+            "movs r0, #0x40",
+            "lsls r0, r0, #24",
+            "movs r1, #0x0d",
+            "lsls r1, r1, #16",
+            "adds r0, r0, r1", // 0x400d0000, QMI
+            "ldr r1, [r4, #28]",
+            "str r1, [r0, #0x0c]", // M0_TIMING
+            "ldr r1, [r4, #32]",
+            "str r1, [r0, #0x10]", // M0_RFMT
+            "ldr r1, [r4, #36]",
+            "str r1, [r0, #0x14]", // M0_RCMD
+            in("r0") ptrs,
+            out("r4") _,
+            clobber_abi("C"),
+        );
+    }
+
+    /// Call `f` with pointers to the ROM flash functions
+    ///
+    /// If `boot2` is given, it is used to re-initialize the XIP engine,
+    /// avoiding a copy. Otherwise, if `use_boot2` is `true`, a copy of the
+    /// 2nd stage boot loader is made for this call. On the RP2350, the XIP
+    /// read configuration is saved and restored instead.
+    ///
+    /// # Safety
+    ///
+    /// If `use_boot2` is `true`, flash must contain a valid 2nd stage boot loader.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `boot2` isn't located in RAM.
+    unsafe fn with_function_pointers<R>(
+        erase: bool,
+        write: bool,
+        use_boot2: bool,
+        boot2: Option<&Boot2>,
+        f: impl FnOnce(&mut FlashFunctionPointers) -> R,
+    ) -> R {
+        #[cfg(not(feature = "rp235x"))]
+        {
+            let mut copy = [0u32; 256 / 4];
+            let mut ptrs = match boot2 {
+                Some(boot2) => flash_function_pointers_with_boot2(erase, write, boot2.words()),
+                None if use_boot2 => {
+                    rom::memcpy44()(&mut copy as *mut _, 0x10000000 as *const _, 256);
+                    flash_function_pointers_with_boot2(erase, write, &copy)
+                }
+                None => flash_function_pointers(erase, write),
+            };
+            xip_disabled(|| f(&mut ptrs))
+        }
+        #[cfg(feature = "rp235x")]
+        {
+            let mut ptrs = if use_boot2 || boot2.is_some() {
+                flash_function_pointers_restoring_xip(erase, write)
+            } else {
+                flash_function_pointers(erase, write)
+            };
+            xip_disabled(|| f(&mut ptrs))
+        }
+    }
+
+    /// Run `f`, which calls RAM-resident code disabling XIP
+    ///
+    /// With the `timing` feature, its duration is recorded.
+    #[inline(always)]
+    fn xip_disabled<R>(f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "timing")]
+        return crate::timing::section(f);
+        #[cfg(not(feature = "timing"))]
+        f()
+    }
+
+    /// Like [`flash_range_erase`], re-initializing XIP with `boot2`
+    ///
+    /// Avoids copying the 2nd stage boot loader for each call.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `boot2` isn't located in RAM.
+    #[cfg(not(feature = "rp235x"))]
+    pub unsafe fn flash_range_erase_with_boot2(addr: u32, len: u32, boot2: &Boot2) {
+        assert!(addr < 0x1000000);
+        timed("flash_range_erase", addr, len, || {
+            with_function_pointers(true, false, true, Some(boot2), |ptrs| {
+                write_flash(addr, len, None, ptrs)
+            })
+        });
+    }
+
+    /// Like [`flash_range_erase_and_program`], re-initializing XIP with `boot2`
+    ///
+    /// Avoids copying the 2nd stage boot loader for each call.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `boot2` isn't located in RAM.
+    #[cfg(not(feature = "rp235x"))]
+    pub unsafe fn flash_range_erase_and_program_with_boot2(addr: u32, data: &[u8], boot2: &Boot2) {
+        assert!(addr < 0x1000000);
+        let len = data.len() as u32;
+        timed("flash_range_erase_and_program", addr, len, || {
+            with_function_pointers(true, true, true, Some(boot2), |ptrs| {
+                write_flash(addr, len, Some(data), ptrs)
+            })
+        });
+    }
+
+    /// Like [`flash_range_program`], re-initializing XIP with `boot2`
+    ///
+    /// Avoids copying the 2nd stage boot loader for each call.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_program`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `boot2` isn't located in RAM.
+    #[cfg(not(feature = "rp235x"))]
+    pub unsafe fn flash_range_program_with_boot2(addr: u32, data: &[u8], boot2: &Boot2) {
+        assert!(addr < 0x1000000);
+        let len = data.len() as u32;
+        timed("flash_range_program", addr, len, || {
+            with_function_pointers(false, true, true, Some(boot2), |ptrs| {
+                write_flash(addr, len, Some(data), ptrs)
+            })
+        });
+    }
+
+    /// How to update the XIP cache after modifying flash
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CacheMaintenance {
+        /// Flush the whole cache, using the ROM function `flash_flush_cache`
+        FlushAll,
+        /// Only invalidate the cache lines covering the modified range
+        ///
+        /// Code and data from other parts of flash stay cached, reducing
+        /// the slowdown after the operation. Invalidation costs one bus write
+        /// per 8 byte cache line, so for large ranges, `FlushAll` is faster.
+        ///
+        /// On the RP2350, this is the same as `FlushAll`.
+        InvalidateRange,
+    }
+
+    impl CacheMaintenance {
+        fn apply(self, ptrs: &mut FlashFunctionPointers) {
+            if cfg!(not(feature = "rp235x")) && self == CacheMaintenance::InvalidateRange {
+                ptrs.flash_flush_cache = release_cs;
+            }
+        }
+
+        fn finish(self, addr: u32, len: u32) {
+            if self == CacheMaintenance::InvalidateRange {
+                #[cfg(not(feature = "rp235x"))]
+                xip::cache_invalidate_range(addr, len);
+                #[cfg(feature = "rp235x")]
+                let _ = (addr, len);
+            }
         }
     }
 
+    /// Replacement for `flash_flush_cache` which doesn't touch the cache
+    ///
+    /// Only releases the chip select override set up by `flash_exit_xip`,
+    /// which `flash_flush_cache` does as well.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe extern "C" fn release_cs() {
+        core::arch::asm!(
+            "movs r0, #0x40",
+            "lsls r0, r0, #24",
+            "movs r1, #0x18",
+            "lsls r1, r1, #12",
+            "adds r0, r0, r1", // 0x40018000, IO_QSPI
+            "ldr r1, [r0, #0x0c]", // GPIO_QSPI_SS_CTRL
+            "movs r2, #3",
+            "lsls r2, r2, #8",
+            "bics r1, r2", // OUTOVER = 0, normal
+            "str r1, [r0, #0x0c]",
+            out("r0") _,
+            out("r1") _,
+            out("r2") _,
+        );
+    }
+
     /// Erase a flash range starting at `addr` with length `len`.
     ///
     /// `addr` and `len` must be multiples of 4096.
@@ -88,16 +449,129 @@ pub mod flash {
     ///   - DMA must not access flash memory
     ///
     /// `addr` and `len` parameters must be valid and are not checked.
+    ///
+    /// Regions protected using [`protect`] are not checked, see the
+    /// `_checked` variants for that.
     pub unsafe fn flash_range_erase(addr: u32, len: u32, use_boot2: bool) {
+        flash_range_erase_with_cache(addr, len, use_boot2, CacheMaintenance::FlushAll);
+    }
+
+    /// Like [`flash_range_erase`], selecting how the XIP cache is updated afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase`].
+    pub unsafe fn flash_range_erase_with_cache(
+        addr: u32,
+        len: u32,
+        use_boot2: bool,
+        cache: CacheMaintenance,
+    ) {
         assert!(addr < 0x1000000);
-        let mut boot2 = [0u32; 256 / 4];
-        let ptrs = if use_boot2 {
-            rom_data::memcpy44(&mut boot2 as *mut _, 0x10000000 as *const _, 256);
-            flash_function_pointers_with_boot2(true, false, &boot2)
-        } else {
-            flash_function_pointers(true, false)
-        };
-        write_flash_inner(addr, len, None, &ptrs as *const FlashFunctionPointers);
+        timed("flash_range_erase", addr, len, || {
+            with_function_pointers(true, false, use_boot2, None, |ptrs| {
+                cache.apply(ptrs);
+                write_flash(addr, len, None, ptrs);
+            });
+            cache.finish(addr, len);
+        });
+    }
+
+    /// Erase command used for aligned parts of a range
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum EraseGranularity {
+        /// Only use 4 KiB sector erase (0x20)
+        Sector4K,
+        /// Use 32 KiB block erase (0x52) where possible
+        Block32K,
+        /// Use 64 KiB block erase (0xD8) where possible
+        Block64K,
+    }
+
+    impl EraseGranularity {
+        /// Block size and command as passed to the ROM's `flash_range_erase`
+        ///
+        /// A block size of `1 << 31` disables block erase.
+        fn rom_args(self) -> (u32, u8) {
+            match self {
+                EraseGranularity::Sector4K => (1 << 31, 0),
+                EraseGranularity::Block32K => (0x8000, 0x52),
+                EraseGranularity::Block64K => (0x10000, 0xd8),
+            }
+        }
+    }
+
+    /// Like [`flash_range_erase`], using block erase commands for large aligned parts
+    ///
+    /// The ROM function erases each part of the range aligned to the block
+    /// size of `granularity` with a single block erase, and the rest with
+    /// 4 KiB sector erases. Erasing a 64 KiB block takes about as long as
+    /// erasing a single sector, so this is much faster for large ranges.
+    ///
+    /// Most chips support 64 KiB block erase. 32 KiB block erase is less
+    /// common; check the data sheet of the flash chip.
+    ///
+    /// `addr` and `len` must be multiples of 4096, whatever the granularity.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addr` or `len` isn't sector-aligned.
+    pub unsafe fn flash_range_erase_with(
+        addr: u32,
+        len: u32,
+        granularity: EraseGranularity,
+        use_boot2: bool,
+    ) {
+        assert!(addr & (SECTOR_SIZE - 1) == 0 && len & (SECTOR_SIZE - 1) == 0);
+        assert!(addr.checked_add(len).is_some_and(|end| end <= 0x1000000));
+        let (block_size, block_cmd) = granularity.rom_args();
+        trace!("flash_range_erase_with block {:#x}", block_size);
+        timed("flash_range_erase_with", addr, len, || {
+            with_function_pointers(true, false, use_boot2, None, |ptrs| {
+                probe::busy(|| erase_flash_inner(addr, len, block_size | block_cmd as u32, ptrs))
+            })
+        });
+    }
+
+    /// Erase the whole flash chip using chip erase (0xC7)
+    ///
+    /// This takes several seconds up to minutes, depending on the chip.
+    /// Fails with [`FlashError::WriteProtected`] if any region is
+    /// protected using [`protect`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// This also erases the running program and the 2nd stage boot
+    /// loader, so it can only be used by a program running entirely from
+    /// RAM, like one linked to RAM and loaded by a debugger. This function,
+    /// the caller and everything it returns to must be located in RAM.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this function is located in flash.
+    pub unsafe fn flash_chip_erase(use_boot2: bool) -> Result<(), FlashError> {
+        assert!(
+            crate::ram::is_flash_independent(flash_chip_erase as *const ()),
+            "flash_chip_erase needs a program running from RAM"
+        );
+        protect::check(0, 0x1000000)?;
+        debug!("flash_chip_erase");
+        timed("flash_chip_erase", 0, 0x1000000, || {
+            // Polls SR1.BUSY until the erase completed
+            write_enabled_cmd(&[0xc7], chip::Operation::ChipErase, use_boot2)?;
+            // The 2nd stage boot loader is gone now
+            flash_check_failure(chip::Operation::ChipErase, false)
+        })
     }
 
     /// Erase and rewrite a flash range starting at `addr` with data `data`.
@@ -119,21 +593,33 @@ pub mod flash {
     ///   - DMA must not access flash memory
     ///
     /// `addr` and `len` parameters must be valid and are not checked.
+    ///
+    /// Regions protected using [`protect`] are not checked, see the
+    /// `_checked` variants for that.
     pub unsafe fn flash_range_erase_and_program(addr: u32, data: &[u8], use_boot2: bool) {
+        flash_range_erase_and_program_with_cache(addr, data, use_boot2, CacheMaintenance::FlushAll);
+    }
+
+    /// Like [`flash_range_erase_and_program`], selecting how the XIP cache is updated afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    pub unsafe fn flash_range_erase_and_program_with_cache(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+        cache: CacheMaintenance,
+    ) {
         assert!(addr < 0x1000000);
-        let mut boot2 = [0u32; 256 / 4];
-        let ptrs = if use_boot2 {
-            rom_data::memcpy44(&mut boot2 as *mut _, 0x10000000 as *const _, 256);
-            flash_function_pointers_with_boot2(true, true, &boot2)
-        } else {
-            flash_function_pointers(true, true)
-        };
-        write_flash_inner(
-            addr,
-            data.len() as u32,
-            Some(data),
-            &ptrs as *const FlashFunctionPointers,
-        );
+        let len = data.len() as u32;
+        timed("flash_range_erase_and_program", addr, len, || {
+            with_function_pointers(true, true, use_boot2, None, |ptrs| {
+                cache.apply(ptrs);
+                write_flash(addr, len, Some(data), ptrs);
+            });
+            cache.finish(addr, len);
+        });
     }
 
     /// Write a flash range starting at `addr` with data `data`.
@@ -155,76 +641,1629 @@ pub mod flash {
     ///   - DMA must not access flash memory
     ///
     /// `addr` and `len` parameters must be valid and are not checked.
+    ///
+    /// Regions protected using [`protect`] are not checked, see the
+    /// `_checked` variants for that.
     pub unsafe fn flash_range_program(addr: u32, data: &[u8], use_boot2: bool) {
+        flash_range_program_with_cache(addr, data, use_boot2, CacheMaintenance::FlushAll);
+    }
+
+    /// Like [`flash_range_program`], selecting how the XIP cache is updated afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_program`].
+    pub unsafe fn flash_range_program_with_cache(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+        cache: CacheMaintenance,
+    ) {
         assert!(addr < 0x1000000);
-        let mut boot2 = [0u32; 256 / 4];
-        let ptrs = if use_boot2 {
-            rom_data::memcpy44(&mut boot2 as *mut _, 0x10000000 as *const _, 256);
-            flash_function_pointers_with_boot2(false, true, &boot2)
-        } else {
-            flash_function_pointers(false, true)
-        };
-        write_flash_inner(
-            addr,
-            data.len() as u32,
-            Some(data),
-            &ptrs as *const FlashFunctionPointers,
-        );
+        let len = data.len() as u32;
+        timed("flash_range_program", addr, len, || {
+            with_function_pointers(false, true, use_boot2, None, |ptrs| {
+                cache.apply(ptrs);
+                write_flash(addr, len, Some(data), ptrs);
+            });
+            cache.finish(addr, len);
+        });
     }
 
+    /// Like [`flash_range_erase`], calling `feed` after each sector
+    ///
+    /// Erasing a large range takes much longer than a typical watchdog
+    /// timeout. Instead of disabling the watchdog, `feed` can reload it.
+    /// It is called while XIP is disabled, so it must be located in RAM,
+    /// e.g. using the [`ram!`](crate::ram!) macro, and must not access flash.
+    ///
     /// # Safety
     ///
-    /// Nothing must access flash while this is running.
-    /// Usually this means:
-    ///   - interrupts must be disabled
-    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
-    ///   - DMA must not access flash memory
+    /// Same as for [`flash_range_erase`].
     ///
-    /// Length of data must be a multiple of 4096
-    /// addr must be aligned to 4096
-    #[inline(never)]
-    #[link_section = ".data.ram_func"]
-    unsafe fn write_flash_inner(
+    /// # Panics
+    ///
+    /// Panics if `feed` isn't located in RAM or ROM, see [`ram::assert_in_ram`],
+    /// or if `addr` or `len` isn't a multiple of 4096.
+    ///
+    /// [`ram::assert_in_ram`]: crate::ram::assert_in_ram
+    pub unsafe fn flash_range_erase_with_feed(
+        addr: u32,
+        len: u32,
+        use_boot2: bool,
+        feed: extern "C" fn(),
+    ) {
+        write_flash_with_feed(true, addr, len, None, use_boot2, feed);
+    }
+
+    /// Like [`flash_range_erase_and_program`], calling `feed` after each sector and page
+    ///
+    /// Each sector is erased and programmed before moving on to the next.
+    /// See [`flash_range_erase_with_feed`] for the requirements on `feed`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `feed` isn't located in RAM or ROM, or if `addr` or `data.len()`
+    /// isn't a multiple of 4096.
+    pub unsafe fn flash_range_erase_and_program_with_feed(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+        feed: extern "C" fn(),
+    ) {
+        write_flash_with_feed(true, addr, data.len() as u32, Some(data), use_boot2, feed);
+    }
+
+    /// Like [`flash_range_program`], calling `feed` after each page
+    ///
+    /// See [`flash_range_erase_with_feed`] for the requirements on `feed`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_program`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `feed` isn't located in RAM or ROM, or if `addr` or `data.len()`
+    /// isn't a multiple of 256.
+    pub unsafe fn flash_range_program_with_feed(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+        feed: extern "C" fn(),
+    ) {
+        write_flash_with_feed(false, addr, data.len() as u32, Some(data), use_boot2, feed);
+    }
+
+    unsafe fn write_flash_with_feed(
+        erase: bool,
         addr: u32,
         len: u32,
         data: Option<&[u8]>,
-        ptrs: *const FlashFunctionPointers,
+        use_boot2: bool,
+        feed: extern "C" fn(),
     ) {
-        /*
-         Should be equivalent to:
-            rom_data::connect_internal_flash();
-            rom_data::flash_exit_xip();
-            rom_data::flash_range_erase(addr, len, 1 << 31, 0); // if selected
-            rom_data::flash_range_program(addr, data as *const _, len); // if selected
-            rom_data::flash_flush_cache();
-            rom_data::flash_enter_cmd_xip();
-        */
+        crate::ram::assert_in_ram(feed as *const ());
+        assert!(addr.checked_add(len).is_some_and(|end| end <= 0x1000000));
+        // The RAM routine works on whole pages, and erases whole sectors
+        assert!(data.is_none_or(|data| data.len() == len as usize));
+        assert!(addr & (PAGE_SIZE - 1) == 0 && len & (PAGE_SIZE - 1) == 0);
+        if erase {
+            assert!(addr & (SECTOR_SIZE - 1) == 0 && len & (SECTOR_SIZE - 1) == 0);
+        }
+        timed("flash write with feed", addr, len, || {
+            with_function_pointers(erase, data.is_some(), use_boot2, None, |ptrs| {
+                probe::busy(|| write_flash_with_feed_inner(addr, len, data, feed, ptrs))
+            })
+        });
+    }
+
+    /// Erase and program pages filled by `fill`, with XIP disabled throughout
+    ///
+    /// Used by [`stream::program_from_ram_fn`](crate::stream::program_from_ram_fn).
+    /// Returns the number of bytes programmed.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`]. `addr` and `max_len`
+    /// must be multiples of 4096, and `fill` must be located in RAM.
+    pub(crate) unsafe fn program_from_source(
+        addr: u32,
+        max_len: u32,
+        use_boot2: bool,
+        fill: extern "C" fn(&mut [u8; PAGE_SIZE as usize]) -> bool,
+    ) -> u32 {
+        crate::ram::assert_in_ram(fill as *const ());
+        let mut page = [0xffu8; PAGE_SIZE as usize];
+        timed("flash program from source", addr, max_len, || {
+            with_function_pointers(true, true, use_boot2, None, |ptrs| {
+                probe::busy(|| program_from_source_inner(addr, max_len, &mut page, fill, ptrs))
+            })
+        })
+    }
+
+    /// Loop over pages: reset the page buffer, call `fill`, erase the
+    /// sector if the page starts one, program the page
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. `fill` must be located in RAM.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn program_from_source_inner(
+        addr: u32,
+        max_len: u32,
+        page: &mut [u8; PAGE_SIZE as usize],
+        fill: extern "C" fn(&mut [u8; PAGE_SIZE as usize]) -> bool,
+        ptrs: *const FlashFunctionPointers,
+    ) -> u32 {
+        let args = [page.as_mut_ptr() as usize, fill as usize];
+        let end: u32;
         core::arch::asm!(
-            "mov r8, r0",
-            "mov r9, r2",
-            "mov r10, r1",
-            "ldr r4, [{ptrs}, #0]",
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            // r5-r9 are preserved by the ROM functions and `fill`.
+            "push {{r4, r5, r6, r7}}",
+            "mov r5, r3", // ptrs
+            "mov r6, r0", // addr
+            "adds r7, r0, r1", // end
+            "ldr r0, [r2, #0]",
+            "mov r8, r0", // page
+            "ldr r0, [r2, #4]",
+            "mov r9, r0", // fill
+
+            "ldr r4, [r5, #0]",
             "blx r4", // connect_internal_flash()
 
-            "ldr r4, [{ptrs}, #4]",
+            "ldr r4, [r5, #4]",
             "blx r4", // flash_exit_xip()
 
-            "mov r0, r8", // r0 = addr
-            "mov r1, r10", // r1 = len
+            // Loop over pages
+            "1:",
+            "cmp r6, r7",
+            "bhs 9f",
+            "mov r0, r8",
+            "movs r1, #0",
+            "mvns r1, r1", // 0xffffffff
+            "movs r2, #1",
+            "lsls r2, r2, #8", // 256
+            "2:",
+            "subs r2, #4",
+            "str r1, [r0, r2]",
+            "bne 2b",
+            "mov r0, r8",
+            "blx r9", // fill(page)
+            "lsls r0, r0, #24",
+            "beq 9f", // no more data
+
+            "lsls r0, r6, #20",
+            "bne 3f", // not at a sector boundary
+            "mov r0, r6", // r0 = addr
+            "movs r1, #1",
+            "lsls r1, r1, #12", // r1 = 4096
             "movs r2, #1",
             "lsls r2, r2, #31", // r2 = 1 << 31
             "movs r3, #0", // r3 = 0
-            "ldr r4, [{ptrs}, #8]",
-            "cmp r4, #0",
-            "beq 1f",
-            "blx r4", // flash_range_erase(addr, len, 1 << 31, 0)
-            "1:",
+            "ldr r4, [r5, #8]",
+            "blx r4", // flash_range_erase(addr, 4096, 1 << 31, 0)
 
-            "mov r0, r8", // r0 = addr
-            "mov r1, r9", // r0 = data
-            "mov r2, r10", // r2 = len
-            "ldr r4, [{ptrs}, #12]",
-            "cmp r4, #0",
+            "3:",
+            "mov r0, r6", // r0 = addr
+            "mov r1, r8", // r1 = page
+            "movs r2, #1",
+            "lsls r2, r2, #8", // r2 = 256
+            "ldr r4, [r5, #12]",
+            "blx r4", // flash_range_program(addr, page, 256)
+            "adds r6, #255",
+            "adds r6, #1", // addr += 256
+            "b 1b",
+
+            "9:",
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "mov r0, r6", // address after the last page programmed
+            "pop {{r4, r5, r6, r7}}",
+            inout("r0") addr => end,
+            inout("r1") max_len => _,
+            inout("r2") args.as_ptr() => _,
+            inout("r3") ptrs => _,
+            // Thumb-1 asm can't take inputs in high registers,
+            // so `page` and `fill` are passed in memory.
+            out("r8") _,
+            out("r9") _,
+            clobber_abi("C"),
+        );
+        end - addr
+    }
+
+    /// Erase and/or program sector by sector, calling `feed` in between
+    ///
+    /// Erases each sector if the erase function pointer is set, then
+    /// programs its pages one by one if the program function pointer is
+    /// set. `feed` is called after each erase and each page.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. `feed` must be located in RAM.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn write_flash_with_feed_inner(
+        addr: u32,
+        len: u32,
+        data: Option<&[u8]>,
+        feed: extern "C" fn(),
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        let args = [
+            data.map(|d| d.as_ptr()).unwrap_or(core::ptr::null()) as usize,
+            feed as usize,
+        ];
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            // r5-r9 are preserved by the ROM functions and `feed`.
+            "push {{r4, r5, r6, r7}}",
+            "mov r5, r3", // ptrs
+            "mov r6, r0", // addr
+            "adds r7, r0, r1", // end
+            "ldr r0, [r2, #0]",
+            "mov r8, r0", // data
+            "ldr r0, [r2, #4]",
+            "mov r9, r0", // feed
+
+            "ldr r4, [r5, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [r5, #4]",
+            "blx r4", // flash_exit_xip()
+
+            // Loop over sectors
+            "1:",
+            "cmp r6, r7",
+            "bhs 9f",
+            "ldr r4, [r5, #8]",
+            "cmp r4, #0",
+            "beq 2f",
+            "mov r0, r6", // r0 = addr
+            "movs r1, #1",
+            "lsls r1, r1, #12", // r1 = 4096
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "blx r4", // flash_range_erase(addr, 4096, 1 << 31, 0)
+            "blx r9", // feed()
+
+            "2:",
+            "ldr r4, [r5, #12]",
+            "cmp r4, #0",
+            "bne 3f",
+            // Erase only, next sector
+            "movs r0, #1",
+            "lsls r0, r0, #12",
+            "adds r6, r6, r0",
+            "b 1b",
+
+            // Loop over pages of the sector
+            "3:",
+            "mov r0, r6", // r0 = addr
+            "mov r1, r8", // r1 = data
+            "movs r2, #1",
+            "lsls r2, r2, #8", // r2 = 256
+            "ldr r4, [r5, #12]",
+            "blx r4", // flash_range_program(addr, data, 256)
+            "blx r9", // feed()
+            "mov r0, r8",
+            "adds r0, #255",
+            "adds r0, #1",
+            "mov r8, r0", // data += 256
+            "adds r6, #255",
+            "adds r6, #1", // addr += 256
+            "cmp r6, r7",
+            "bhs 9f",
+            "lsls r0, r6, #20",
+            "bne 3b", // not at a sector boundary
+            "b 1b",
+
+            "9:",
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "pop {{r4, r5, r6, r7}}",
+            inout("r0") addr => _,
+            inout("r1") len => _,
+            inout("r2") args.as_ptr() => _,
+            inout("r3") ptrs => _,
+            // Thumb-1 asm can't take inputs in high registers,
+            // so `data` and `feed` are passed in memory.
+            out("r8") _,
+            out("r9") _,
+            clobber_abi("C"),
+        );
+    }
+
+    /// Swap `len` bytes at `a` and `b` sector by sector, then reset the chip
+    ///
+    /// Used by [`update`](crate::update) to activate an image. Interrupts
+    /// are disabled and stay disabled, as the running program may be
+    /// overwritten. Everything after the first erase runs from RAM or ROM.
+    ///
+    /// Returns an error, without touching flash, if one of the ranges
+    /// overlaps a region protected using [`protect`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing except this function must access flash from now on:
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///   - the watchdog must not reset the chip before the swap completed
+    pub(crate) unsafe fn swap_and_reboot(
+        a: u32,
+        b: u32,
+        len: u32,
+        use_boot2: bool,
+    ) -> Result<core::convert::Infallible, FlashError> {
+        assert!(a & (SECTOR_SIZE - 1) == 0 && b & (SECTOR_SIZE - 1) == 0);
+        assert!(len & (SECTOR_SIZE - 1) == 0);
+        protect::check(a, len)?;
+        protect::check(b, len)?;
+        debug!("swapping {:#x} and {:#x}, len {:#x}", a, b, len);
+        let mut buf_a = [0u32; SECTOR_SIZE as usize / 4];
+        let mut buf_b = [0u32; SECTOR_SIZE as usize / 4];
+        let args = SwapArgs {
+            a,
+            b,
+            len,
+            buf_a: buf_a.as_mut_ptr(),
+            buf_b: buf_b.as_mut_ptr(),
+            watchdog_ctrl_set: WATCHDOG_CTRL_SET,
+        };
+        cortex_m::interrupt::disable();
+        with_function_pointers::<()>(true, true, use_boot2, None, |ptrs| {
+            swap_and_reboot_inner(&args, ptrs)
+        });
+        unreachable!()
+    }
+
+    /// Erase `len` bytes at `addr`, then call the ROM function `f` with `args`
+    ///
+    /// Used by [`bootsel`](crate::bootsel) to reboot into the USB boot
+    /// loader. Interrupts are disabled and stay disabled, as the running
+    /// program may be erased. Everything after the erase runs from RAM or ROM.
+    ///
+    /// Returns an error, without touching flash, if the range overlaps a
+    /// region protected using [`protect`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing except this function must access flash from now on:
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// `f` must be a ROM function taking up to four `u32` arguments, which
+    /// doesn't return.
+    pub(crate) unsafe fn erase_and_call(
+        addr: u32,
+        len: u32,
+        use_boot2: bool,
+        f: usize,
+        args: [u32; 4],
+    ) -> Result<core::convert::Infallible, FlashError> {
+        assert!(addr & (SECTOR_SIZE - 1) == 0 && len & (SECTOR_SIZE - 1) == 0);
+        assert!(addr.checked_add(len).is_some_and(|end| end <= 0x1000000));
+        protect::check(addr, len)?;
+        debug!("erasing {:#x} len {:#x} before reboot", addr, len);
+        let call = EraseAndCall { addr, len, f, args };
+        cortex_m::interrupt::disable();
+        with_function_pointers::<()>(true, false, use_boot2, None, |ptrs| {
+            erase_and_call_inner(&call, ptrs)
+        });
+        unreachable!()
+    }
+
+    #[repr(C)]
+    struct EraseAndCall {
+        addr: u32,
+        len: u32,
+        f: usize,
+        args: [u32; 4],
+    }
+
+    /// Erase the range described by `call`, then call its function
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. Interrupts must be disabled.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn erase_and_call_inner(
+        call: *const EraseAndCall,
+        ptrs: *const FlashFunctionPointers,
+    ) -> ! {
+        core::arch::asm!(
+            // Never returns, so r4-r7 don't need to be saved.
+            // r5 and r6 are preserved by the ROM functions.
+            "mov r5, r1", // ptrs
+            "mov r6, r0", // call
+
+            "ldr r4, [r5, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [r5, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "ldr r0, [r6, #0]", // r0 = addr
+            "ldr r1, [r6, #4]", // r1 = len
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "ldr r4, [r5, #8]",
+            "blx r4", // flash_range_erase(addr, len, 1 << 31, 0)
+
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "ldr r0, [r6, #12]",
+            "ldr r1, [r6, #16]",
+            "ldr r2, [r6, #20]",
+            "ldr r3, [r6, #24]",
+            "ldr r4, [r6, #8]",
+            "blx r4", // f(args[0], args[1], args[2], args[3])
+            "1:",
+            "b 1b",
+            in("r0") call,
+            in("r1") ptrs,
+            options(noreturn),
+        );
+    }
+
+    /// WATCHDOG CTRL register, atomic bit set alias
+    #[cfg(not(feature = "rp235x"))]
+    const WATCHDOG_CTRL_SET: u32 = 0x4005_8000 + 0x2000;
+    /// WATCHDOG CTRL register, atomic bit set alias
+    #[cfg(feature = "rp235x")]
+    const WATCHDOG_CTRL_SET: u32 = 0x400d_8000 + 0x2000;
+
+    #[repr(C)]
+    struct SwapArgs {
+        a: u32,
+        b: u32,
+        len: u32,
+        buf_a: *mut u32,
+        buf_b: *mut u32,
+        watchdog_ctrl_set: u32,
+    }
+
+    /// Swap the ranges described by `args`, then trigger a watchdog reset
+    ///
+    /// Each sector pair is copied to the buffers through XIP, then both
+    /// sectors are erased and programmed with XIP disabled.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. Interrupts must be disabled.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn swap_and_reboot_inner(
+        args: *const SwapArgs,
+        ptrs: *const FlashFunctionPointers,
+    ) -> ! {
+        core::arch::asm!(
+            // Never returns, so r4-r7 don't need to be saved.
+            // r5-r7 are preserved by the ROM functions.
+            "mov r5, r1", // ptrs
+            "mov r6, r0", // args
+            "movs r7, #0", // offset within the ranges
+
+            // Loop over sectors
+            "1:",
+            "ldr r0, [r6, #8]", // len
+            "cmp r7, r0",
+            "bhs 9f",
+
+            // Copy both sectors to RAM through XIP
+            "movs r2, #1",
+            "lsls r2, r2, #28", // XIP_BASE
+            "ldr r0, [r6, #0]",
+            "adds r0, r0, r7",
+            "adds r0, r0, r2", // XIP address in a
+            "ldr r1, [r6, #12]", // buf_a
+            "movs r2, #1",
+            "lsls r2, r2, #12", // 4096
+            "2:",
+            "subs r2, #4",
+            "ldr r3, [r0, r2]",
+            "str r3, [r1, r2]",
+            "bne 2b",
+
+            "movs r2, #1",
+            "lsls r2, r2, #28", // XIP_BASE
+            "ldr r0, [r6, #4]",
+            "adds r0, r0, r7",
+            "adds r0, r0, r2", // XIP address in b
+            "ldr r1, [r6, #16]", // buf_b
+            "movs r2, #1",
+            "lsls r2, r2, #12", // 4096
+            "3:",
+            "subs r2, #4",
+            "ldr r3, [r0, r2]",
+            "str r3, [r1, r2]",
+            "bne 3b",
+
+            "ldr r4, [r5, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [r5, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "ldr r0, [r6, #0]",
+            "adds r0, r0, r7", // r0 = addr in a
+            "movs r1, #1",
+            "lsls r1, r1, #12", // r1 = 4096
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "ldr r4, [r5, #8]",
+            "blx r4", // flash_range_erase(addr, 4096, 1 << 31, 0)
+
+            "ldr r0, [r6, #0]",
+            "adds r0, r0, r7", // r0 = addr in a
+            "ldr r1, [r6, #16]", // r1 = buf_b
+            "movs r2, #1",
+            "lsls r2, r2, #12", // r2 = 4096
+            "ldr r4, [r5, #12]",
+            "blx r4", // flash_range_program(addr, buf_b, 4096)
+
+            "ldr r0, [r6, #4]",
+            "adds r0, r0, r7", // r0 = addr in b
+            "movs r1, #1",
+            "lsls r1, r1, #12", // r1 = 4096
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "ldr r4, [r5, #8]",
+            "blx r4", // flash_range_erase(addr, 4096, 1 << 31, 0)
+
+            "ldr r0, [r6, #4]",
+            "adds r0, r0, r7", // r0 = addr in b
+            "ldr r1, [r6, #12]", // r1 = buf_a
+            "movs r2, #1",
+            "lsls r2, r2, #12", // r2 = 4096
+            "ldr r4, [r5, #12]",
+            "blx r4", // flash_range_program(addr, buf_a, 4096)
+
+            // Re-enable XIP to read the next sectors
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "movs r0, #1",
+            "lsls r0, r0, #12",
+            "adds r7, r7, r0", // offset += 4096
+            "b 1b",
+
+            // Reset using the watchdog
+            "9:",
+            "ldr r0, [r6, #20]", // watchdog_ctrl_set
+            "movs r1, #1",
+            "lsls r1, r1, #31", // TRIGGER
+            "str r1, [r0]",
+            "8:",
+            "b 8b",
+            in("r0") args,
+            in("r1") ptrs,
+            options(noreturn),
+        );
+    }
+
+    /// Like [`flash_range_erase`], but checks the flash chip's failure flags afterwards.
+    ///
+    /// See [`flash_check_failure`] for details.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase`].
+    pub unsafe fn flash_range_erase_checked(
+        addr: u32,
+        len: u32,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        protect::check(addr, len)?;
+        flash_range_erase(addr, len, use_boot2);
+        flash_check_failure(chip::Operation::SectorErase, use_boot2)
+    }
+
+    /// Like [`flash_range_erase_and_program`], but checks the flash chip's failure flags afterwards.
+    ///
+    /// See [`flash_check_failure`] for details.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    pub unsafe fn flash_range_erase_and_program_checked(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        protect::check(addr, data.len() as u32)?;
+        flash_range_erase_and_program(addr, data, use_boot2);
+        flash_check_failure(chip::Operation::PageProgram, use_boot2)
+    }
+
+    /// Like [`flash_range_program`], but checks the flash chip's failure flags afterwards.
+    ///
+    /// See [`flash_check_failure`] for details.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_program`].
+    pub unsafe fn flash_range_program_checked(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        protect::check(addr, data.len() as u32)?;
+        flash_range_program(addr, data, use_boot2);
+        flash_check_failure(chip::Operation::PageProgram, use_boot2)
+    }
+
+    /// Like [`flash_range_erase_checked`], but validates the arguments first
+    ///
+    /// See [`validate_range`] for the checks done.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn try_flash_range_erase(
+        addr: u32,
+        len: u32,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        validate_range(addr, len, SECTOR_SIZE, use_boot2)?;
+        flash_range_erase_checked(addr, len, use_boot2)
+    }
+
+    /// Like [`flash_range_erase_and_program_checked`], but validates the arguments first
+    ///
+    /// See [`validate_range`] for the checks done.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn try_flash_range_erase_and_program(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        validate_range(addr, data.len() as u32, SECTOR_SIZE, use_boot2)?;
+        flash_range_erase_and_program_checked(addr, data, use_boot2)
+    }
+
+    /// Like [`flash_range_program_checked`], but validates the arguments first
+    ///
+    /// See [`validate_range`] for the checks done.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn try_flash_range_program(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        validate_range(addr, data.len() as u32, PAGE_SIZE, use_boot2)?;
+        flash_range_program_checked(addr, data, use_boot2)
+    }
+
+    /// Like [`try_flash_range_erase`], taking a [`FlashOffset`](crate::address::FlashOffset) or [`XipAddress`](crate::address::XipAddress)
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_range_erase_at(
+        addr: impl FlashAddress,
+        len: u32,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        try_flash_range_erase(addr.flash_offset()?.0, len, use_boot2)
+    }
+
+    /// Like [`try_flash_range_erase_and_program`], taking a [`FlashOffset`](crate::address::FlashOffset) or [`XipAddress`](crate::address::XipAddress)
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_range_erase_and_program_at(
+        addr: impl FlashAddress,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        try_flash_range_erase_and_program(addr.flash_offset()?.0, data, use_boot2)
+    }
+
+    /// Like [`try_flash_range_program`], taking a [`FlashOffset`](crate::address::FlashOffset) or [`XipAddress`](crate::address::XipAddress)
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_range_program_at(
+        addr: impl FlashAddress,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        try_flash_range_program(addr.flash_offset()?.0, data, use_boot2)
+    }
+
+    /// Write `data` at `addr`, without alignment requirements
+    ///
+    /// Like [`flash_write_unaligned_with_buffer`], using a 4 KiB buffer on the stack.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_write_unaligned(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        flash_write_unaligned_with_buffer(addr, data, &mut buf, use_boot2)
+    }
+
+    /// Write `data` at `addr`, without alignment requirements
+    ///
+    /// Each affected sector is read into `buf`, merged with the new data,
+    /// and written back. Sectors whose contents don't change are skipped,
+    /// and sectors which only need bits cleared are programmed without
+    /// erasing, only touching the changed pages.
+    ///
+    /// `addr` is relative to the beginning of the flash area.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// The sectors touched must not contain code or data of the running program.
+    pub unsafe fn flash_write_unaligned_with_buffer(
+        addr: u32,
+        data: &[u8],
+        buf: &mut [u8; SECTOR_SIZE as usize],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        match addr.checked_add(data.len() as u32) {
+            Some(end) if end <= 0x1000000 => {}
+            _ => return Err(FlashError::OutOfBounds),
+        }
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let sector = addr & !(SECTOR_SIZE - 1);
+            let n = data.len().min((sector + SECTOR_SIZE - addr) as usize);
+            write_sector(sector, (addr - sector) as usize, &data[..n], buf, use_boot2)?;
+            addr += n as u32;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Rewrite part of the sector at `sector`, starting at `offset` within the sector
+    unsafe fn write_sector(
+        sector: u32,
+        offset: usize,
+        bytes: &[u8],
+        buf: &mut [u8; SECTOR_SIZE as usize],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let src = (xip::XIP_BASE + sector) as *const u8;
+        core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        let range = offset..offset + bytes.len();
+        let old = &buf[range.clone()];
+        if old == bytes {
+            return Ok(());
+        }
+        // Programming can only clear bits
+        let needs_erase = old.iter().zip(bytes).any(|(&o, &n)| o & n != n);
+        buf[range.clone()].copy_from_slice(bytes);
+        if needs_erase {
+            flash_range_erase_and_program_checked(sector, buf, use_boot2)
+        } else {
+            // Only program the pages which changed
+            let first = range.start as u32 / PAGE_SIZE * PAGE_SIZE;
+            let last = (range.end as u32).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+            let pages = &buf[first as usize..last as usize];
+            flash_range_program_checked(sector + first, pages, use_boot2)
+        }
+    }
+
+    /// Update a flash range to `data`, only writing what changed
+    ///
+    /// Each 256 byte page is compared to the current flash contents.
+    /// Pages which already match are skipped. A sector is only erased if
+    /// one of its pages has bits which need to be set to 1; otherwise just
+    /// the differing pages are programmed. After an erase, only pages which
+    /// aren't all 0xff are programmed. When most of a firmware image or
+    /// asset is unchanged, this saves a lot of time and wear.
+    ///
+    /// `addr` is relative to the beginning of the flash area and must be a
+    /// multiple of [`SECTOR_SIZE`]. `data` may have any length; the rest of
+    /// the last sector is treated as if `data` was padded with 0xff, so it
+    /// reads as 0xff afterwards.
+    ///
+    /// Pages are copied to a buffer on the stack before programming, so
+    /// `data` may itself be located in flash, e.g. in a staging area.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// The sectors touched must not contain code or data of the running program.
+    pub unsafe fn flash_range_update(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        if addr & (SECTOR_SIZE - 1) != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        match addr.checked_add((data.len() as u32).next_multiple_of(SECTOR_SIZE)) {
+            Some(end) if end <= 0x1000000 => {}
+            _ => return Err(FlashError::OutOfBounds),
+        }
+        for (i, sector_data) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+            update_sector(addr + i as u32 * SECTOR_SIZE, sector_data, use_boot2)?;
+        }
+        Ok(())
+    }
+
+    /// Update the sector at `sector` to `data`, padded with 0xff
+    unsafe fn update_sector(sector: u32, data: &[u8], use_boot2: bool) -> Result<(), FlashError> {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut needs_erase = false;
+        let mut changed = 0u16;
+        for i in 0..(SECTOR_SIZE / PAGE_SIZE) as usize {
+            let src = (xip::XIP_BASE + sector + i as u32 * PAGE_SIZE) as *const u8;
+            core::ptr::copy_nonoverlapping(src, page.as_mut_ptr(), page.len());
+            let new = padded_page(data, i);
+            if page.iter().zip(new.clone()).any(|(&o, n)| o != n) {
+                changed |= 1 << i;
+                // Programming can only clear bits
+                needs_erase |= page.iter().zip(new).any(|(&o, n)| o & n != n);
+            }
+        }
+        if changed == 0 {
+            return Ok(());
+        }
+        if needs_erase {
+            trace!("update: erasing sector {:#x}", sector);
+            flash_range_erase_checked(sector, SECTOR_SIZE, use_boot2)?;
+        }
+        for i in 0..(SECTOR_SIZE / PAGE_SIZE) as usize {
+            for (dst, src) in page.iter_mut().zip(padded_page(data, i)) {
+                *dst = src;
+            }
+            let program = if needs_erase {
+                page.iter().any(|&b| b != 0xff)
+            } else {
+                changed & (1 << i) != 0
+            };
+            if program {
+                let addr = sector + i as u32 * PAGE_SIZE;
+                flash_range_program_checked(addr, &page, use_boot2)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of page `i` of `data`, padded with 0xff
+    fn padded_page(data: &[u8], i: usize) -> impl Iterator<Item = u8> + Clone + '_ {
+        let start = (i * PAGE_SIZE as usize).min(data.len());
+        let end = ((i + 1) * PAGE_SIZE as usize).min(data.len());
+        data[start..end]
+            .iter()
+            .copied()
+            .chain(core::iter::repeat(0xff))
+            .take(PAGE_SIZE as usize)
+    }
+
+    /// Size of an erasable sector
+    pub const SECTOR_SIZE: u32 = 4096;
+    /// Size of a programmable page
+    pub const PAGE_SIZE: u32 = 256;
+
+    /// Check that a range can be erased or programmed
+    ///
+    /// - `addr` and `len` must be multiples of `align`, else
+    ///   [`FlashError::NotAligned`] is returned.
+    /// - The range must fit into the flash, else [`FlashError::OutOfBounds`]
+    ///   is returned. The flash size is looked up in the [`chip`] database,
+    ///   or detected with [`flash_size`] for unknown chips.
+    /// - The range must not overlap the flash image of the running program,
+    ///   else [`FlashError::OverlapsImage`] is returned. The image is located
+    ///   using the symbols defined by the `cortex-m-rt` linker script.
+    /// - The range must not overlap a region protected using [`protect`],
+    ///   else [`FlashError::WriteProtected`] is returned.
+    ///
+    /// [`chip`]: crate::chip
+    ///
+    /// # Safety
+    ///
+    /// The first call reads the flash size from the chip, see
+    /// [`flash_jedec_id`] for the requirements.
+    pub unsafe fn validate_range(
+        addr: u32,
+        len: u32,
+        align: u32,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        if addr & (align - 1) != 0 || len & (align - 1) != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        let end = addr.checked_add(len).ok_or(FlashError::OutOfBounds)?;
+        if end > detected_size(use_boot2) {
+            return Err(FlashError::OutOfBounds);
+        }
+        let (image_start, image_end) = image_range();
+        if len > 0 && addr < image_end && image_start < end {
+            return Err(FlashError::OverlapsImage);
+        }
+        protect::check(addr, len)
+    }
+
+    /// Flash size used by [`validate_range`], 0 if not detected yet
+    static DETECTED_SIZE: AtomicU32 = AtomicU32::new(0);
+
+    pub(crate) unsafe fn detected_size(use_boot2: bool) -> u32 {
+        let size = DETECTED_SIZE.load(Ordering::Relaxed);
+        if size != 0 {
+            return size;
+        }
+        let size = chip::lookup(flash_jedec_id(use_boot2))
+            .map(|chip| chip.size)
+            .or_else(|| flash_size(use_boot2))
+            .unwrap_or(0x1000000)
+            .min(0x1000000);
+        debug!("flash size {:#x}", size);
+        DETECTED_SIZE.store(size, Ordering::Relaxed);
+        size
+    }
+
+    /// Flash offsets occupied by the running program, including the
+    /// initializers of `.data`
+    fn image_range() -> (u32, u32) {
+        extern "C" {
+            static __sidata: u8;
+            static __sdata: u8;
+            static __edata: u8;
+        }
+        let sidata = core::ptr::addr_of!(__sidata) as u32;
+        let sdata = core::ptr::addr_of!(__sdata) as u32;
+        let edata = core::ptr::addr_of!(__edata) as u32;
+        (0, sidata + (edata - sdata) - 0x10000000)
+    }
+
+    /// Erase and rewrite a flash range, verify it, and retry according to `policy`
+    ///
+    /// After each attempt, the chip's failure flags are checked as in
+    /// [`flash_check_failure`], and the range is read back through the
+    /// uncached XIP alias and compared to `data`. A mismatch is reported as
+    /// [`FlashError::VerifyFailed`] with the flash offset of the first
+    /// differing byte.
+    ///
+    /// As this always erases before programming, `policy.reerase` has no effect.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    /// `policy.backoff` is called between attempts, so it must be safe to
+    /// call in the same context.
+    pub unsafe fn flash_range_erase_and_program_with_retry(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+        policy: &RetryPolicy,
+    ) -> Result<(), FlashError> {
+        policy.run(|_attempt, _erase| {
+            flash_range_erase_and_program_checked(addr, data, use_boot2)?;
+            Ok(verify(addr, data)?)
+        })
+    }
+
+    /// Program a flash range and verify it
+    ///
+    /// Like [`flash_range_program`], but afterwards the range is read back
+    /// through the uncached XIP alias and compared to `data`. On mismatch,
+    /// [`VerifyError`] holds the flash offset of the first differing byte.
+    ///
+    /// This detects writes that silently failed, e.g. due to a marginal
+    /// power supply or a worn out sector. Bits which were already
+    /// programmed to 0 before can't be set to 1 by programming, so this
+    /// also fails if the range wasn't erased.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_program`].
+    pub unsafe fn flash_range_program_verified(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), VerifyError> {
+        flash_range_program(addr, data, use_boot2);
+        verify(addr, data)
+    }
+
+    /// Erase and program a flash range and verify it
+    ///
+    /// Like [`flash_range_erase_and_program`], but afterwards the range is
+    /// read back and compared as in [`flash_range_program_verified`].
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`flash_range_erase_and_program`].
+    pub unsafe fn flash_range_erase_and_program_verified(
+        addr: u32,
+        data: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), VerifyError> {
+        flash_range_erase_and_program(addr, data, use_boot2);
+        verify(addr, data)
+    }
+
+    /// Compare flash contents at `addr` to `data`, reading through the uncached XIP alias
+    fn verify(addr: u32, data: &[u8]) -> Result<(), VerifyError> {
+        let mut buf = [0u8; 32];
+        for (i, expected) in data.chunks(buf.len()).enumerate() {
+            let chunk_addr = addr + (i * buf.len()) as u32;
+            let actual = &mut buf[..expected.len()];
+            xip::read_uncached(chunk_addr, actual);
+            if let Some(pos) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+                let offset = chunk_addr + pos as u32;
+                warn!("verify failed at {:#x}", offset);
+                return Err(VerifyError { offset });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the last program or erase operation failed
+    ///
+    /// First waits for the chip to become idle, see [`flash_wait_ready`],
+    /// with the worst-case time of `op`, the last operation, from the
+    /// [`chip`] database as timeout. For unknown chips, the time is taken
+    /// from their SFDP parameters, see [`chip::timing_for`]. If the Write Enable Latch is still set
+    /// afterwards, the chip didn't perform the operation, e.g. because it
+    /// was rejected as write protected. The latch is cleared, and
+    /// [`FlashError::ProgramFailed`] is returned.
+    ///
+    /// Some flash chips report failed program and erase operations in a
+    /// vendor specific register, e.g. the P_FAIL and E_FAIL bits of the
+    /// Macronix security register. If the chip is listed in the [`chip`]
+    /// database with such a register, it is read (and cleared, if the chip
+    /// requires that), and a failure is reported as
+    /// [`FlashError::HardwareFailure`].
+    ///
+    /// [`chip`]: crate::chip
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_check_failure(
+        op: chip::Operation,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let jedec_id = flash_jedec_id(use_boot2);
+        flash_wait_ready(timeout_for(jedec_id, op, use_boot2), use_boot2)?;
+        // SR1.WEL
+        if read_status(0x05, use_boot2) & 0x02 != 0 {
+            error!("flash operation not performed, WEL still set");
+            // 04 - write disable
+            let wrdi = [0x04];
+            with_function_pointers(false, false, use_boot2, None, |ptrs| {
+                do_cmd(&[FlashTransfer::new(&wrdi, None)], ptrs)
+            });
+            return Err(FlashError::ProgramFailed);
+        }
+        let Some(flags) = chip::lookup(jedec_id).and_then(|chip| chip.fail_flags) else {
+            return Ok(());
+        };
+        let read_cmd = [flags.read_cmd, 0];
+        let mut status = [0u8; 2];
+        let clear_cmd = [flags.clear_cmd.unwrap_or(0)];
+        let transfers = [
+            FlashTransfer::new(&read_cmd, Some(&mut status)),
+            FlashTransfer::new(&clear_cmd, None),
+        ];
+        let count = if flags.clear_cmd.is_some() { 2 } else { 1 };
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            do_cmd(&transfers[..count], ptrs)
+        });
+        let status = status[1];
+        if status & (flags.program_fail | flags.erase_fail) != 0 {
+            error!("flash reported failure, status {:#x}", status);
+            return Err(FlashError::HardwareFailure { status });
+        }
+        Ok(())
+    }
+
+    /// Poll SR1.BUSY until the chip is idle, for at most `timeout`
+    ///
+    /// The polling runs from RAM with XIP disabled once for the whole wait.
+    /// Time is measured with the 1 MHz system timer, which HALs start at
+    /// boot. Independent of the timer, polling stops after one poll per
+    /// microsecond of `timeout`, as each poll takes longer than that, so
+    /// this returns even if the timer isn't running.
+    ///
+    /// Returns [`FlashError::Timeout`] if the chip is still busy.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_wait_ready(
+        timeout: core::time::Duration,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        cmd_and_wait(&[], timeout, use_boot2)
+    }
+
+    /// Worst-case duration of `op`, see [`chip::timing_for`]
+    ///
+    /// The SFDP parameters are only read for chips missing in the database.
+    unsafe fn timeout_for(
+        jedec_id: u32,
+        op: chip::Operation,
+        use_boot2: bool,
+    ) -> core::time::Duration {
+        let sfdp = match chip::lookup(jedec_id) {
+            Some(_) => None,
+            None => crate::sfdp::read_basic_parameters(use_boot2),
+        };
+        chip::timing_for(jedec_id, op, sfdp.as_ref())
+    }
+
+    /// Run `transfers`, then poll SR1.BUSY for at most `timeout`, with XIP
+    /// disabled once for both
+    ///
+    /// Returns [`FlashError::Timeout`] if the chip is still busy.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    unsafe fn cmd_and_wait(
+        transfers: &[FlashTransfer],
+        timeout: core::time::Duration,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let timeout_us = u32::try_from(timeout.as_micros()).unwrap_or(u32::MAX);
+        let rdsr = [0x05, 0];
+        let mut sr = [0u8; 2];
+        let status = FlashTransfer::new(&rdsr, Some(&mut sr));
+        let wait = WaitCmd {
+            transfers: transfers.as_ptr(),
+            count: transfers.len() as u32,
+            status: &status,
+            timeout_us,
+            timer: TIMERAWL,
+        };
+        let sr1 = with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            probe::busy(|| wait_ready_inner(&wait, ptrs))
+        });
+        // SR1.BUSY
+        if sr1 & 0x01 == 0 {
+            return Ok(());
+        }
+        error!("flash still busy after {} us", timeout_us);
+        Err(FlashError::Timeout)
+    }
+
+    /// Transfers followed by a bounded wait, see `wait_ready_inner`
+    #[repr(C)]
+    struct WaitCmd {
+        transfers: *const FlashTransfer,
+        count: u32,
+        /// Transfer reading SR1
+        status: *const FlashTransfer,
+        timeout_us: u32,
+        /// Low word of the 1 MHz timer
+        timer: *const u32,
+    }
+
+    /// Run the transfers of `cmd`, then poll its status transfer until BUSY is clear
+    ///
+    /// Gives up after `timeout_us` microseconds of the timer, or after
+    /// `timeout_us` polls. Returns the last SR1 read.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. `cmd` and the transfers must be
+    /// located in RAM.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn wait_ready_inner(cmd: *const WaitCmd, ptrs: *const FlashFunctionPointers) -> u8 {
+        let sr1: u32;
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            // r5-r10 are preserved by the ROM functions and `transfers_inner`.
+            "push {{r4, r5, r6, r7}}",
+            "mov r5, r1", // ptrs
+            "mov r4, r0", // cmd
+            "ldr r6, [r4, #8]", // status
+            "ldr r7, [r4, #12]", // polls remaining
+            "mov r10, r7", // timeout_us
+            "ldr r0, [r4, #16]",
+            "mov r9, r0", // timer
+            "mov r8, r4", // cmd, until the start time is read
+
+            "ldr r4, [r5, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [r5, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "mov r4, r8",
+            "ldr r0, [r4, #0]", // transfers
+            "ldr r1, [r4, #4]", // count
+            "bl {transfers}",
+
+            "mov r0, r9",
+            "ldr r0, [r0]",
+            "mov r8, r0", // start
+
+            "1:",
+            "mov r0, r6",
+            "movs r1, #1",
+            "bl {transfers}", // transfers_inner(status, 1)
+            "ldr r0, [r6, #4]", // rx
+            "ldrb r0, [r0, #1]", // SR1
+            "movs r1, #1",
+            "tst r0, r1", // BUSY
+            "beq 2f",
+            "mov r0, r9",
+            "ldr r0, [r0]",
+            "mov r1, r8",
+            "subs r0, r0, r1", // elapsed
+            "cmp r0, r10",
+            "bhi 2f",
+            "cmp r7, #0",
+            "beq 2f",
+            "subs r7, #1",
+            "b 1b",
+
+            "2:",
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "ldr r0, [r6, #4]", // rx
+            "ldrb r0, [r0, #1]", // last SR1
+            "pop {{r4, r5, r6, r7}}",
+            transfers = sym transfers_inner,
+            inout("r0") cmd => sr1,
+            inout("r1") ptrs => _,
+            // The values can't be passed in using r8-r10 directly
+            // due to https://github.com/rust-lang/rust/issues/99071
+            out("r8") _,
+            out("r9") _,
+            out("r10") _,
+            clobber_abi("C"),
+        );
+        sr1 as u8
+    }
+
+    /// TIMER TIMERAWL, the low word of the 1 MHz system timer
+    #[cfg(not(feature = "rp235x"))]
+    const TIMERAWL: *const u32 = 0x4005_4028 as _;
+    /// TIMER0 TIMERAWL, the low word of the 1 MHz system timer
+    #[cfg(feature = "rp235x")]
+    const TIMERAWL: *const u32 = 0x400b_0028 as _;
+
+    fn timer_us() -> u32 {
+        // Safety: reading the raw timer register has no side effects
+        unsafe { TIMERAWL.read_volatile() }
+    }
+
+    /// Run `f`, which performs `op` on `len` bytes at `addr`, and trace its duration
+    ///
+    /// The trace is emitted after `f` returned, i.e. with XIP enabled again.
+    fn timed<R>(op: &'static str, addr: u32, len: u32, f: impl FnOnce() -> R) -> R {
+        let start = timer_us();
+        let result = f();
+        trace!(
+            "{} {:#x} len {:#x} took {} us",
+            op,
+            addr,
+            len,
+            timer_us().wrapping_sub(start)
+        );
+        result
+    }
+
+    /// An erase and/or program operation of a batch
+    ///
+    /// Erases `len` bytes at `addr` if `erase` is nonzero, then programs
+    /// `len` bytes from `data` if it isn't null.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(crate) struct BatchOp {
+        pub(crate) addr: u32,
+        pub(crate) len: u32,
+        pub(crate) data: *const u8,
+        pub(crate) erase: u32,
+    }
+
+    /// Run all `ops` with XIP disabled once
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// The ops must have been validated, and their data must be located in RAM.
+    ///
+    /// After each operation, SR1 is read. If the write enable latch is still
+    /// set, the chip didn't perform the operation, and the remaining ones
+    /// are skipped. Returns the number of operations performed.
+    pub(crate) unsafe fn run_batch(ops: &[BatchOp], use_boot2: bool) -> usize {
+        let erase = ops.iter().any(|op| op.erase != 0);
+        let write = ops.iter().any(|op| !op.data.is_null());
+        for op in ops {
+            trace!(
+                "flash batch: erase {} program {} {:#x} len {:#x}",
+                op.erase != 0,
+                !op.data.is_null(),
+                op.addr,
+                op.len
+            );
+        }
+        let rdsr = [0x05, 0];
+        let mut sr = [0u8; 2];
+        let status = FlashTransfer::new(&rdsr, Some(&mut sr));
+        let start = timer_us();
+        let remaining = with_function_pointers(erase, write, use_boot2, None, |ptrs| {
+            probe::busy(|| run_batch_inner(ops.as_ptr(), ops.len() as u32, ptrs, &status))
+        });
+        let done = ops.len() - remaining as usize;
+        trace!(
+            "flash batch of {} operations took {} us",
+            done,
+            timer_us().wrapping_sub(start)
+        );
+        if done < ops.len() {
+            error!(
+                "flash batch operation {} not performed, WEL still set",
+                done
+            );
+        }
+        done
+    }
+
+    /// Run `count` operations, see `BatchOp`, stopping at the first failed one
+    ///
+    /// After each operation, the SR1 read by `status` is checked. Returns
+    /// the number of operations not performed.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`. `status` must be located in RAM.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn run_batch_inner(
+        ops: *const BatchOp,
+        count: u32,
+        ptrs: *const FlashFunctionPointers,
+        status: *const FlashTransfer,
+    ) -> u32 {
+        let remaining: u32;
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            // r5-r8 are preserved by the ROM functions and `transfers_inner`.
+            "push {{r4, r5, r6, r7}}",
+            "mov r5, r2", // ptrs
+            "mov r6, r0", // ops
+            "mov r7, r1", // count
+            "mov r8, r3", // status
+
+            "ldr r4, [r5, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [r5, #4]",
+            "blx r4", // flash_exit_xip()
+
+            // Loop over operations
+            "1:",
+            "cmp r7, #0",
+            "beq 9f",
+            "ldr r0, [r6, #12]", // erase
+            "cmp r0, #0",
+            "beq 2f",
+            "ldr r0, [r6, #0]", // r0 = addr
+            "ldr r1, [r6, #4]", // r1 = len
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "ldr r4, [r5, #8]",
+            "blx r4", // flash_range_erase(addr, len, 1 << 31, 0)
+
+            "2:",
+            "ldr r1, [r6, #8]", // r1 = data
+            "cmp r1, #0",
+            "beq 3f",
+            "ldr r0, [r6, #0]", // r0 = addr
+            "ldr r2, [r6, #4]", // r2 = len
+            "ldr r4, [r5, #12]",
+            "blx r4", // flash_range_program(addr, data, len)
+
+            // Read SR1, WEL is cleared once the operation completed
+            "3:",
+            "mov r0, r8",
+            "movs r1, #1",
+            "bl {transfers}", // transfers_inner(status, 1)
+            "mov r0, r8",
+            "ldr r0, [r0, #4]", // rx
+            "ldrb r0, [r0, #1]", // SR1
+            "movs r1, #2",
+            "tst r0, r1", // WEL
+            "bne 9f",
+
+            "adds r6, #16",
+            "subs r7, #1",
+            "b 1b",
+
+            "9:",
+            "ldr r4, [r5, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, r5",
+            "ldr r4, [r5, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "mov r0, r7", // remaining operations
+            "pop {{r4, r5, r6, r7}}",
+            transfers = sym transfers_inner,
+            inout("r0") ops => remaining,
+            inout("r1") count => _,
+            inout("r2") ptrs => _,
+            inout("r3") status => _,
+            // The status can't be passed in using r8 directly
+            // due to https://github.com/rust-lang/rust/issues/99071
+            out("r8") _,
+            clobber_abi("C"),
+        );
+        remaining
+    }
+
+    /// Call `write_flash_inner`, signalling the operation to a debug probe
+    unsafe fn write_flash(
+        addr: u32,
+        len: u32,
+        data: Option<&[u8]>,
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        probe::busy(|| write_flash_inner(addr, len, data, ptrs));
+    }
+
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// Length of data must be a multiple of 4096
+    /// addr must be aligned to 4096
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn write_flash_inner(
+        addr: u32,
+        len: u32,
+        data: Option<&[u8]>,
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        /*
+         Should be equivalent to:
+            rom_data::connect_internal_flash();
+            rom_data::flash_exit_xip();
+            rom_data::flash_range_erase(addr, len, 1 << 31, 0); // if selected
+            rom_data::flash_range_program(addr, data as *const _, len); // if selected
+            rom_data::flash_flush_cache();
+            rom_data::flash_enter_cmd_xip();
+        */
+        core::arch::asm!(
+            "mov r8, r0",
+            "mov r9, r2",
+            "mov r10, r1",
+            "ldr r4, [{ptrs}, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [{ptrs}, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "mov r0, r8", // r0 = addr
+            "mov r1, r10", // r1 = len
+            "movs r2, #1",
+            "lsls r2, r2, #31", // r2 = 1 << 31
+            "movs r3, #0", // r3 = 0
+            "ldr r4, [{ptrs}, #8]",
+            "cmp r4, #0",
+            "beq 1f",
+            "blx r4", // flash_range_erase(addr, len, 1 << 31, 0)
+            "1:",
+
+            "mov r0, r8", // r0 = addr
+            "mov r1, r9", // r0 = data
+            "mov r2, r10", // r2 = len
+            "ldr r4, [{ptrs}, #12]",
+            "cmp r4, #0",
             "beq 1f",
             "blx r4", // flash_range_program(addr, data, len);
             "1:",
@@ -232,8 +2271,9 @@ pub mod flash {
             "ldr r4, [{ptrs}, #16]",
             "blx r4", // flash_flush_cache();
 
+            "mov r0, {ptrs}",
             "ldr r4, [{ptrs}, #20]",
-            "blx r4", // flash_enter_cmd_xip();
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
             ptrs = in(reg) ptrs,
             in("r0") addr,
             in("r2") data.map(|d| d.as_ptr()).unwrap_or(core::ptr::null()),
@@ -252,29 +2292,199 @@ pub mod flash {
         );
     }
 
-    #[repr(C)]
-    struct FlashCommand {
-        cmd_addr: *const u8,
-        cmd_addr_len: u32,
-        dummy_len: u32,
-        data: *mut u8,
-        data_len: u32,
+    /// Erase a flash range using block erase where possible
+    ///
+    /// `block` is the block size passed to the ROM's `flash_range_erase`,
+    /// with the block erase command in the low byte.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `write_flash_inner`.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn erase_flash_inner(
+        addr: u32,
+        len: u32,
+        block: u32,
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        core::arch::asm!(
+            "mov r8, r0",
+            "mov r9, r1",
+            "mov r10, r2",
+            "ldr r4, [{ptrs}, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "ldr r4, [{ptrs}, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "mov r0, r8", // r0 = addr
+            "mov r1, r9", // r1 = len
+            "mov r2, r10",
+            "uxtb r3, r2", // r3 = block_cmd
+            "subs r2, r2, r3", // r2 = block_size
+            "ldr r4, [{ptrs}, #8]",
+            "blx r4", // flash_range_erase(addr, len, block_size, block_cmd)
+
+            "ldr r4, [{ptrs}, #16]",
+            "blx r4", // flash_flush_cache();
+
+            "mov r0, {ptrs}",
+            "ldr r4, [{ptrs}, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+            ptrs = in(reg) ptrs,
+            in("r0") addr,
+            in("r1") len,
+            in("r2") block,
+            out("r3") _,
+            out("r4") _,
+            // See write_flash_inner for why r8-r10 are used
+            out("r8") _,
+            out("r9") _,
+            out("r10") _,
+            clobber_abi("C"),
+        );
+    }
+
+    #[cfg(not(feature = "rp235x"))]
+    #[repr(C)]
+    struct FlashCommand {
+        cmd_addr: *const u8,
+        cmd_addr_len: u32,
+        dummy_len: u32,
+        data: *mut u8,
+        data_len: u32,
+    }
+
+    /// Return SPI flash unique ID
+    ///
+    /// Not all SPI flashes implement this command, so check the JEDEC
+    /// ID before relying on it. The Winbond parts commonly seen on
+    /// RP2040 devboards (JEDEC=0xEF7015) support an 8-byte unique ID;
+    /// https://forums.raspberrypi.com/viewtopic.php?t=331949 suggests
+    /// that LCSC (Zetta) parts have a 16-byte unique ID (which is
+    /// *not* unique in just its first 8 bytes),
+    /// JEDEC=0xBA6015. Macronix and Spansion parts do not have a
+    /// unique ID.
+    ///
+    /// The returned bytes are relatively predictable and should be
+    /// salted and hashed before use if that is an issue (e.g. for MAC
+    /// addresses).
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_unique_id(out: &mut [u8], use_boot2: bool) {
+        // 4B - read unique ID
+        let cmd = [0x4B];
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            read_flash(&cmd[..], 4, out, ptrs)
+        });
+    }
+
+    /// Return SPI flash JEDEC ID
+    ///
+    /// This is the three-byte manufacturer-and-model identifier
+    /// commonly used to check before using manufacturer-specific SPI
+    /// flash features, e.g. 0xEF7015 for Winbond W25Q16JV.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_jedec_id(use_boot2: bool) -> u32 {
+        let mut id = [0u8; 4];
+        // 9F - read JEDEC ID
+        let cmd = [0x9F];
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            read_flash(&cmd[..], 0, &mut id[1..4], ptrs)
+        });
+        u32::from_be_bytes(id)
+    }
+
+    /// Read `out.len()` bytes at `addr` with the Fast Read command (0x0B)
+    ///
+    /// Unlike reads through the XIP window, this bypasses the XIP cache,
+    /// so it always returns the current flash contents, even if stale data
+    /// is cached. Fast Read is used instead of Read (0x03), as it works at
+    /// any SPI clock rate.
+    ///
+    /// `addr` is relative to the beginning of the flash area.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range exceeds the 16 MiB reachable with 24 bit addresses.
+    pub unsafe fn flash_range_read(addr: u32, out: &mut [u8], use_boot2: bool) {
+        assert!(addr
+            .checked_add(out.len() as u32)
+            .is_some_and(|end| end <= 0x1000000));
+        trace!("flash_range_read {:#x} len {:#x}", addr, out.len());
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            // The SSI transfers less than 64 KiB per command, as the frame
+            // count includes the dummy byte, so read in 32 KiB chunks
+            for (i, chunk) in out.chunks_mut(0x8000).enumerate() {
+                let [_, a2, a1, a0] = (addr + i as u32 * 0x8000).to_be_bytes();
+                read_flash(&[0x0b, a2, a1, a0], 1, chunk, ptrs);
+            }
+        });
+    }
+
+    /// Determine the size of the flash chip in bytes
+    ///
+    /// The size is taken from the density byte of the JEDEC ID, i.e. its
+    /// lowest byte, which is the base 2 logarithm of the size for most
+    /// vendors. If that value is implausible, the density reported in the
+    /// SFDP Basic Flash Parameter table is used. As a last resort, the size
+    /// is detected with [`flash_size_from_wraparound`].
+    ///
+    /// Returns `None` if none of these methods work.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn flash_size(use_boot2: bool) -> Option<u32> {
+        let density = flash_jedec_id(use_boot2) & 0xff;
+        // 64 KiB to 2 GiB
+        if (16..32).contains(&density) {
+            return Some(1 << density);
+        }
+        debug!("implausible JEDEC density {:#x}", density);
+        if let Some(size) = flash_size_from_sfdp(use_boot2) {
+            return Some(size);
+        }
+        flash_size_from_wraparound(use_boot2)
+    }
+
+    /// Read the flash density from the SFDP Basic Flash Parameter table
+    unsafe fn flash_size_from_sfdp(use_boot2: bool) -> Option<u32> {
+        let parameters = sfdp::read_basic_parameters(use_boot2)?;
+        u32::try_from(parameters.size).ok()
     }
 
-    /// Return SPI flash unique ID
+    /// Read `out.len()` bytes of the SFDP tables at `offset`
     ///
-    /// Not all SPI flashes implement this command, so check the JEDEC
-    /// ID before relying on it. The Winbond parts commonly seen on
-    /// RP2040 devboards (JEDEC=0xEF7015) support an 8-byte unique ID;
-    /// https://forums.raspberrypi.com/viewtopic.php?t=331949 suggests
-    /// that LCSC (Zetta) parts have a 16-byte unique ID (which is
-    /// *not* unique in just its first 8 bytes),
-    /// JEDEC=0xBA6015. Macronix and Spansion parts do not have a
-    /// unique ID.
+    /// Uses the Read SFDP command (0x5A). See [`sfdp`] for parsing the tables.
     ///
-    /// The returned bytes are relatively predictable and should be
-    /// salted and hashed before use if that is an issue (e.g. for MAC
-    /// addresses).
+    /// [`sfdp`]: crate::sfdp
     ///
     /// # Safety
     ///
@@ -283,24 +2493,38 @@ pub mod flash {
     ///   - interrupts must be disabled
     ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
     ///   - DMA must not access flash memory
-    pub unsafe fn flash_unique_id(out: &mut [u8], use_boot2: bool) {
-        let mut boot2 = [0u32; 256 / 4];
-        let ptrs = if use_boot2 {
-            rom_data::memcpy44(&mut boot2 as *mut _, 0x10000000 as *const _, 256);
-            flash_function_pointers_with_boot2(false, false, &boot2)
-        } else {
-            flash_function_pointers(false, false)
-        };
-        // 4B - read unique ID
-        let cmd = [0x4B];
-        read_flash(&cmd[..], 4, out, &ptrs as *const FlashFunctionPointers);
+    pub unsafe fn read_sfdp(offset: u32, out: &mut [u8], use_boot2: bool) {
+        // 5A - read SFDP, 24 bit address, 8 dummy cycles
+        let cmd = [
+            0x5a,
+            (offset >> 16) as u8,
+            (offset >> 8) as u8,
+            offset as u8,
+        ];
+        read_cmd(&cmd, 1, out, use_boot2);
     }
 
-    /// Return SPI flash JEDEC ID
+    /// Determine the size of the flash chip by detecting address wrap-around
     ///
-    /// This is the three-byte manufacturer-and-model identifier
-    /// commonly used to check before using manufacturer-specific SPI
-    /// flash features, e.g. 0xEF7015 for Winbond W25Q16JV.
+    /// SPI flash chips ignore address bits above their capacity, so a read
+    /// beyond the end of the array returns data from the start of the flash.
+    /// This compares the first bytes of the flash (which hold the 2nd stage
+    /// boot loader and are therefore known not to be blank), and 64 bytes at
+    /// each 4 KiB boundary of the first 64 KiB, to the data found at each
+    /// power-of-two offset. All reads use the serial read command (0x03)
+    /// through the SSI, bypassing the XIP cache.
+    ///
+    /// Comparing more than the boot loader avoids mistaking a second copy of
+    /// it, e.g. at the start of an image staged by [`update`](crate::update),
+    /// for wrap-around. Only an exact copy of the first 64 KiB at a
+    /// power-of-two offset is still reported as wrap-around.
+    ///
+    /// Use this if the density byte of the JEDEC ID can't be trusted, e.g. to
+    /// avoid configuring a 16 MiB layout on a 2 MiB part.
+    ///
+    /// Returns the size in bytes, or `None` if the start of the flash is blank,
+    /// in which case wrap-around can't be detected. If no wrap-around is found,
+    /// the flash is at least 16 MiB, the maximum reachable with 24 bit addresses.
     ///
     /// # Safety
     ///
@@ -309,42 +2533,53 @@ pub mod flash {
     ///   - interrupts must be disabled
     ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
     ///   - DMA must not access flash memory
-    pub unsafe fn flash_jedec_id(use_boot2: bool) -> u32 {
-        let mut boot2 = [0u32; 256 / 4];
-        let ptrs = if use_boot2 {
-            rom_data::memcpy44(&mut boot2 as *mut _, 0x10000000 as *const _, 256);
-            flash_function_pointers_with_boot2(false, false, &boot2)
-        } else {
-            flash_function_pointers(false, false)
-        };
-        let mut id = [0u8; 4];
-        // 9F - read JEDEC ID
-        let cmd = [0x9F];
-        read_flash(
-            &cmd[..],
-            0,
-            &mut id[1..4],
-            &ptrs as *const FlashFunctionPointers,
-        );
-        u32::from_be_bytes(id)
+    pub unsafe fn flash_size_from_wraparound(use_boot2: bool) -> Option<u32> {
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            let mut reference = [0u8; 64];
+            read_flash(&serial_read(0), 0, &mut reference, ptrs);
+            if reference.iter().all(|&b| b == 0xff) || reference.iter().all(|&b| b == 0) {
+                warn!("start of flash is blank, can't detect size");
+                return None;
+            }
+            let mut alias = [0u8; 64];
+            // Smallest plausible part is 64 KiB, largest addressable one 16 MiB
+            for bits in 16..24 {
+                let size = 1u32 << bits;
+                let wraps = (0..0x10000).step_by(0x1000).all(|offset| {
+                    read_flash(&serial_read(offset), 0, &mut reference, ptrs);
+                    read_flash(&serial_read(size + offset), 0, &mut alias, ptrs);
+                    alias == reference
+                });
+                if wraps {
+                    debug!("flash wraps around at {:#x}", size);
+                    return Some(size);
+                }
+            }
+            debug!("no flash wrap-around below 16 MiB");
+            Some(1 << 24)
+        })
+    }
+
+    /// Serial read command (0x03) with a 24 bit address
+    fn serial_read(addr: u32) -> [u8; 4] {
+        [0x03, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8]
     }
 
+    #[cfg(not(feature = "rp235x"))]
     unsafe fn read_flash(
         cmd_addr: &[u8],
         dummy_len: u32,
         out: &mut [u8],
         ptrs: *const FlashFunctionPointers,
     ) {
-        read_flash_inner(
-            FlashCommand {
-                cmd_addr: cmd_addr.as_ptr(),
-                cmd_addr_len: cmd_addr.len() as u32,
-                dummy_len,
-                data: out.as_mut_ptr(),
-                data_len: out.len() as u32,
-            },
-            ptrs,
-        );
+        let cmd = FlashCommand {
+            cmd_addr: cmd_addr.as_ptr(),
+            cmd_addr_len: cmd_addr.len() as u32,
+            dummy_len,
+            data: out.as_mut_ptr(),
+            data_len: out.len() as u32,
+        };
+        probe::busy(|| read_flash_inner(cmd, ptrs));
     }
 
     /// Issue a generic SPI flash read command
@@ -353,6 +2588,7 @@ pub mod flash {
     ///
     /// * `cmd` - `FlashCommand` structure
     /// * `ptrs` - Flash function pointers as per `write_flash_inner`
+    #[cfg(not(feature = "rp235x"))]
     #[inline(never)]
     #[link_section = ".data.ram_func"]
     unsafe fn read_flash_inner(cmd: FlashCommand, ptrs: *const FlashFunctionPointers) {
@@ -450,8 +2686,9 @@ pub mod flash {
             // wrong unless we do it here
             "str r0, [r4, #4]", // CTRLR1
 
+            "mov r0, r5",
             "ldr r4, [r5, #20]",
-            "blx r4", // flash_enter_cmd_xip();
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
 
             in("r0") &cmd as *const FlashCommand,
             in("r1") ptrs,
@@ -469,4 +2706,620 @@ pub mod flash {
             clobber_abi("C"),
         );
     }
+
+    /// Send `cmd_addr` and `dummy_len` dummy bytes, then read `out.len()` bytes
+    ///
+    /// The RP2350 has no SSI, so this is built from QMI direct mode
+    /// transfers, keeping chip select asserted between the phases.
+    #[cfg(feature = "rp235x")]
+    unsafe fn read_flash(
+        cmd_addr: &[u8],
+        dummy_len: u32,
+        out: &mut [u8],
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        let transfers = [
+            FlashTransfer::new(cmd_addr, None).hold_cs(),
+            FlashTransfer::zeros(dummy_len, None).hold_cs(),
+            FlashTransfer::zeros(out.len() as u32, Some(out)),
+        ];
+        do_cmd(&transfers, ptrs);
+    }
+
+    /// Issue a full-duplex SPI command
+    ///
+    /// Sends `tx` to the flash chip, and stores the bytes received at the
+    /// same time in `rx`, with chip select asserted for the whole transfer.
+    /// This is equivalent to pico-sdk's `flash_do_cmd`, and can be used for
+    /// vendor specific commands.
+    ///
+    /// For example, reading status register 1 sends `[0x05, 0]` and returns
+    /// the register in `rx[1]`.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// The command must leave the flash chip in a state where it can be
+    /// accessed by XIP. Commands modifying flash contents bypass the checks
+    /// of [`protect`], and don't wait for the operation to complete.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tx` and `rx` have different lengths.
+    pub unsafe fn flash_do_cmd(tx: &[u8], rx: &mut [u8], use_boot2: bool) {
+        assert_eq!(tx.len(), rx.len());
+        trace!(
+            "flash_do_cmd {:#x} len {}",
+            tx.first().copied().unwrap_or(0),
+            tx.len()
+        );
+        let transfer = FlashTransfer::new(tx, Some(rx));
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            do_cmd(&[transfer], ptrs)
+        });
+    }
+
+    /// Read status register `n`, 1 to 3
+    ///
+    /// Uses the commands 0x05, 0x35 and 0x15. Status register 1 holds the
+    /// BUSY and WEL bits in bits 0 and 1 on all common chips. The meaning
+    /// of the other bits and registers is vendor specific, and not all
+    /// chips implement status registers 2 and 3.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is not 1, 2 or 3.
+    pub unsafe fn read_status_register(n: u8, use_boot2: bool) -> u8 {
+        let cmd = match n {
+            1 => 0x05,
+            2 => 0x35,
+            3 => 0x15,
+            _ => panic!("invalid status register {}", n),
+        };
+        read_status(cmd, use_boot2)
+    }
+
+    /// Write status register `n`, 1 to 3, and wait for the write to complete
+    ///
+    /// Uses the commands 0x01, 0x31 and 0x11, preceded by Write Enable
+    /// (0x06). Some older chips don't support 0x31, and expect status
+    /// register 2 to be written as second data byte of 0x01 instead.
+    ///
+    /// Returns [`FlashError::Timeout`] if the chip stays busy longer than a
+    /// sector erase.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///
+    /// Writing invalid values can make the flash inaccessible, e.g. clearing
+    /// the QE bit while the 2nd stage boot loader uses quad mode, or
+    /// permanently write protect it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is not 1, 2 or 3.
+    pub unsafe fn write_status_register(
+        n: u8,
+        value: u8,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let cmd = match n {
+            1 => 0x01,
+            2 => 0x31,
+            3 => 0x11,
+            _ => panic!("invalid status register {}", n),
+        };
+        trace!("write status register {} = {:#x}", n, value);
+        write_status(cmd, &[value], use_boot2)
+    }
+
+    /// Read a status register using the read command `cmd`, e.g. 0x05 for SR1
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    unsafe fn read_status(cmd: u8, use_boot2: bool) -> u8 {
+        let tx = [cmd, 0];
+        let mut rx = [0u8; 2];
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            do_cmd(&[FlashTransfer::new(&tx, Some(&mut rx))], ptrs)
+        });
+        rx[1]
+    }
+
+    /// Write `value` using the write command `cmd`, e.g. 0x01 for SR1
+    ///
+    /// The write is enabled with WREN (0x06) before, making it non-volatile,
+    /// and SR1.BUSY is polled until the write has completed.
+    ///
+    /// No time is specified for status register writes in the [`chip`]
+    /// database. They take at most as long as a sector erase, which is used
+    /// as timeout.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Writing invalid values can make the flash inaccessible.
+    pub(crate) unsafe fn write_status(
+        cmd: u8,
+        value: &[u8],
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let mut tx = [0u8; 4];
+        tx[0] = cmd;
+        tx[1..=value.len()].copy_from_slice(value);
+        write_enabled_cmd(&tx[..=value.len()], chip::Operation::SectorErase, use_boot2)
+    }
+
+    /// Send `tx` after WREN (0x06), and poll SR1.BUSY until the chip is idle
+    ///
+    /// The worst-case time of `op` is used as timeout, see
+    /// [`flash_wait_ready`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    pub(crate) unsafe fn write_enabled_cmd(
+        tx: &[u8],
+        op: chip::Operation,
+        use_boot2: bool,
+    ) -> Result<(), FlashError> {
+        let timeout = timeout_for(flash_jedec_id(use_boot2), op, use_boot2);
+        let wren = [0x06];
+        let transfers = [
+            FlashTransfer::new(&wren, None),
+            FlashTransfer::new(tx, None),
+        ];
+        cmd_and_wait(&transfers, timeout, use_boot2)
+    }
+
+    /// Run an erase for a while, then suspend it
+    ///
+    /// Sends WREN (0x06) followed by `start`, or resume (0x7A) if `start`
+    /// is `None`. Then waits for the time it takes to send `wait` bytes,
+    /// sends suspend (0x75), and polls SR1.BUSY until the chip is idle, for
+    /// at most the worst-case sector erase time. Returns the byte read with
+    /// `status_cmd` afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    pub(crate) unsafe fn erase_slice(
+        start: Option<&[u8]>,
+        wait: u32,
+        status_cmd: u8,
+        use_boot2: bool,
+    ) -> Result<u8, FlashError> {
+        let timeout = timeout_for(
+            flash_jedec_id(use_boot2),
+            chip::Operation::SectorErase,
+            use_boot2,
+        );
+        let wren = [0x06];
+        let resume = [0x7a];
+        let suspend = [0x75];
+        let transfers = [
+            FlashTransfer::new(&wren, None),
+            FlashTransfer::new(start.unwrap_or(&resume), None),
+            // Zeros are ignored by the chip, this only waits
+            FlashTransfer::zeros(wait, None),
+            FlashTransfer::new(&suspend, None),
+        ];
+        let transfers = if start.is_some() {
+            &transfers[..]
+        } else {
+            &transfers[1..]
+        };
+        cmd_and_wait(transfers, timeout, use_boot2)?;
+        Ok(read_status(status_cmd, use_boot2))
+    }
+
+    /// Send `cmd`, skip `dummy_len` bytes, and read `out.len()` bytes
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    pub(crate) unsafe fn read_cmd(cmd: &[u8], dummy_len: u32, out: &mut [u8], use_boot2: bool) {
+        with_function_pointers(false, false, use_boot2, None, |ptrs| {
+            read_flash(cmd, dummy_len, out, ptrs)
+        });
+    }
+
+    /// A single SPI transaction, framed by chip select
+    #[repr(C)]
+    struct FlashTransfer {
+        /// Bytes to send, or null to send zeros
+        tx: *const u8,
+        /// Buffer for received bytes, or null to discard them
+        rx: *mut u8,
+        len: u32,
+        /// On the RP2350, bit 8 (`HOLD_CS`) keeps chip select asserted
+        /// for the next transfer. Unused on the RP2040.
+        flags: u32,
+    }
+
+    #[cfg(feature = "rp235x")]
+    const HOLD_CS: u32 = 1 << 8;
+
+    impl FlashTransfer {
+        /// Send `tx`, storing the bytes received at the same time in `rx`
+        ///
+        /// `rx` must be at least as long as `tx`.
+        fn new(tx: &[u8], rx: Option<&mut [u8]>) -> Self {
+            FlashTransfer {
+                tx: tx.as_ptr(),
+                rx: match rx {
+                    Some(rx) => {
+                        assert!(rx.len() >= tx.len());
+                        rx.as_mut_ptr()
+                    }
+                    None => core::ptr::null_mut(),
+                },
+                len: tx.len() as u32,
+                flags: 0,
+            }
+        }
+
+        /// Send `len` zeros, storing the bytes received in `rx`
+        fn zeros(len: u32, rx: Option<&mut [u8]>) -> Self {
+            FlashTransfer {
+                tx: core::ptr::null(),
+                rx: match rx {
+                    Some(rx) => {
+                        assert!(rx.len() >= len as usize);
+                        rx.as_mut_ptr()
+                    }
+                    None => core::ptr::null_mut(),
+                },
+                len,
+                flags: 0,
+            }
+        }
+
+        /// Keep chip select asserted after this transfer
+        #[cfg(feature = "rp235x")]
+        fn hold_cs(mut self) -> Self {
+            self.flags |= HOLD_CS;
+            self
+        }
+    }
+
+    unsafe fn do_cmd(transfers: &[FlashTransfer], ptrs: *const FlashFunctionPointers) {
+        probe::busy(|| do_cmd_inner(transfers.as_ptr(), transfers.len() as u32, ptrs));
+    }
+
+    /// Issue a sequence of full-duplex SPI transactions, with XIP disabled
+    ///
+    /// # Arguments
+    ///
+    /// * `transfers` - Pointer to `count` `FlashTransfer` structures
+    /// * `ptrs` - Flash function pointers as per `write_flash_inner`
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn do_cmd_inner(
+        transfers: *const FlashTransfer,
+        count: u32,
+        ptrs: *const FlashFunctionPointers,
+    ) {
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            "push {{r4, r5, r6, r7}}",
+            "mov r8, r0", // transfers
+            "mov r9, r1", // count
+            "mov r10, r2", // ptrs
+
+            "ldr r4, [r2, #0]",
+            "blx r4", // connect_internal_flash()
+
+            "mov r4, r10",
+            "ldr r4, [r4, #4]",
+            "blx r4", // flash_exit_xip()
+
+            "mov r0, r8",
+            "mov r1, r9",
+            "bl {transfers}",
+
+            "mov r4, r10",
+            "ldr r4, [r4, #16]",
+            "blx r4", // flash_flush_cache(), on the RP2040 also releases CS override
+
+            "mov r0, r10",
+            "ldr r4, [r0, #20]",
+            "blx r4", // flash_enter_cmd_xip(), ptrs in r0
+
+            "pop {{r4, r5, r6, r7}}",
+            transfers = sym transfers_inner,
+            in("r0") transfers,
+            in("r1") count,
+            in("r2") ptrs,
+            // Registers r8-r10 are used to store values
+            // from r0-r2 in registers not clobbered by
+            // function calls.
+            // The values can't be passed in using r8-r10 directly
+            // due to https://github.com/rust-lang/rust/issues/99071
+            out("r8") _,
+            out("r9") _,
+            out("r10") _,
+            clobber_abi("C"),
+        );
+    }
+
+    /// Run `count` transfers, see `FlashTransfer`
+    ///
+    /// Like pico-sdk's `flash_do_cmd`, chip select is driven using the
+    /// QSPI pad overrides, so each transfer may be of arbitrary length.
+    ///
+    /// Called by the RAM routines with XIP already disabled, between
+    /// `flash_exit_xip` and `flash_flush_cache`.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from RAM, with XIP disabled. `transfers` must point
+    /// to `count` `FlashTransfer` structures located in RAM.
+    #[cfg(not(feature = "rp235x"))]
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe extern "C" fn transfers_inner(transfers: *const FlashTransfer, count: u32) {
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            "push {{r4, r5, r6, r7}}",
+            "mov r8, r0", // transfers
+            "mov r9, r1", // count
+
+            "movs r4, #0x18",
+            "lsls r4, r4, #24", // 0x18000000, SSI, RP2040 datasheet 4.10.13
+
+            // Loop over transfers
+            "1:",
+            "mov r5, r9",
+            "cmp r5, #0",
+            "beq 9f",
+            "mov r5, r8",
+            "ldr r0, [r5, #0]", // tx
+            "ldr r1, [r5, #4]", // rx
+            "ldr r2, [r5, #8]", // len = tx remaining
+            "mov r3, r2", // rx remaining
+
+            // Force CS low: IO_QSPI GPIO_QSPI_SS_CTRL.OUTOVER = 2
+            "movs r5, #0x40",
+            "lsls r5, r5, #24",
+            "movs r6, #0x18",
+            "lsls r6, r6, #12",
+            "adds r5, r5, r6", // 0x40018000, IO_QSPI
+            "ldr r6, [r5, #0x0c]", // GPIO_QSPI_SS_CTRL
+            "movs r7, #3",
+            "lsls r7, r7, #8",
+            "bics r6, r7",
+            "movs r7, #2",
+            "lsls r7, r7, #8",
+            "orrs r6, r7",
+            "str r6, [r5, #0x0c]",
+
+            // Transfer bytes, keeping at most 14 bytes in flight
+            // so the 16 entry RX FIFO can't overflow
+            "2:",
+            "mov r5, r2",
+            "orrs r5, r3",
+            "beq 8f",
+            "ldr r5, [r4, #0x28]", // SR
+            "movs r6, #0x2",
+            "tst r5, r6", // SR.TFNF
+            "beq 4f",
+            "cmp r2, #0",
+            "beq 4f",
+            "subs r6, r3, r2",
+            "cmp r6, #14",
+            "bhs 4f",
+            "movs r6, #0",
+            "cmp r0, #0",
+            "beq 3f",
+            "ldrb r6, [r0]",
+            "adds r0, #1",
+            "3:",
+            "str r6, [r4, #0x60]", // DR0
+            "subs r2, #1",
+
+            "4:",
+            "movs r6, #0x8",
+            "tst r5, r6", // SR.RFNE
+            "beq 2b",
+            "cmp r3, #0",
+            "beq 2b",
+            "ldr r6, [r4, #0x60]", // DR0
+            "subs r3, #1",
+            "cmp r1, #0",
+            "beq 2b",
+            "strb r6, [r1]",
+            "adds r1, #1",
+            "b 2b",
+
+            // Force CS high: IO_QSPI GPIO_QSPI_SS_CTRL.OUTOVER = 3
+            "8:",
+            "movs r5, #0x40",
+            "lsls r5, r5, #24",
+            "movs r6, #0x18",
+            "lsls r6, r6, #12",
+            "adds r5, r5, r6", // 0x40018000, IO_QSPI
+            "ldr r6, [r5, #0x0c]", // GPIO_QSPI_SS_CTRL
+            "movs r7, #3",
+            "lsls r7, r7, #8",
+            "orrs r6, r7",
+            "str r6, [r5, #0x0c]",
+
+            // Next transfer
+            "mov r5, r8",
+            "adds r5, #16", // size_of::<FlashTransfer>()
+            "mov r8, r5",
+            "mov r5, r9",
+            "subs r5, #1",
+            "mov r9, r5",
+            "b 1b",
+
+            "9:",
+            "pop {{r4, r5, r6, r7}}",
+            in("r0") transfers,
+            in("r1") count,
+            // The values can't be passed in using r8/r9 directly
+            // due to https://github.com/rust-lang/rust/issues/99071
+            out("r8") _,
+            out("r9") _,
+            clobber_abi("C"),
+        );
+    }
+
+    /// Run `count` transfers, see `FlashTransfer`
+    ///
+    /// RP2350 version, using QMI direct mode.
+    /// Chip select is asserted through `DIRECT_CSR.ASSERT_CS0N`.
+    ///
+    /// Called by the RAM routines with XIP already disabled, between
+    /// `flash_exit_xip` and `flash_flush_cache`.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from RAM, with XIP disabled. `transfers` must point
+    /// to `count` `FlashTransfer` structures located in RAM.
+    #[cfg(feature = "rp235x")]
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe extern "C" fn transfers_inner(transfers: *const FlashTransfer, count: u32) {
+        core::arch::asm!(
+            // r4-r7 are used as scratch registers, and r6/r7 can't
+            // be declared as clobbered, so save them on the stack.
+            "push {{r4, r5, r6, r7}}",
+            "mov r8, r0", // transfers
+            "mov r9, r1", // count
+
+            "movs r4, #0x40",
+            "lsls r4, r4, #24",
+            "movs r5, #0x0d",
+            "lsls r5, r5, #16",
+            "adds r4, r4, r5", // 0x400d0000, QMI
+
+            // Enable direct mode, and wait for the last XIP access to finish
+            "ldr r5, [r4, #0]", // DIRECT_CSR
+            "movs r6, #1",
+            "orrs r5, r6", // EN
+            "str r5, [r4, #0]",
+            "0:",
+            "ldr r5, [r4, #0]",
+            "lsrs r5, r5, #2", // BUSY
+            "bcs 0b",
+
+            // Loop over transfers
+            "1:",
+            "mov r5, r9",
+            "cmp r5, #0",
+            "beq 9f",
+            "mov r5, r8",
+            "ldr r0, [r5, #0]", // tx
+            "ldr r1, [r5, #4]", // rx
+            "ldr r2, [r5, #8]", // len = tx remaining
+            "mov r3, r2", // rx remaining
+
+            // Assert CS
+            "ldr r5, [r4, #0]",
+            "movs r6, #4",
+            "orrs r5, r6", // ASSERT_CS0N
+            "str r5, [r4, #0]",
+
+            // Transfer bytes, keeping at most 4 bytes in flight
+            // so the 4 entry RX FIFO can't overflow
+            "2:",
+            "mov r5, r2",
+            "orrs r5, r3",
+            "beq 8f",
+            "ldr r5, [r4, #0]", // DIRECT_CSR
+            "cmp r2, #0",
+            "beq 4f",
+            "lsrs r6, r5, #11", // TXFULL
+            "bcs 4f",
+            "subs r6, r3, r2",
+            "cmp r6, #4",
+            "bhs 4f",
+            "movs r6, #0",
+            "cmp r0, #0",
+            "beq 3f",
+            "ldrb r6, [r0]",
+            "adds r0, #1",
+            "3:",
+            "str r6, [r4, #4]", // DIRECT_TX
+            "subs r2, #1",
+
+            "4:",
+            "cmp r3, #0",
+            "beq 2b",
+            "lsrs r6, r5, #17", // RXEMPTY
+            "bcs 2b",
+            "ldr r6, [r4, #8]", // DIRECT_RX
+            "subs r3, #1",
+            "cmp r1, #0",
+            "beq 2b",
+            "strb r6, [r1]",
+            "adds r1, #1",
+            "b 2b",
+
+            "8:",
+            "mov r5, r8",
+            "ldr r6, [r5, #12]", // flags
+            "lsrs r7, r6, #9", // HOLD_CS
+            "bcs 7f",
+
+            // Deassert CS
+            "ldr r7, [r4, #0]",
+            "movs r2, #4",
+            "bics r7, r2", // ASSERT_CS0N
+            "str r7, [r4, #0]",
+
+            // Next transfer
+            "7:",
+            "mov r5, r8",
+            "adds r5, #16", // size_of::<FlashTransfer>()
+            "mov r8, r5",
+            "mov r5, r9",
+            "subs r5, #1",
+            "mov r9, r5",
+            "b 1b",
+
+            // Disable direct mode
+            "9:",
+            "ldr r5, [r4, #0]",
+            "movs r6, #1",
+            "bics r5, r6", // EN
+            "str r5, [r4, #0]",
+
+
+            "pop {{r4, r5, r6, r7}}",
+            in("r0") transfers,
+            in("r1") count,
+            // The values can't be passed in using r8/r9 directly
+            // due to https://github.com/rust-lang/rust/issues/99071
+            out("r8") _,
+            out("r9") _,
+            clobber_abi("C"),
+        );
+    }
 }
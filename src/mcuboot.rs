@@ -0,0 +1,293 @@
+//! mcuboot compatible image headers and slot trailers
+//!
+//! Allows firmware updated with this crate to interoperate with mcuboot
+//! based tooling (e.g. `imgtool` for signing) and boot loaders. A slot is
+//! any storage implementing the `embedded-storage` traits, usually a
+//! [`Partition`](crate::partition::Partition).
+//!
+//! Only the trailer layout for `BOOT_MAX_ALIGN == 8` is supported, which is
+//! the mcuboot default.
+
+use embedded_storage::{ReadStorage, Storage};
+
+/// Magic number at the start of an image header
+pub const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+/// Size of the fixed part of the image header
+pub const HEADER_SIZE: usize = 32;
+
+const TLV_INFO_MAGIC: u16 = 0x6907;
+const TLV_SHA256: u16 = 0x10;
+
+/// Magic marking a valid trailer at the end of a slot
+pub const TRAILER_MAGIC: [u8; 16] = [
+    0x77, 0xc2, 0x95, 0xf3, 0x60, 0xd2, 0xef, 0x7f, 0x35, 0x52, 0x50, 0x0f, 0x2c, 0xb6, 0x79, 0x80,
+];
+const MAX_ALIGN: u32 = 8;
+
+const FLAG_SET: u8 = 1;
+const FLAG_BAD: u8 = 2;
+
+/// Version of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32,
+}
+
+/// mcuboot image header, found at the start of a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub load_addr: u32,
+    /// Size of the header, i.e. offset of the image within the slot
+    pub hdr_size: u16,
+    /// Size of the protected TLV area following the image
+    pub protect_tlv_size: u16,
+    /// Size of the image, excluding header and TLVs
+    pub img_size: u32,
+    pub flags: u32,
+    pub version: ImageVersion,
+}
+
+impl ImageHeader {
+    /// Parse a header, returning `None` if the magic doesn't match
+    pub fn parse(bytes: &[u8; HEADER_SIZE]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if u32_at(0) != IMAGE_MAGIC {
+            return None;
+        }
+        Some(ImageHeader {
+            load_addr: u32_at(4),
+            hdr_size: u16_at(8),
+            protect_tlv_size: u16_at(10),
+            img_size: u32_at(12),
+            flags: u32_at(16),
+            version: ImageVersion {
+                major: bytes[20],
+                minor: bytes[21],
+                revision: u16_at(22),
+                build_num: u32_at(24),
+            },
+        })
+    }
+
+    /// Serialize the header, for writing it in front of an image
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.load_addr.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.hdr_size.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.protect_tlv_size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.img_size.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[20] = self.version.major;
+        bytes[21] = self.version.minor;
+        bytes[22..24].copy_from_slice(&self.version.revision.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.version.build_num.to_le_bytes());
+        bytes
+    }
+
+    /// Read the header at the start of `slot`
+    ///
+    /// Returns `Ok(None)` if the slot doesn't contain an image.
+    pub fn read<S: ReadStorage>(slot: &mut S) -> Result<Option<Self>, S::Error> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        slot.read(0, &mut bytes)?;
+        Ok(Self::parse(&bytes))
+    }
+}
+
+/// Find the SHA256 hash of the image in `slot` in its TLV area
+///
+/// This is the hash used by mcumgr to identify images. Returns `Ok(None)`
+/// if the TLV area doesn't contain a hash, or lies outside of the slot.
+pub fn image_hash<S: ReadStorage>(
+    slot: &mut S,
+    header: &ImageHeader,
+) -> Result<Option<[u8; 32]>, S::Error> {
+    let capacity = slot.capacity() as u32;
+    // The unprotected TLVs follow the image and the protected TLVs
+    let Some(mut offset) = (header.hdr_size as u32)
+        .checked_add(header.img_size)
+        .and_then(|o| o.checked_add(header.protect_tlv_size as u32))
+        .filter(|&o| o.checked_add(4).is_some_and(|e| e <= capacity))
+    else {
+        return Ok(None);
+    };
+    let mut info = [0u8; 4];
+    slot.read(offset, &mut info)?;
+    if u16::from_le_bytes([info[0], info[1]]) != TLV_INFO_MAGIC {
+        return Ok(None);
+    }
+    let Some(end) = offset.checked_add(u16::from_le_bytes([info[2], info[3]]) as u32) else {
+        return Ok(None);
+    };
+    offset += 4;
+    while let Some(next) = offset.checked_add(4).filter(|&n| n <= end && n <= capacity) {
+        let mut tlv = [0u8; 4];
+        slot.read(offset, &mut tlv)?;
+        let kind = u16::from_le_bytes([tlv[0], tlv[1]]);
+        let len = u16::from_le_bytes([tlv[2], tlv[3]]) as u32;
+        if kind == TLV_SHA256 && len == 32 {
+            if next.checked_add(32).is_none_or(|e| e > capacity) {
+                return Ok(None);
+            }
+            let mut hash = [0u8; 32];
+            slot.read(next, &mut hash)?;
+            return Ok(Some(hash));
+        }
+        offset = match next.checked_add(len) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+    }
+    Ok(None)
+}
+
+/// Swap requested by, or recorded in, a slot trailer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapType {
+    None = 1,
+    /// Boot the secondary image once, revert unless it's confirmed
+    Test = 2,
+    /// Boot the secondary image permanently
+    Perm = 3,
+    /// Revert to the previous image
+    Revert = 4,
+    Fail = 5,
+}
+
+impl SwapType {
+    fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            1 => SwapType::None,
+            2 => SwapType::Test,
+            3 => SwapType::Perm,
+            4 => SwapType::Revert,
+            5 => SwapType::Fail,
+            _ => return None,
+        })
+    }
+}
+
+/// State of a trailer flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Set,
+    Bad,
+    /// Not set, including erased flash
+    Unset,
+}
+
+impl Flag {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            FLAG_SET => Flag::Set,
+            FLAG_BAD => Flag::Bad,
+            _ => Flag::Unset,
+        }
+    }
+}
+
+/// Contents of the trailer at the end of a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trailer {
+    /// The trailer magic is present
+    pub magic: bool,
+    /// Swap type, if recorded
+    pub swap_type: Option<SwapType>,
+    /// Image number the swap info applies to
+    pub image_num: u8,
+    pub copy_done: Flag,
+    pub image_ok: Flag,
+    /// Size of the swapped area, if recorded
+    pub swap_size: Option<u32>,
+}
+
+/// Offsets of the trailer fields, relative to the start of a slot
+struct Offsets {
+    magic: u32,
+    image_ok: u32,
+    copy_done: u32,
+    swap_info: u32,
+    swap_size: u32,
+}
+
+impl Offsets {
+    fn new(slot_size: u32) -> Self {
+        let magic = slot_size - TRAILER_MAGIC.len() as u32;
+        let image_ok = magic - MAX_ALIGN;
+        let copy_done = image_ok - MAX_ALIGN;
+        let swap_info = copy_done - MAX_ALIGN;
+        let swap_size = swap_info - MAX_ALIGN;
+        Offsets {
+            magic,
+            image_ok,
+            copy_done,
+            swap_info,
+            swap_size,
+        }
+    }
+}
+
+fn read_u8<S: ReadStorage>(slot: &mut S, offset: u32) -> Result<u8, S::Error> {
+    let mut byte = [0u8];
+    slot.read(offset, &mut byte)?;
+    Ok(byte[0])
+}
+
+impl Trailer {
+    /// Read the trailer at the end of `slot`
+    pub fn read<S: ReadStorage>(slot: &mut S) -> Result<Self, S::Error> {
+        let offsets = Offsets::new(slot.capacity() as u32);
+        let mut magic = [0u8; 16];
+        slot.read(offsets.magic, &mut magic)?;
+        let swap_info = read_u8(slot, offsets.swap_info)?;
+        let mut swap_size = [0u8; 4];
+        slot.read(offsets.swap_size, &mut swap_size)?;
+        let swap_size = u32::from_le_bytes(swap_size);
+        Ok(Trailer {
+            magic: magic == TRAILER_MAGIC,
+            swap_type: SwapType::from_raw(swap_info & 0x0f),
+            image_num: match swap_info {
+                0xff => 0,
+                info => info >> 4,
+            },
+            copy_done: Flag::from_raw(read_u8(slot, offsets.copy_done)?),
+            image_ok: Flag::from_raw(read_u8(slot, offsets.image_ok)?),
+            swap_size: (swap_size != 0xffff_ffff).then_some(swap_size),
+        })
+    }
+}
+
+/// Mark the image in the secondary slot for installation on next boot
+///
+/// Equivalent to mcuboot's `boot_set_pending`. If `permanent` is `false`,
+/// the image is booted once for testing and reverted unless it's confirmed
+/// using [`set_confirmed`].
+pub fn set_pending<S: Storage>(secondary: &mut S, permanent: bool) -> Result<(), S::Error> {
+    let offsets = Offsets::new(secondary.capacity() as u32);
+    if permanent {
+        secondary.write(offsets.image_ok, &[FLAG_SET])?;
+    }
+    let swap_type = if permanent {
+        SwapType::Perm
+    } else {
+        SwapType::Test
+    };
+    secondary.write(offsets.swap_info, &[swap_type as u8])?;
+    secondary.write(offsets.magic, &TRAILER_MAGIC)
+}
+
+/// Confirm the running image in the primary slot, preventing a revert
+///
+/// Equivalent to mcuboot's `boot_set_confirmed`.
+pub fn set_confirmed<S: Storage>(primary: &mut S) -> Result<(), S::Error> {
+    let offsets = Offsets::new(primary.capacity() as u32);
+    if read_u8(primary, offsets.image_ok)? == FLAG_SET {
+        return Ok(());
+    }
+    primary.write(offsets.image_ok, &[FLAG_SET])
+}
@@ -0,0 +1,268 @@
+//! In-memory flash for tests on the host
+//!
+//! With the `mock` feature, [`MockFlash`] is available on the host, where
+//! it replaces the hardware. It provides the methods
+//! of [`FlashDriver`](https://docs.rs/rp2040-flash/latest/rp2040_flash/driver/struct.FlashDriver.html)
+//! and implements the same `embedded-storage` traits as
+//! [`nor_flash::Flash`](https://docs.rs/rp2040-flash/latest/rp2040_flash/nor_flash/struct.Flash.html),
+//! so storage code written against either can be unit tested. The stores
+//! of this crate, like [`kv::Store`](crate::kv::Store), accept it in place
+//! of the internal flash:
+//!
+//! ```
+//! use rp2040_flash::mock::MockFlash;
+//!
+//! let mut flash = MockFlash::new(0x10000);
+//! flash.erase(0x1000, 0x1000).unwrap();
+//! flash.program(0x1000, &[0x55; 256]).unwrap();
+//! let mut buf = [0u8; 4];
+//! flash.read(0x1000, &mut buf).unwrap();
+//! assert_eq!(buf, [0x55; 4]);
+//! ```
+//!
+//! Like real NOR flash, it starts out erased (0xff), erasing sets whole
+//! sectors to 0xff, and programming can only clear bits. Programming a
+//! range which wasn't erased fails with [`FlashError::VerifyFailed`], as
+//! the data read back would differ, like with
+//! `flash_range_program_verified` on hardware. Ranges are checked for
+//! alignment and bounds like on hardware.
+
+use crate::error::FlashError;
+use crate::nor_flash::{from_kind, ERASE_SIZE, WRITE_SIZE};
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash,
+};
+use embedded_storage::{ReadStorage, Storage};
+
+const SECTOR_SIZE: u32 = ERASE_SIZE as u32;
+const PAGE_SIZE: u32 = WRITE_SIZE as u32;
+
+/// Flash contents kept in memory
+#[derive(Debug, Clone)]
+pub struct MockFlash {
+    data: Vec<u8>,
+    erase_counts: Vec<u32>,
+}
+
+impl MockFlash {
+    /// An erased flash of `size` bytes
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` isn't a multiple of 4096 or exceeds 16 MiB.
+    pub fn new(size: u32) -> Self {
+        assert!(size & (SECTOR_SIZE - 1) == 0 && size <= 0x1000000);
+        MockFlash {
+            data: vec![0xff; size as usize],
+            erase_counts: vec![0; (size / SECTOR_SIZE) as usize],
+        }
+    }
+
+    /// A flash with the given contents
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` isn't a multiple of 4096 or exceeds
+    /// 16 MiB.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut flash = Self::new(data.len() as u32);
+        flash.data.copy_from_slice(data);
+        flash
+    }
+
+    /// The current flash contents
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// How often the sector at `addr` was erased
+    pub fn erase_count(&self, addr: u32) -> u32 {
+        self.erase_counts[(addr / SECTOR_SIZE) as usize]
+    }
+
+    /// Size of the flash
+    pub fn size(&mut self) -> u32 {
+        self.data.len() as u32
+    }
+
+    /// A Winbond style JEDEC ID, with the density byte matching the size
+    ///
+    /// Sizes which aren't a power of two are rounded up, like the capacity
+    /// of real chips.
+    pub fn jedec_id(&mut self) -> u32 {
+        let density = usize::BITS - (self.data.len().max(2) - 1).leading_zeros();
+        0xef4000 | density
+    }
+
+    /// Read `buf.len()` bytes at `addr`
+    pub fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let start = self.range(addr, buf.len() as u32, 1)?;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Erase `len` bytes at `addr`
+    ///
+    /// `addr` and `len` must be multiples of 4096.
+    pub fn erase(&mut self, addr: u32, len: u32) -> Result<(), FlashError> {
+        let start = self.range(addr, len, SECTOR_SIZE)?;
+        self.data[start..start + len as usize].fill(0xff);
+        for sector in addr / SECTOR_SIZE..(addr + len) / SECTOR_SIZE {
+            self.erase_counts[sector as usize] += 1;
+        }
+        Ok(())
+    }
+
+    /// Program `data` at `addr`
+    ///
+    /// `addr` and the length of `data` must be multiples of 256. Bits are
+    /// only cleared, so programming fails with
+    /// [`FlashError::VerifyFailed`] if `data` has bits set which are
+    /// already cleared.
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let start = self.range(addr, data.len() as u32, PAGE_SIZE)?;
+        let target = &mut self.data[start..start + data.len()];
+        for (t, d) in target.iter_mut().zip(data) {
+            *t &= d;
+        }
+        match target.iter().zip(data).position(|(t, d)| t != d) {
+            Some(pos) => Err(FlashError::VerifyFailed {
+                offset: addr + pos as u32,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Erase and program `data` at `addr`
+    ///
+    /// `addr` and the length of `data` must be multiples of 4096.
+    pub fn erase_and_program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.range(addr, data.len() as u32, SECTOR_SIZE)?;
+        self.erase(addr, data.len() as u32)?;
+        self.program(addr, data)
+    }
+
+    /// Write `data` at `addr` without alignment requirements
+    ///
+    /// Like `flash_write_unaligned` on hardware, the affected sectors are
+    /// rewritten, only erasing them if bits need to be set.
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let start = self.range(addr, data.len() as u32, 1)?;
+        let first = addr / SECTOR_SIZE;
+        let last = (addr + data.len() as u32).div_ceil(SECTOR_SIZE);
+        for sector in first..last {
+            let sector_start = (sector * SECTOR_SIZE) as usize;
+            let from = start.max(sector_start);
+            let to = (start + data.len()).min(sector_start + SECTOR_SIZE as usize);
+            let new = &data[from - start..to - start];
+            let old = &self.data[from..to];
+            if old == new {
+                continue;
+            }
+            if old.iter().zip(new).any(|(o, n)| n & !o != 0) {
+                self.erase_counts[sector as usize] += 1;
+                let mut contents =
+                    self.data[sector_start..sector_start + SECTOR_SIZE as usize].to_vec();
+                contents[from - sector_start..to - sector_start].copy_from_slice(new);
+                self.data[sector_start..sector_start + SECTOR_SIZE as usize]
+                    .copy_from_slice(&contents);
+            } else {
+                self.data[from..to].copy_from_slice(new);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check alignment and bounds, returning the start index
+    fn range(&self, addr: u32, len: u32, align: u32) -> Result<usize, FlashError> {
+        if addr & (align - 1) != 0 || len & (align - 1) != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        match addr.checked_add(len) {
+            Some(end) if end as usize <= self.data.len() => Ok(addr as usize),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+}
+
+impl ErrorType for MockFlash {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        check_read(self, offset, bytes.len()).map_err(from_kind)?;
+        MockFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        check_erase(self, from, to).map_err(from_kind)?;
+        MockFlash::erase(self, from, to - from)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check_write(self, offset, bytes.len()).map_err(from_kind)?;
+        // Like hardware, writing bits which are already cleared isn't an error
+        let start = offset as usize;
+        for (t, d) in self.data[start..start + bytes.len()].iter_mut().zip(bytes) {
+            *t &= d;
+        }
+        Ok(())
+    }
+}
+
+impl MultiwriteNorFlash for MockFlash {}
+
+impl ReadStorage for MockFlash {
+    type Error = FlashError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        MockFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl Storage for MockFlash {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        MockFlash::write(self, offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jedec_id_rounds_size_up() {
+        assert_eq!(MockFlash::new(0x200000).jedec_id(), 0xef4015);
+        assert_eq!(MockFlash::new(0x3000).jedec_id(), 0xef400e);
+        assert_eq!(MockFlash::new(0x1000).jedec_id(), 0xef400c);
+    }
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut flash = MockFlash::new(0x1000);
+        flash.program(0, &[0x0f; 256]).unwrap();
+        assert_eq!(
+            flash.program(0, &[0xf0; 256]),
+            Err(FlashError::VerifyFailed { offset: 0 })
+        );
+        flash.erase(0, 0x1000).unwrap();
+        assert_eq!(flash.erase_count(0), 1);
+        assert_eq!(flash.program(1, &[0; 256]), Err(FlashError::NotAligned));
+    }
+}
@@ -0,0 +1,125 @@
+//! MPU-assisted detection of stray writes to flash
+//!
+//! Writes to the XIP address window are silently ignored by the hardware,
+//! so a stray pointer write into flash-resident data goes unnoticed. As a
+//! development aid, [`enable`] programs an MPU region covering a data
+//! partition in the cached XIP alias as read-only, so such writes cause a
+//! HardFault at the offending instruction. Code and other aliases of the
+//! XIP window are not covered.
+//!
+//! Flash operations of this crate don't write through the XIP window and
+//! are not affected. The few places which do legitimately write to the XIP
+//! window (cache line maintenance) disable the MPU temporarily.
+//!
+//! Only available with the `mpu-guard` feature.
+
+use crate::error::FlashError;
+use core::ptr::{read_volatile, write_volatile};
+
+const MPU_CTRL: *mut u32 = 0xe000_ed94 as *mut u32;
+const MPU_RNR: *mut u32 = 0xe000_ed98 as *mut u32;
+const MPU_RBAR: *mut u32 = 0xe000_ed9c as *mut u32;
+const MPU_RASR: *mut u32 = 0xe000_eda0 as *mut u32;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+const XIP_BASE: u32 = 0x1000_0000;
+/// The flash is at most 16 MiB = 2^24 bytes
+const FLASH_SIZE_LOG2: u32 = 24;
+
+/// Make `len` bytes of flash at `offset` read-only, using MPU region `region` (0-7)
+///
+/// `offset` is relative to the beginning of the flash area. An MPU region
+/// covers a naturally aligned power of two, split into 8 subregions, so
+/// the range must consist of whole subregions of such a block, e.g. a
+/// 12 KiB partition at 0x1f0000 in the 16 KiB block at that address.
+/// Returns [`FlashError::NotAligned`] if it doesn't, and
+/// [`FlashError::OutOfBounds`] if it is empty or exceeds 16 MiB. Use one
+/// region per data partition.
+///
+/// The MPU is enabled with the default memory map as background region,
+/// so other memory accesses are unaffected. Regions with a higher number
+/// take precedence over lower ones.
+///
+/// # Safety
+///
+/// Must not conflict with other uses of the MPU, e.g. by an RTOS.
+pub unsafe fn enable(region: u8, offset: u32, len: u32) -> Result<(), FlashError> {
+    assert!(region < 8);
+    if len == 0 || offset as u64 + len as u64 > 1 << FLASH_SIZE_LOG2 {
+        return Err(FlashError::OutOfBounds);
+    }
+    let (base, size_log2, disabled) = encode(offset, len).ok_or(FlashError::NotAligned)?;
+    cortex_m::asm::dsb();
+    write_volatile(MPU_RNR, region as u32);
+    write_volatile(MPU_RBAR, XIP_BASE + base);
+    // AP = 0b110: read-only, code execution allowed
+    // C = 1: normal, cacheable memory
+    // SRD: disabled subregions
+    // SIZE = log2(size) - 1
+    write_volatile(
+        MPU_RASR,
+        (0b110 << 24) | (1 << 17) | ((disabled as u32) << 8) | ((size_log2 - 1) << 1) | 1,
+    );
+    write_volatile(MPU_CTRL, CTRL_PRIVDEFENA | CTRL_ENABLE);
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    Ok(())
+}
+
+/// Find the smallest MPU region covering exactly the range
+///
+/// Returns the base offset and log2 of the size of the region, and the
+/// mask of the subregions outside of the range.
+fn encode(offset: u32, len: u32) -> Option<(u32, u32, u8)> {
+    // Subregions are only supported for regions of 256 bytes and larger
+    (8..=FLASH_SIZE_LOG2).find_map(|size_log2| {
+        let base = offset & !((1 << size_log2) - 1);
+        let subregion = 1 << (size_log2 - 3);
+        if offset + len > base + (1 << size_log2) || (offset | len) & (subregion - 1) != 0 {
+            return None;
+        }
+        let first = (offset - base) / subregion;
+        let count = len / subregion;
+        let enabled = (((1u32 << count) - 1) << first) as u8;
+        Some((base, size_log2, !enabled))
+    })
+}
+
+/// Remove the guard configured by [`enable`]
+///
+/// The MPU itself stays enabled, as other regions may still be in use.
+///
+/// # Safety
+///
+/// Must not conflict with other uses of the MPU, e.g. by an RTOS.
+pub unsafe fn disable(region: u8) {
+    assert!(region < 8);
+    cortex_m::asm::dsb();
+    write_volatile(MPU_RNR, region as u32);
+    write_volatile(MPU_RASR, 0);
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Run `f` with the MPU disabled, for legitimate writes to the XIP window
+pub(crate) fn unguarded<R>(f: impl FnOnce() -> R) -> R {
+    // Safety: only toggles the MPU enable bit, restoring its previous state
+    unsafe {
+        let ctrl = read_volatile(MPU_CTRL);
+        if ctrl & CTRL_ENABLE == 0 {
+            return f();
+        }
+        crate::cs::free(|| {
+            cortex_m::asm::dsb();
+            write_volatile(MPU_CTRL, ctrl & !CTRL_ENABLE);
+            cortex_m::asm::isb();
+            let result = f();
+            cortex_m::asm::dsb();
+            write_volatile(MPU_CTRL, ctrl);
+            cortex_m::asm::isb();
+            result
+        })
+    }
+}
@@ -0,0 +1,157 @@
+//! Parking the other core during flash operations
+//!
+//! While flash is erased or programmed, the other core must not execute
+//! code from flash. This implements a lockout protocol similar to the
+//! `multicore_lockout` functions of the pico-sdk, using the SIO FIFO:
+//!
+//! - The core which is going to access flash calls [`flash_safe_execute`].
+//!   It sends a request through the FIFO and waits for an acknowledgement.
+//! - The other core handles the request in [`lockout_victim_handler`],
+//!   usually called from its `SIO_IRQ_PROC0` or `SIO_IRQ_PROC1` interrupt
+//!   handler. It disables interrupts, acknowledges the request and spins in
+//!   RAM until it is released.
+//! - After the flash operation, [`flash_safe_execute`] releases the other
+//!   core and waits for a second acknowledgement.
+//!
+//! The protocol uses the FIFO exclusively while a lockout is in progress.
+//! Other messages in the FIFO are discarded.
+
+use core::ptr::{read_volatile, write_volatile};
+
+const SIO_BASE: u32 = 0xd000_0000;
+const FIFO_ST: *mut u32 = (SIO_BASE + 0x50) as *mut u32;
+const FIFO_WR: *mut u32 = (SIO_BASE + 0x54) as *mut u32;
+const FIFO_RD: *const u32 = (SIO_BASE + 0x58) as *const u32;
+
+const FIFO_ST_VLD: u32 = 1 << 0;
+const FIFO_ST_RDY: u32 = 1 << 1;
+
+const LOCKOUT_START: u32 = 0x73a8_831e;
+const LOCKOUT_END: u32 = 0x73a8_831f;
+
+/// Number of FIFO polls before giving up on the other core
+const TIMEOUT_POLLS: u32 = 1_000_000;
+
+/// The other core didn't acknowledge the lockout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LockoutError {
+    /// The other core didn't acknowledge the request to park
+    ///
+    /// It is either not running, or doesn't call [`lockout_victim_handler`].
+    /// `f` was not called. The release is sent anyway, so the other core
+    /// doesn't stay parked if it handles the request late.
+    StartTimeout,
+    /// The other core didn't acknowledge being released
+    ///
+    /// `f` was called and its result is discarded.
+    EndTimeout,
+}
+
+/// Run `f` while the other core is parked in RAM
+///
+/// Interrupts are disabled on the current core while `f` runs, so `f` can
+/// call the flash functions of this crate directly.
+///
+/// # Safety
+///
+/// The other core must call [`lockout_victim_handler`] when data is
+/// available in its FIFO, and must not use the FIFO for anything else
+/// while a lockout can happen.
+///
+/// DMA must not access flash while `f` runs.
+pub unsafe fn flash_safe_execute<R>(f: impl FnOnce() -> R) -> Result<R, LockoutError> {
+    crate::cs::free(|| {
+        drain();
+        if !send(LOCKOUT_START) || !wait_for(LOCKOUT_START) {
+            warn!("other core didn't acknowledge lockout");
+            // Its acknowledgements are discarded by the next `drain`
+            send(LOCKOUT_END);
+            return Err(LockoutError::StartTimeout);
+        }
+        let result = f();
+        if !send(LOCKOUT_END) || !wait_for(LOCKOUT_END) {
+            warn!("other core didn't acknowledge release");
+            return Err(LockoutError::EndTimeout);
+        }
+        Ok(result)
+    })
+}
+
+/// Discard stale messages and clear the error flags
+unsafe fn drain() {
+    while read_volatile(FIFO_ST) & FIFO_ST_VLD != 0 {
+        read_volatile(FIFO_RD);
+    }
+    write_volatile(FIFO_ST, 0xff);
+}
+
+unsafe fn send(value: u32) -> bool {
+    for _ in 0..TIMEOUT_POLLS {
+        if read_volatile(FIFO_ST) & FIFO_ST_RDY != 0 {
+            write_volatile(FIFO_WR, value);
+            cortex_m::asm::sev();
+            return true;
+        }
+    }
+    false
+}
+
+unsafe fn wait_for(value: u32) -> bool {
+    for _ in 0..TIMEOUT_POLLS {
+        if read_volatile(FIFO_ST) & FIFO_ST_VLD != 0 && read_volatile(FIFO_RD) == value {
+            return true;
+        }
+    }
+    false
+}
+
+/// Handle a lockout request from the other core
+///
+/// Call this on the core which should be parked, when data is available
+/// in its FIFO, e.g. from the `SIO_IRQ_PROCn` interrupt handler. If the
+/// FIFO holds a lockout request, this disables interrupts, acknowledges the
+/// request and spins in RAM until the other core releases it. Other
+/// messages are discarded.
+///
+/// The function is located in RAM and doesn't access flash while parked.
+///
+/// # Safety
+///
+/// Must not be called on the core running [`flash_safe_execute`].
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn lockout_victim_handler() {
+    core::arch::asm!(
+        "movs r0, #0xd0",
+        "lsls r0, r0, #24", // SIO_BASE
+        "ldr r1, [r0, #0x50]", // FIFO_ST
+        "lsls r1, r1, #31", // VLD
+        "beq 9f",
+        "ldr r1, [r0, #0x58]", // FIFO_RD
+        "cmp r1, r2",
+        "bne 9f",
+        "mrs r1, primask",
+        "mov r12, r1",
+        "cpsid i",
+        "str r2, [r0, #0x54]", // Acknowledge start
+        "sev",
+        "1:",
+        "ldr r1, [r0, #0x50]",
+        "lsls r1, r1, #31",
+        "beq 1b",
+        "ldr r1, [r0, #0x58]",
+        "cmp r1, r3",
+        "bne 1b",
+        "str r3, [r0, #0x54]", // Acknowledge end
+        "sev",
+        "mov r1, r12",
+        "msr primask, r1",
+        "9:",
+        in("r2") LOCKOUT_START,
+        in("r3") LOCKOUT_END,
+        out("r0") _,
+        out("r1") _,
+        out("r12") _,
+    );
+}
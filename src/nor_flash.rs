@@ -0,0 +1,127 @@
+//! `embedded-storage` NOR flash traits
+//!
+//! [`Flash`] implements [`ReadNorFlash`], [`NorFlash`] and
+//! [`MultiwriteNorFlash`] for the first `SIZE` bytes of the internal flash,
+//! so storage crates like `sequential-storage` or `tickv` can use it
+//! directly. Offsets are relative to the beginning of the flash area.
+//!
+//! When building for the host, only the error conversion is available,
+//! for use by `mock::MockFlash`.
+
+use crate::error::FlashError;
+#[cfg(target_os = "none")]
+use crate::flash;
+#[cfg(target_os = "none")]
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash,
+};
+use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+/// Size of an erasable sector
+pub const ERASE_SIZE: usize = 4096;
+/// Size of a programmable page
+pub const WRITE_SIZE: usize = 256;
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(any(target_os = "none", test, feature = "mock"))]
+pub(crate) fn from_kind(kind: NorFlashErrorKind) -> FlashError {
+    match kind {
+        NorFlashErrorKind::NotAligned => FlashError::NotAligned,
+        _ => FlashError::OutOfBounds,
+    }
+}
+
+/// The internal flash, with a size of `SIZE` bytes
+///
+/// Only usable on the RP2040 and RP2350. The type exists on the host as
+/// well, as the default flash of the stores.
+pub struct Flash<const SIZE: usize> {
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
+    use_boot2: bool,
+}
+
+/// The whole 16 MiB flash window, used by the stores unless they are given
+/// another flash, e.g. `mock::MockFlash`
+pub type InternalFlash = Flash<0x100_0000>;
+
+#[cfg(target_os = "none")]
+impl<const SIZE: usize> Flash<SIZE> {
+    const VALID_SIZE: () = assert!(
+        SIZE & (ERASE_SIZE - 1) == 0 && SIZE <= 0x1000000,
+        "SIZE must be a multiple of 4096 and at most 16MiB"
+    );
+
+    /// Access the internal flash
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// There must only be one instance at a time, and the caller must not
+    /// erase or program flash containing code or data of the running
+    /// program. Use [`protect`](crate::protect) to guard such regions.
+    ///
+    /// Each operation disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash during
+    /// erase and write operations.
+    pub unsafe fn new(use_boot2: bool) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::VALID_SIZE;
+        Flash { use_boot2 }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl<const SIZE: usize> ErrorType for Flash<SIZE> {
+    type Error = FlashError;
+}
+
+#[cfg(target_os = "none")]
+impl<const SIZE: usize> ReadNorFlash for Flash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        check_read(self, offset, bytes.len()).map_err(from_kind)?;
+        let src = (0x10000000 + offset) as *const u8;
+        // Safety: the range is inside the XIP window, which is always readable
+        unsafe { core::ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), bytes.len()) };
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+#[cfg(target_os = "none")]
+impl<const SIZE: usize> NorFlash for Flash<SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        check_erase(self, from, to).map_err(from_kind)?;
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::flash_range_erase_checked(from, to - from, use_boot2) })
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check_write(self, offset, bytes.len()).map_err(from_kind)?;
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::flash_range_program_checked(offset, bytes, use_boot2) })
+    }
+}
+
+#[cfg(target_os = "none")]
+// Programming only clears bits, so pages can be written several times
+impl<const SIZE: usize> MultiwriteNorFlash for Flash<SIZE> {}
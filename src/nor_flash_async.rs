@@ -0,0 +1,111 @@
+//! `embedded-storage-async` NOR flash traits
+//!
+//! [`AsyncFlash`] wraps [`Flash`] and implements the async NOR flash
+//! traits. Erase and write operations are split into single sectors and
+//! pages, and the task yields to the executor after each of them. This
+//! bounds the time interrupts are disabled and other tasks are stalled to
+//! a single sector erase, instead of the whole operation.
+//!
+//! The flash operations themselves are still blocking, the XIP window
+//! isn't usable while they run.
+//!
+//! Only available with the `async` feature.
+
+use crate::error::FlashError;
+use crate::nor_flash::{Flash, ERASE_SIZE, WRITE_SIZE};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use embedded_storage::nor_flash::{
+    check_erase, check_write, ErrorType, NorFlash as _, ReadNorFlash as _,
+};
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+/// The internal flash, with a size of `SIZE` bytes, accessed asynchronously
+pub struct AsyncFlash<const SIZE: usize> {
+    flash: Flash<SIZE>,
+}
+
+impl<const SIZE: usize> AsyncFlash<SIZE> {
+    /// Access the internal flash
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`Flash::new`].
+    pub unsafe fn new(use_boot2: bool) -> Self {
+        AsyncFlash {
+            flash: Flash::new(use_boot2),
+        }
+    }
+
+    /// Get back the blocking interface
+    pub fn into_blocking(self) -> Flash<SIZE> {
+        self.flash
+    }
+}
+
+impl<const SIZE: usize> ErrorType for AsyncFlash<SIZE> {
+    type Error = FlashError;
+}
+
+impl<const SIZE: usize> ReadNorFlash for AsyncFlash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.flash.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for AsyncFlash<SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        // Report errors before erasing anything
+        check_erase(&self.flash, from, to).map_err(crate::nor_flash::from_kind)?;
+        for sector in (from..to).step_by(ERASE_SIZE) {
+            self.flash.erase(sector, sector + ERASE_SIZE as u32)?;
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check_write(&self.flash, offset, bytes.len()).map_err(crate::nor_flash::from_kind)?;
+        for (i, page) in bytes.chunks(WRITE_SIZE).enumerate() {
+            self.flash.write(offset + (i * WRITE_SIZE) as u32, page)?;
+            yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> MultiwriteNorFlash for AsyncFlash<SIZE> {}
+
+/// Return `Pending` once, waking the task immediately
+fn yield_now() -> impl Future<Output = ()> {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    YieldNow(false)
+}
@@ -0,0 +1,221 @@
+//! Byte-granular access to a region of flash
+//!
+//! [`Partition`] implements the `embedded-storage` traits
+//! [`ReadStorage`] and [`Storage`], which allow writing arbitrary bytes
+//! at arbitrary offsets. Writes are done by read-modify-write of the
+//! affected sectors, so crates which only speak this simpler interface
+//! work on the internal flash out of the box.
+
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::Flash;
+use crate::nor_flash::InternalFlash;
+use embedded_storage::nor_flash::MultiwriteNorFlash;
+use embedded_storage::{ReadStorage, Storage};
+
+/// A region of flash
+///
+/// `F` is the flash holding the region, the internal flash unless given
+/// to [`with_flash`](Self::with_flash).
+pub struct Partition<F = InternalFlash> {
+    flash: F,
+    offset: u32,
+    len: u32,
+}
+
+#[cfg(target_os = "none")]
+impl Partition {
+    /// Use `len` bytes of flash starting at `offset`
+    ///
+    /// `offset` is relative to the beginning of the flash area. `offset`
+    /// and `len` must be multiples of 4096.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The region must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each write disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash during writes.
+    pub unsafe fn new(offset: u32, len: u32, use_boot2: bool) -> Self {
+        Self::with_flash(Flash::new(use_boot2), offset, len)
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> Partition<F> {
+    /// Use `len` bytes of `flash` starting at `offset`
+    ///
+    /// `offset` and `len` must be multiples of 4096.
+    pub fn with_flash(flash: F, offset: u32, len: u32) -> Self {
+        assert!(offset & (SECTOR_SIZE - 1) == 0);
+        assert!(len & (SECTOR_SIZE - 1) == 0);
+        assert!(offset
+            .checked_add(len)
+            .is_some_and(|end| end as usize <= flash.capacity()));
+        Partition { flash, offset, len }
+    }
+
+    /// Offset of the partition, relative to the beginning of the flash area
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Length of the partition in bytes
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Returns `true` if the partition has a length of 0
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Erase `len` bytes starting at `offset` within the partition
+    ///
+    /// `offset` and `len` must be multiples of 4096, otherwise
+    /// [`FlashError::NotAligned`] is returned.
+    pub fn erase(&mut self, offset: u32, len: u32) -> Result<(), FlashError> {
+        if (offset | len) & (SECTOR_SIZE - 1) != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        self.check_bounds(offset, len as usize)?;
+        let addr = self.offset + offset;
+        self.flash.erase(addr, addr + len)
+    }
+
+    fn check_bounds(&self, offset: u32, len: usize) -> Result<(), FlashError> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Rewrite part of a sector, starting at `offset` within the partition
+    ///
+    /// Only erases the sector if bits need to be set, and only programs
+    /// the pages which change.
+    fn write_sector(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let addr = self.offset + offset;
+        let sector = addr & !(SECTOR_SIZE - 1);
+        let start = (addr - sector) as usize;
+        let end = start + bytes.len();
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        self.flash.read(sector, &mut buf)?;
+        let old = &buf[start..end];
+        if old == bytes {
+            return Ok(());
+        }
+        let erase = old.iter().zip(bytes).any(|(o, n)| n & !o != 0);
+        buf[start..end].copy_from_slice(bytes);
+        let pages = if erase {
+            self.flash.erase(sector, sector + SECTOR_SIZE)?;
+            0..SECTOR_SIZE as usize
+        } else {
+            start & !(PAGE_SIZE as usize - 1)..end.next_multiple_of(PAGE_SIZE as usize)
+        };
+        let first = pages.start;
+        for (i, data) in buf[pages].chunks(PAGE_SIZE as usize).enumerate() {
+            // Erased pages are left alone
+            if data.iter().any(|&b| b != 0xff) {
+                let page_addr = sector + (first + i * PAGE_SIZE as usize) as u32;
+                self.flash.write(page_addr, data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> ReadStorage for Partition<F> {
+    type Error = FlashError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.check_bounds(offset, bytes.len())?;
+        self.flash.read(self.offset + offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.len as usize
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> Storage for Partition<F> {
+    /// Write `bytes` at `offset`, using read-modify-write of the affected sectors
+    ///
+    /// Sectors whose contents don't change are skipped, and sectors which
+    /// only need bits cleared are programmed without erasing. Uses a 4 KiB
+    /// buffer on the stack.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.check_bounds(offset, bytes.len())?;
+        let mut offset = offset;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let sector = offset & !(SECTOR_SIZE - 1);
+            let n = bytes.len().min((sector + SECTOR_SIZE - offset) as usize);
+            self.write_sector(offset, &bytes[..n])?;
+            offset += n as u32;
+            bytes = &bytes[n..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    #[test]
+    fn writes_across_sectors() {
+        let mut partition = Partition::with_flash(MockFlash::new(0x4000), 0x1000, 0x2000);
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8);
+        partition.write(0xf80, &data).unwrap();
+        let mut buf = [0u8; 300];
+        partition.read(0xf80, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        // Nothing outside the range changed
+        assert!(partition.flash.as_bytes()[..0x1f80]
+            .iter()
+            .all(|&b| b == 0xff));
+        assert!(partition.flash.as_bytes()[0x1f80 + 300..]
+            .iter()
+            .all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn erases_only_to_set_bits() {
+        let mut partition = Partition::with_flash(MockFlash::new(0x1000), 0, 0x1000);
+        partition.write(10, &[0x0f; 4]).unwrap();
+        partition.write(300, &[0x55]).unwrap();
+        partition.write(10, &[0x0e; 4]).unwrap();
+        assert_eq!(partition.flash.erase_count(0), 0);
+        partition.write(10, &[0xf0; 4]).unwrap();
+        assert_eq!(partition.flash.erase_count(0), 1);
+        let mut buf = [0u8; 1];
+        partition.read(300, &mut buf).unwrap();
+        assert_eq!(buf, [0x55]);
+    }
+
+    #[test]
+    fn checks_bounds() {
+        let mut partition = Partition::with_flash(MockFlash::new(0x2000), 0, 0x1000);
+        assert_eq!(
+            partition.write(0xfff, &[0, 0]),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(
+            partition.read(u32::MAX, &mut [0]),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(partition.erase(0x800, 0x1000), Err(FlashError::NotAligned));
+        assert_eq!(partition.erase(0, 0x800), Err(FlashError::NotAligned));
+        assert_eq!(
+            partition.erase(0x1000, 0x1000),
+            Err(FlashError::OutOfBounds)
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Coordination with an attached debug probe
+//!
+//! While a flash operation is in progress, XIP is disabled. If a debugger
+//! reads flash during that time (e.g. when attaching to RTT, or to show a
+//! backtrace), it interferes with the operation and may corrupt flash.
+//!
+//! To let the debugger know when flash must not be read, this crate sets
+//! the global symbol [`RP2040_FLASH_BUSY`] to [`BUSY`] for the duration
+//! of each operation, and optionally writes the same value to one of the
+//! watchdog scratch registers, whose address is fixed and doesn't depend on
+//! the ELF file.
+//!
+//! A debugger script (e.g. using probe-rs as a library, or a gdb hook)
+//! should read one of those locations before accessing flash, and wait
+//! while it contains [`BUSY`]. Reading RAM and peripheral registers is
+//! safe at any time.
+//!
+//! Note that `probe-rs run` and defmt-rtt don't check this flag by
+//! themselves. With them, wait a few milliseconds after startup before
+//! the first flash operation, so the probe can finish attaching to RTT.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+/// Value signalling an ongoing flash operation
+pub const BUSY: u32 = 0xf1a5_b057;
+
+/// Value signalling that flash may be accessed
+pub const IDLE: u32 = 0;
+
+/// Contains [`BUSY`] while a flash operation is in progress, [`IDLE`] otherwise
+#[no_mangle]
+#[used]
+pub static RP2040_FLASH_BUSY: AtomicU32 = AtomicU32::new(IDLE);
+
+/// Index of the watchdog scratch register to signal on, or `NO_SCRATCH`
+static SCRATCH_INDEX: AtomicU8 = AtomicU8::new(NO_SCRATCH);
+const NO_SCRATCH: u8 = 0xff;
+
+/// WATCHDOG SCRATCH0, RP2040 datasheet 4.7.6
+#[cfg(not(feature = "rp235x"))]
+const WATCHDOG_SCRATCH0: *mut u32 = 0x4005_800c as *mut u32;
+/// WATCHDOG SCRATCH0 on the RP2350
+#[cfg(feature = "rp235x")]
+const WATCHDOG_SCRATCH0: *mut u32 = 0x400d_800c as *mut u32;
+
+/// Also signal flash operations in watchdog scratch register `index`
+///
+/// Only scratch registers 0 to 3 can be used, as the boot ROM uses
+/// registers 4 to 7. Pass `None` to stop signalling.
+pub fn set_watchdog_scratch(index: Option<u8>) {
+    let index = match index {
+        Some(index) => {
+            assert!(
+                index < 4,
+                "watchdog scratch registers 4-7 are used by the boot ROM"
+            );
+            index
+        }
+        None => NO_SCRATCH,
+    };
+    SCRATCH_INDEX.store(index, Ordering::Relaxed);
+}
+
+fn signal(value: u32) {
+    RP2040_FLASH_BUSY.store(value, Ordering::SeqCst);
+    let index = SCRATCH_INDEX.load(Ordering::Relaxed);
+    if index != NO_SCRATCH {
+        // Safety: index is in range 0..4, and the scratch registers
+        // have no side effects
+        unsafe { core::ptr::write_volatile(WATCHDOG_SCRATCH0.add(index as usize), value) };
+    }
+}
+
+/// Run `f`, signalling a flash operation to the debug probe
+pub(crate) fn busy<R>(f: impl FnOnce() -> R) -> R {
+    signal(BUSY);
+    let result = f();
+    signal(IDLE);
+    result
+}
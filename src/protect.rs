@@ -0,0 +1,150 @@
+//! Software write protection of flash regions
+//!
+//! Regions like the boot loader, calibration data or a recovery image can
+//! be registered as protected. The checked erase and program functions of
+//! this crate, like `flash_range_erase_checked`, and the storage types built
+//! on them check the list and refuse to touch a protected region, unless
+//! the operation runs with an [`Override`] for it:
+//!
+//! ```ignore
+//! let recovery = Region { start: 0x100000, len: 0x80000 };
+//! protect::add(recovery)?;
+//! // Fails with FlashError::WriteProtected
+//! unsafe { flash::flash_range_erase_checked(0x100000, 0x1000, true) }?;
+//! unsafe { Override::new(recovery) }
+//!     .run(|| unsafe { flash::flash_range_erase_checked(0x100000, 0x1000, true) })?;
+//! ```
+//!
+//! The unchecked functions like `flash_range_erase` don't consult the list.
+//!
+//! This only protects against mistakes in code using this crate. It does
+//! not stop other code from writing flash, see the hardware block protection
+//! of the flash chip for that.
+
+use crate::cs::Mutex;
+use crate::error::FlashError;
+use core::cell::Cell;
+
+/// Maximum number of protected regions
+pub const MAX_REGIONS: usize = 8;
+
+/// A range of flash, given as offset from the start of flash and length in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Region {
+    pub start: u32,
+    pub len: u32,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.start as u64 + self.len as u64
+    }
+
+    fn overlaps(&self, start: u32, len: u32) -> bool {
+        (start as u64) < self.end() && (self.start as u64) < start as u64 + len as u64
+    }
+
+    fn contains(&self, start: u32, len: u32) -> bool {
+        self.start <= start && start as u64 + len as u64 <= self.end()
+    }
+}
+
+/// All slots for protected regions are in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TooManyRegions;
+
+static REGIONS: Mutex<Cell<[Option<Region>; MAX_REGIONS]>> =
+    Mutex::new(Cell::new([None; MAX_REGIONS]));
+/// Region of the [`Override`] currently running
+static OVERRIDE: Mutex<Cell<Option<Region>>> = Mutex::new(Cell::new(None));
+
+/// Protect `region` from being erased or programmed by this crate
+pub fn add(region: Region) -> Result<(), TooManyRegions> {
+    REGIONS.lock(|cell| {
+        let mut regions = cell.get();
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(TooManyRegions)?;
+        *slot = Some(region);
+        cell.set(regions);
+        Ok(())
+    })
+}
+
+/// Remove the protection of `region`, which must have been added before
+///
+/// `token` must cover `region`. Returns `false` if `region` wasn't
+/// protected, or isn't covered by `token`.
+pub fn remove(region: Region, token: &Override) -> bool {
+    if !token.region.contains(region.start, region.len) {
+        return false;
+    }
+    REGIONS.lock(|cell| {
+        let mut regions = cell.get();
+        let found = regions.iter_mut().find(|r| **r == Some(region));
+        let removed = found.map(|r| *r = None).is_some();
+        cell.set(regions);
+        removed
+    })
+}
+
+/// Check if the given range may be erased or programmed
+///
+/// Returns [`FlashError::WriteProtected`] if it overlaps a protected
+/// region, unless the range is inside the region of a running [`Override`].
+pub fn check(start: u32, len: u32) -> Result<(), FlashError> {
+    crate::cs::free(|| {
+        if OVERRIDE
+            .lock(Cell::get)
+            .is_some_and(|r| r.contains(start, len))
+        {
+            return Ok(());
+        }
+        match REGIONS
+            .lock(Cell::get)
+            .iter()
+            .flatten()
+            .find(|r| r.overlaps(start, len))
+        {
+            Some(region) => {
+                error!("write to protected region at {:#x}", region.start);
+                Err(FlashError::WriteProtected {
+                    offset: region.start,
+                })
+            }
+            None => Ok(()),
+        }
+    })
+}
+
+/// Token allowing a single operation to write a protected region
+///
+/// The token covers one range of flash. It is consumed by [`run`](Self::run),
+/// so it lifts the protection for one operation only.
+pub struct Override {
+    region: Region,
+}
+
+impl Override {
+    /// Allow writing the protected regions inside `region`
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible for only writing protected regions deliberately.
+    pub unsafe fn new(region: Region) -> Self {
+        Override { region }
+    }
+
+    /// Run the flash operation `f`, which may write inside the token's region
+    ///
+    /// Checks of ranges outside the region still fail.
+    pub fn run<R>(self, f: impl FnOnce() -> R) -> R {
+        let previous = OVERRIDE.lock(|cell| cell.replace(Some(self.region)));
+        let result = f();
+        OVERRIDE.lock(|cell| cell.set(previous));
+        result
+    }
+}
@@ -0,0 +1,112 @@
+//! Quad Enable (QE) bit
+//!
+//! Quad I/O read modes, as used by most 2nd stage boot loaders, only
+//! work if the QE bit of the flash chip is set, which turns the WP# and
+//! HOLD# pins into IO2 and IO3. Winbond chips usually ship with QE set,
+//! others don't, so boards with their flash need it set once, e.g. by
+//! provisioning firmware loaded to RAM.
+//!
+//! The location of the bit differs between vendors:
+//!
+//! - Winbond, GigaDevice and Zetta: bit 1 of status register 2
+//! - Macronix and ISSI: bit 6 of status register 1
+//!
+//! Other chips are reported as [`FlashError::Unsupported`].
+
+use crate::error::FlashError;
+use crate::flash;
+
+/// Where the QE bit is located
+#[derive(Clone, Copy)]
+enum Location {
+    /// SR2 bit 1
+    StatusRegister2,
+    /// SR1 bit 6
+    StatusRegister1,
+}
+
+impl Location {
+    fn detect(jedec_id: u32) -> Option<Location> {
+        match jedec_id >> 16 {
+            0xef | 0xc8 | 0xba => Some(Location::StatusRegister2),
+            0xc2 | 0x9d => Some(Location::StatusRegister1),
+            _ => None,
+        }
+    }
+
+    unsafe fn read(self, use_boot2: bool) -> bool {
+        match self {
+            Location::StatusRegister2 => flash::read_status_register(2, use_boot2) & (1 << 1) != 0,
+            Location::StatusRegister1 => flash::read_status_register(1, use_boot2) & (1 << 6) != 0,
+        }
+    }
+
+    unsafe fn write(self, enable: bool, use_boot2: bool) -> Result<(), FlashError> {
+        let update = |sr: u8, bit: u8| if enable { sr | bit } else { sr & !bit };
+        match self {
+            Location::StatusRegister2 => {
+                let sr1 = flash::read_status_register(1, use_boot2);
+                let sr2 = update(flash::read_status_register(2, use_boot2), 1 << 1);
+                // Writing both registers with 0x01 works on older chips
+                // lacking 0x31, newer ones may only support 0x31
+                flash::write_status(0x01, &[sr1, sr2], use_boot2)?;
+                if self.read(use_boot2) != enable {
+                    flash::write_status(0x31, &[sr2], use_boot2)?;
+                }
+                Ok(())
+            }
+            Location::StatusRegister1 => {
+                let sr1 = update(flash::read_status_register(1, use_boot2), 1 << 6);
+                flash::write_status(0x01, &[sr1], use_boot2)
+            }
+        }
+    }
+}
+
+unsafe fn detect(use_boot2: bool) -> Result<Location, FlashError> {
+    Location::detect(flash::flash_jedec_id(use_boot2)).ok_or(FlashError::Unsupported)
+}
+
+/// Check if the QE bit is set
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn quad_enabled(use_boot2: bool) -> Result<bool, FlashError> {
+    Ok(detect(use_boot2)?.read(use_boot2))
+}
+
+/// Set or clear the QE bit
+///
+/// The bit is written non-volatile, preserving the other status register
+/// bits, and only if it differs from `enable`. It is read back afterwards,
+/// and [`FlashError::StatusWriteFailed`] is returned if it didn't change, e.g.
+/// because the status register is locked.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+///
+/// Clearing QE while the 2nd stage boot loader uses a quad I/O mode makes
+/// the flash inaccessible after this call if `use_boot2` is `true`, and
+/// the program unbootable.
+pub unsafe fn set_quad_enable(enable: bool, use_boot2: bool) -> Result<(), FlashError> {
+    let location = detect(use_boot2)?;
+    if location.read(use_boot2) == enable {
+        return Ok(());
+    }
+    debug!("setting QE to {}", enable);
+    location.write(enable, use_boot2)?;
+    if location.read(use_boot2) != enable {
+        return Err(FlashError::StatusWriteFailed);
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Helpers for code which has to run while flash is inaccessible
+//!
+//! While a flash operation is in progress, XIP is disabled and any attempt
+//! to execute code from flash faults or returns garbage. Callbacks invoked
+//! during flash operations must therefore live in RAM (or ROM).
+
+/// Place functions in RAM
+///
+/// Wraps one or more function items, adding `#[inline(never)]` and
+/// `#[link_section = ".data.ram_func"]`, so they are copied to RAM at
+/// startup like the crate's own flash routines.
+///
+/// Only the function itself is placed in RAM. Everything it calls must
+/// either be inlined, or live in RAM or ROM as well. In particular, avoid
+/// anything that may panic, and be aware that debug builds inline much less.
+#[macro_export]
+macro_rules! ram {
+    ($($item:item)*) => {
+        $(
+            #[inline(never)]
+            #[link_section = ".data.ram_func"]
+            $item
+        )*
+    };
+}
+
+/// Striped SRAM (SRAM0-5)
+#[cfg(not(feature = "rp235x"))]
+const SRAM: core::ops::Range<usize> = 0x2000_0000..0x2004_2000;
+/// Non-striped alias of SRAM0-3
+#[cfg(not(feature = "rp235x"))]
+const SRAM_NON_STRIPED: core::ops::Range<usize> = 0x2100_0000..0x2104_0000;
+/// Boot ROM
+#[cfg(not(feature = "rp235x"))]
+const ROM: core::ops::Range<usize> = 0x0000_0000..0x0000_4000;
+
+/// SRAM0-9
+#[cfg(feature = "rp235x")]
+const SRAM: core::ops::Range<usize> = 0x2000_0000..0x2008_2000;
+/// The RP2350 has no non-striped alias
+#[cfg(feature = "rp235x")]
+const SRAM_NON_STRIPED: core::ops::Range<usize> = 0..0;
+/// Boot ROM
+#[cfg(feature = "rp235x")]
+const ROM: core::ops::Range<usize> = 0x0000_0000..0x0000_8000;
+
+/// Check if `addr` can be executed while XIP is disabled
+///
+/// Returns `true` for addresses in SRAM or in the boot ROM.
+pub fn is_flash_independent(addr: *const ()) -> bool {
+    // Clear the thumb bit of function pointers
+    let addr = addr as usize & !1;
+    SRAM.contains(&addr) || SRAM_NON_STRIPED.contains(&addr) || ROM.contains(&addr)
+}
+
+/// Panic if `f` doesn't point to code in RAM or ROM
+///
+/// Used to check callbacks before they are called while flash is
+/// inaccessible, where a flash-resident callback would fault in a
+/// way that is hard to diagnose. Call as `assert_in_ram(callback as *const ())`.
+#[track_caller]
+pub fn assert_in_ram(f: *const ()) {
+    assert!(
+        is_flash_independent(f),
+        "function is not located in RAM, use the ram! macro"
+    );
+}
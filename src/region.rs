@@ -0,0 +1,142 @@
+//! Flash regions with compile-time checked geometry
+//!
+//! [`FlashRegion`] takes its offset and length as const generic
+//! parameters. Misaligned or out of bounds regions, and writes which
+//! don't fit the region, are rejected when the program is compiled:
+//!
+//! ```ignore
+//! // Last 64 KiB of a 2 MiB flash
+//! let mut region = unsafe { FlashRegion::<0x1f0000, 0x10000>::new(true) };
+//! region.erase()?;
+//! region.program::<0x100, 256>(&[0x55; 256])?;
+//! // Doesn't compile: not page aligned
+//! // region.program::<0x80, 256>(&[0x55; 256])?;
+//! ```
+
+use crate::error::FlashError;
+use crate::flash::{self, PAGE_SIZE, SECTOR_SIZE};
+use crate::xip::XIP_BASE;
+
+/// `LEN` bytes of flash at offset `OFFSET`
+///
+/// `OFFSET` and `LEN` must be multiples of 4096, and the region must fit
+/// into the 16 MiB flash address space.
+pub struct FlashRegion<const OFFSET: u32, const LEN: u32> {
+    use_boot2: bool,
+}
+
+impl<const OFFSET: u32, const LEN: u32> FlashRegion<OFFSET, LEN> {
+    const VALID: () = assert!(
+        OFFSET & (SECTOR_SIZE - 1) == 0
+            && LEN & (SECTOR_SIZE - 1) == 0
+            && LEN > 0
+            && OFFSET as u64 + LEN as u64 <= 0x1000000,
+        "region must be sector aligned and fit into 16MiB"
+    );
+
+    /// Offset of the region, relative to the beginning of the flash area
+    pub const OFFSET: u32 = OFFSET;
+    /// Length of the region in bytes
+    pub const LEN: u32 = LEN;
+
+    /// Use the region
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// There must only be one instance for the region at a time, and the
+    /// region must not be used for anything else, in particular not
+    /// contain code or data of the running program.
+    ///
+    /// Each operation disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash during
+    /// erase and program operations.
+    pub unsafe fn new(use_boot2: bool) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::VALID;
+        FlashRegion { use_boot2 }
+    }
+
+    /// The contents of the region, read through XIP
+    pub fn as_slice(&self) -> &[u8] {
+        // Safety: the region is inside the XIP window, which is always
+        // readable, and can't be modified while borrowed
+        unsafe { core::slice::from_raw_parts((XIP_BASE + OFFSET) as *const u8, LEN as usize) }
+    }
+
+    /// Erase the whole region
+    pub fn erase(&mut self) -> Result<(), FlashError> {
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe { flash::flash_range_erase_checked(OFFSET, LEN, use_boot2) })
+    }
+
+    /// Erase sector `SECTOR` of the region
+    pub fn erase_sector<const SECTOR: u32>(&mut self) -> Result<(), FlashError> {
+        #[allow(clippy::let_unit_value)]
+        let _ = SectorCheck::<LEN, SECTOR>::FITS;
+        let (addr, use_boot2) = (OFFSET + SECTOR * SECTOR_SIZE, self.use_boot2);
+        crate::cs::free(|| unsafe {
+            flash::flash_range_erase_checked(addr, SECTOR_SIZE, use_boot2)
+        })
+    }
+
+    /// Program `data` at offset `AT` within the region
+    ///
+    /// `AT` and `N` must be multiples of 256, and the range must fit into
+    /// the region. The range must have been erased before.
+    pub fn program<const AT: u32, const N: usize>(
+        &mut self,
+        data: &[u8; N],
+    ) -> Result<(), FlashError> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Check::<LEN, AT, N, PAGE_SIZE>::FITS;
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe {
+            flash::flash_range_program_checked(OFFSET + AT, data, use_boot2)
+        })
+    }
+
+    /// Erase and program `data` at offset `AT` within the region
+    ///
+    /// `AT` and `N` must be multiples of 4096, and the range must fit into
+    /// the region.
+    pub fn erase_and_program<const AT: u32, const N: usize>(
+        &mut self,
+        data: &[u8; N],
+    ) -> Result<(), FlashError> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Check::<LEN, AT, N, SECTOR_SIZE>::FITS;
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| unsafe {
+            flash::flash_range_erase_and_program_checked(OFFSET + AT, data, use_boot2)
+        })
+    }
+}
+
+/// Compile-time check that `N` bytes at `AT` are aligned to `ALIGN`, a
+/// power of two, and fit into a region of `REGION` bytes
+struct Check<const REGION: u32, const AT: u32, const N: usize, const ALIGN: u32>;
+
+impl<const REGION: u32, const AT: u32, const N: usize, const ALIGN: u32>
+    Check<REGION, AT, N, ALIGN>
+{
+    const FITS: () = assert!(
+        AT & (ALIGN - 1) == 0
+            && N & (ALIGN as usize - 1) == 0
+            && AT as u64 + N as u64 <= REGION as u64,
+        "range must be aligned and fit into the region"
+    );
+}
+
+/// Compile-time check that sector `SECTOR` is part of a region of `REGION` bytes
+struct SectorCheck<const REGION: u32, const SECTOR: u32>;
+
+impl<const REGION: u32, const SECTOR: u32> SectorCheck<REGION, SECTOR> {
+    const FITS: () = assert!(
+        SECTOR < REGION / SECTOR_SIZE,
+        "sector must be part of the region"
+    );
+}
@@ -0,0 +1,334 @@
+//! Retry policy for write paths which can detect failures
+//!
+//! [`RetryPolicy`] is taken by the verified write functions, like
+//! `flash::flash_range_erase_and_program_with_retry`. The stores of this
+//! crate use it through [`Retrying`], which wraps their flash, verifies
+//! each erase and write and retries failed ones:
+//!
+//! ```ignore
+//! let flash = Retrying::new(unsafe { InternalFlash::new(true) }, RetryPolicy::default());
+//! let mut store = kv::Store::with_flash(flash, 0x1f0000, 4)?;
+//! ```
+
+use crate::error::FlashError;
+use embedded_storage::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+/// Size of the buffer used to rewrite a sector, the largest supported erase size
+const SECTOR_SIZE: usize = 4096;
+
+/// How often, and how, to retry a failed write
+///
+/// Used by the write paths which can detect failures, like
+/// `flash::flash_range_erase_and_program_with_retry` and [`Retrying`].
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Erase the affected sectors again before retrying
+    ///
+    /// Only has an effect on write paths which don't always erase, like
+    /// [`Retrying`].
+    pub reerase: bool,
+    /// Called before each retry with the number of the upcoming attempt,
+    /// starting at 1 for the first retry
+    ///
+    /// Can be used to wait for a supply voltage to recover, to feed a
+    /// watchdog, or to log the failure. It is called with flash accessible.
+    pub backoff: Option<fn(attempt: u32)>,
+}
+
+impl RetryPolicy {
+    /// Try only once
+    pub const fn once() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            reerase: false,
+            backoff: None,
+        }
+    }
+
+    /// Run `op` until it succeeds or the attempts are exhausted
+    ///
+    /// `op` is called with the number of the attempt, starting at 0, and
+    /// whether it should erase before writing. Returns the error of the
+    /// last attempt if all of them failed.
+    pub fn run<E>(&self, mut op: impl FnMut(u32, bool) -> Result<(), E>) -> Result<(), E> {
+        let mut attempt = 0;
+        loop {
+            let result = op(attempt, attempt > 0 && self.reerase);
+            attempt += 1;
+            match result {
+                Err(_) if attempt < self.max_attempts => {
+                    warn!("flash write failed, retrying (attempt {})", attempt);
+                    if let Some(backoff) = self.backoff {
+                        backoff(attempt);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Up to 3 attempts, erasing again before each retry, without backoff
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            reerase: true,
+            backoff: None,
+        }
+    }
+}
+
+/// Flash wrapper verifying each erase and write, and retrying according to a policy
+///
+/// Erased ranges are read back and must be 0xff. Written ranges are read
+/// back, and each bit cleared in the data written must be cleared in
+/// flash. Bits already cleared before aren't an error, so multiple writes
+/// to a page work as before. Failures are reported as
+/// [`FlashError::VerifyFailed`].
+///
+/// With [`RetryPolicy::reerase`], a retried write reads the affected
+/// sectors, erases them and programs them with their previous contents
+/// combined with the data written. This needs an erase size of at most
+/// 4096 bytes; with larger sectors, writes are retried without erasing.
+pub struct Retrying<F> {
+    flash: F,
+    policy: RetryPolicy,
+}
+
+impl<F> Retrying<F> {
+    /// Wrap `flash`, retrying failed operations according to `policy`
+    pub fn new(flash: F, policy: RetryPolicy) -> Self {
+        Retrying { flash, policy }
+    }
+
+    /// The wrapped flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F: NorFlash<Error = FlashError>> Retrying<F> {
+    /// Check `len` bytes at `offset`, failing at the first one for which `bad` is true
+    ///
+    /// `bad` is called with the position relative to `offset` and the byte read.
+    fn verify(
+        &mut self,
+        offset: u32,
+        len: usize,
+        bad: impl Fn(usize, u8) -> bool,
+    ) -> Result<(), FlashError> {
+        let mut buf = [0u8; 64];
+        for start in (0..len).step_by(buf.len()) {
+            let chunk = &mut buf[..(len - start).min(64)];
+            self.flash.read(offset + start as u32, chunk)?;
+            if let Some(pos) = chunk
+                .iter()
+                .enumerate()
+                .position(|(i, &b)| bad(start + i, b))
+            {
+                return Err(FlashError::VerifyFailed {
+                    offset: offset + (start + pos) as u32,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase and reprogram the sectors overlapping `bytes` at `offset`,
+    /// keeping their contents outside of the range
+    fn rewrite(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let erase_size = F::ERASE_SIZE as u32;
+        let end = offset + bytes.len() as u32;
+        let mut buf = [0u8; SECTOR_SIZE];
+        let buf = &mut buf[..F::ERASE_SIZE];
+        let mut sector = offset - offset % erase_size;
+        while sector < end {
+            self.flash.read(sector, buf)?;
+            let from = sector.max(offset);
+            let to = (sector + erase_size).min(end);
+            for addr in from..to {
+                buf[(addr - sector) as usize] = bytes[(addr - offset) as usize];
+            }
+            self.flash.erase(sector, sector + erase_size)?;
+            self.flash.write(sector, buf)?;
+            sector += erase_size;
+        }
+        Ok(())
+    }
+}
+
+impl<F: ErrorType<Error = FlashError>> ErrorType for Retrying<F> {
+    type Error = FlashError;
+}
+
+impl<F: ReadNorFlash<Error = FlashError>> ReadNorFlash for Retrying<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.flash.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash<Error = FlashError>> NorFlash for Retrying<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        let policy = self.policy;
+        policy.run(|_attempt, _erase| {
+            self.flash.erase(from, to)?;
+            self.verify(from, to.saturating_sub(from) as usize, |_, b| b != 0xff)
+        })
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let policy = self.policy;
+        policy.run(|_attempt, erase| {
+            if erase && F::ERASE_SIZE <= SECTOR_SIZE {
+                self.rewrite(offset, bytes)?;
+            } else {
+                self.flash.write(offset, bytes)?;
+            }
+            // Bits still set which should have been cleared
+            self.verify(offset, bytes.len(), |i, b| b & !bytes[i] != 0)
+        })
+    }
+}
+
+impl<F: MultiwriteNorFlash<Error = FlashError>> MultiwriteNorFlash for Retrying<F> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    /// Silently fails the next `failures` operations
+    ///
+    /// Failed erases and writes do nothing. If `glitch` is set, the next
+    /// write clears a wrong bit in its first byte and leaves the second one
+    /// erased instead.
+    struct Flaky {
+        flash: MockFlash,
+        failures: u32,
+        glitch: bool,
+    }
+
+    impl Flaky {
+        fn fail(&mut self) -> bool {
+            let fail = self.failures > 0;
+            self.failures = self.failures.saturating_sub(1);
+            fail
+        }
+    }
+
+    impl ErrorType for Flaky {
+        type Error = FlashError;
+    }
+
+    impl ReadNorFlash for Flaky {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+            ReadNorFlash::read(&mut self.flash, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            ReadNorFlash::capacity(&self.flash)
+        }
+    }
+
+    impl NorFlash for Flaky {
+        const WRITE_SIZE: usize = 256;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+            if self.fail() {
+                return Ok(());
+            }
+            NorFlash::erase(&mut self.flash, from, to)
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+            if self.fail() {
+                return Ok(());
+            }
+            if core::mem::take(&mut self.glitch) {
+                let mut glitched = [0u8; 256];
+                let glitched = &mut glitched[..bytes.len()];
+                glitched.copy_from_slice(bytes);
+                glitched[0] &= 0x7f;
+                glitched[1] = 0xff;
+                return NorFlash::write(&mut self.flash, offset, glitched);
+            }
+            NorFlash::write(&mut self.flash, offset, bytes)
+        }
+    }
+
+    fn flaky(failures: u32, reerase: bool) -> Retrying<Flaky> {
+        let mut flash = MockFlash::new(0x2000);
+        flash.program(0x1100, &[0x42; 256]).unwrap();
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            reerase,
+            backoff: None,
+        };
+        Retrying::new(
+            Flaky {
+                flash,
+                failures,
+                glitch: false,
+            },
+            policy,
+        )
+    }
+
+    #[test]
+    fn retries_erase() {
+        let mut flash = flaky(1, false);
+        flash.erase(0x1000, 0x2000).unwrap();
+        assert!(flash.flash.flash.as_bytes()[0x1000..]
+            .iter()
+            .all(|&b| b == 0xff));
+        let mut flash = flaky(2, false);
+        assert_eq!(
+            flash.erase(0x1000, 0x2000),
+            Err(FlashError::VerifyFailed { offset: 0x1100 })
+        );
+    }
+
+    #[test]
+    fn reerases_before_retrying_write() {
+        let mut flash = flaky(2, false);
+        assert_eq!(
+            flash.write(0x1000, &[0x55; 256]),
+            Err(FlashError::VerifyFailed { offset: 0x1000 })
+        );
+        let mut flash = flaky(1, false);
+        flash.write(0x1000, &[0x55; 256]).unwrap();
+        assert_eq!(flash.into_inner().flash.erase_count(0x1000), 0);
+        let mut flash = flaky(1, true);
+        flash.write(0x1000, &[0x55; 256]).unwrap();
+        let bytes = flash.into_inner().flash;
+        assert!(bytes.as_bytes()[0x1000..0x1100].iter().all(|&b| b == 0x55));
+        // The rest of the sector is kept
+        assert!(bytes.as_bytes()[0x1100..0x1200].iter().all(|&b| b == 0x42));
+        assert_eq!(bytes.erase_count(0x1000), 1);
+    }
+
+    #[test]
+    fn rewrites_wrongly_cleared_bits() {
+        let mut flash = flaky(0, true);
+        flash.flash.glitch = true;
+        flash.write(0x1000, &[0xf0; 256]).unwrap();
+        let bytes = flash.into_inner().flash;
+        assert!(bytes.as_bytes()[0x1000..0x1100].iter().all(|&b| b == 0xf0));
+        assert_eq!(bytes.erase_count(0x1000), 1);
+    }
+}
@@ -0,0 +1,185 @@
+//! Boot ROM function lookup
+//!
+//! The RP2040 and RP2350 boot ROMs provide the same low-level flash
+//! functions, but differ in how they are located. On the RP2040,
+//! `rp2040-hal` provides the lookup, or with the `no-hal` feature, the
+//! function table is searched directly, so `rp2040-hal` isn't needed. On
+//! the RP2350, with the `rp235x` feature, the function table is searched
+//! using the lookup function of the RP2350 boot ROM.
+
+type RomFn = unsafe extern "C" fn();
+
+#[cfg(feature = "rp2040")]
+mod imp {
+    use super::RomFn;
+    use rp2040_hal::rom_data;
+
+    pub fn memcpy44() -> unsafe extern "C" fn(*mut u32, *const u32, u32) -> *mut u8 {
+        rom_data::memcpy44::ptr()
+    }
+
+    pub fn connect_internal_flash() -> RomFn {
+        rom_data::connect_internal_flash::ptr()
+    }
+
+    pub fn flash_exit_xip() -> RomFn {
+        rom_data::flash_exit_xip::ptr()
+    }
+
+    pub fn flash_range_erase() -> unsafe extern "C" fn(u32, usize, u32, u8) {
+        rom_data::flash_range_erase::ptr()
+    }
+
+    pub fn flash_range_program() -> unsafe extern "C" fn(u32, *const u8, usize) {
+        rom_data::flash_range_program::ptr()
+    }
+
+    pub fn flash_flush_cache() -> RomFn {
+        rom_data::flash_flush_cache::ptr()
+    }
+
+    pub fn flash_enter_cmd_xip() -> RomFn {
+        rom_data::flash_enter_cmd_xip::ptr()
+    }
+
+    pub fn reset_to_usb_boot() -> unsafe extern "C" fn(u32, u32) {
+        rom_data::reset_to_usb_boot::ptr()
+    }
+}
+
+#[cfg(feature = "no-hal")]
+mod imp {
+    use super::RomFn;
+
+    /// Magic value and version 1 of the boot ROM
+    const ROM_MAGIC: *const [u8; 3] = 0x0000_0010 as _;
+    /// Pointer to the function table, as halfword
+    const FUNC_TABLE: *const u16 = 0x0000_0014 as _;
+    /// Pointer to the table lookup function, as halfword
+    const ROM_TABLE_LOOKUP: *const u16 = 0x0000_0018 as _;
+
+    type RomTableLookupFn = unsafe extern "C" fn(table: *const u16, code: u32) -> usize;
+
+    fn lookup(tag: [u8; 2]) -> usize {
+        // Safety: the boot ROM is always mapped, and the table pointers
+        // are part of its documented interface
+        unsafe {
+            assert!(ROM_MAGIC.read_volatile() == *b"Mu\x01", "unknown boot ROM");
+            let lookup_fn = ROM_TABLE_LOOKUP.read_volatile() as usize;
+            let lookup_fn: RomTableLookupFn = core::mem::transmute(lookup_fn);
+            let table = FUNC_TABLE.read_volatile() as usize as *const u16;
+            let addr = lookup_fn(table, u16::from_le_bytes(tag) as u32);
+            assert!(addr != 0, "ROM function not found");
+            addr
+        }
+    }
+
+    pub fn memcpy44() -> unsafe extern "C" fn(*mut u32, *const u32, u32) -> *mut u8 {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"C4")) }
+    }
+
+    pub fn connect_internal_flash() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"IF")) }
+    }
+
+    pub fn flash_exit_xip() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"EX")) }
+    }
+
+    pub fn flash_range_erase() -> unsafe extern "C" fn(u32, usize, u32, u8) {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"RE")) }
+    }
+
+    pub fn flash_range_program() -> unsafe extern "C" fn(u32, *const u8, usize) {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"RP")) }
+    }
+
+    pub fn flash_flush_cache() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"FC")) }
+    }
+
+    pub fn flash_enter_cmd_xip() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"CX")) }
+    }
+
+    pub fn reset_to_usb_boot() -> unsafe extern "C" fn(u32, u32) {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"UB")) }
+    }
+}
+
+#[cfg(feature = "rp235x")]
+mod imp {
+    use super::RomFn;
+
+    /// Pointer to the table lookup function, as halfword, for ROM versions >= 2
+    const ROM_TABLE_LOOKUP_A2: *const u16 = 0x0000_0016 as _;
+    /// Pointer to the table lookup function, for ROM version 1
+    const ROM_TABLE_LOOKUP_A1: *const u32 = 0x0000_0018 as _;
+    /// ROM version number
+    const ROM_VERSION: *const u8 = 0x0000_0013 as _;
+    /// Look up the entry point for secure Arm code
+    const RT_FLAG_FUNC_ARM_SEC: u32 = 0x0004;
+
+    type RomTableLookupFn = unsafe extern "C" fn(code: u32, mask: u32) -> usize;
+
+    fn lookup(tag: [u8; 2]) -> usize {
+        // Safety: the boot ROM is always mapped, and the lookup function
+        // pointer is part of its documented interface
+        unsafe {
+            let lookup_fn = if ROM_VERSION.read_volatile() == 1 {
+                ROM_TABLE_LOOKUP_A1.read_volatile() as usize
+            } else {
+                ROM_TABLE_LOOKUP_A2.read_volatile() as usize
+            };
+            let lookup_fn: RomTableLookupFn = core::mem::transmute(lookup_fn);
+            let addr = lookup_fn(u16::from_le_bytes(tag) as u32, RT_FLAG_FUNC_ARM_SEC);
+            assert!(addr != 0, "ROM function not found");
+            addr
+        }
+    }
+
+    pub fn connect_internal_flash() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"IF")) }
+    }
+
+    pub fn flash_exit_xip() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"EX")) }
+    }
+
+    pub fn flash_range_erase() -> unsafe extern "C" fn(u32, usize, u32, u8) {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"RE")) }
+    }
+
+    pub fn flash_range_program() -> unsafe extern "C" fn(u32, *const u8, usize) {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"RP")) }
+    }
+
+    pub fn flash_flush_cache() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"FC")) }
+    }
+
+    pub fn flash_enter_cmd_xip() -> RomFn {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"CX")) }
+    }
+
+    pub fn reboot() -> unsafe extern "C" fn(u32, u32, u32, u32) -> i32 {
+        // Safety: the ROM table entry has this signature
+        unsafe { core::mem::transmute(lookup(*b"RB")) }
+    }
+}
+
+pub(crate) use imp::*;
@@ -0,0 +1,103 @@
+//! Winbond security registers
+//!
+//! Winbond W25Q chips have three 256 byte security registers outside the
+//! main array, which are useful for serial numbers and factory data. They
+//! can be erased and programmed like normal flash, and individually locked
+//! forever using the LB1-LB3 bits of status register 2.
+//!
+//! All functions check the JEDEC ID and return [`FlashError::Unsupported`]
+//! for chips not made by Winbond.
+
+use crate::chip::Operation;
+use crate::error::FlashError;
+use crate::flash;
+
+/// Size of a security register
+pub const SECURITY_REGISTER_SIZE: usize = 256;
+
+/// Number of security registers
+pub const SECURITY_REGISTERS: u8 = 3;
+
+/// Address of byte `offset` of security register `n`
+unsafe fn address(n: u8, offset: usize, len: usize, use_boot2: bool) -> Result<u32, FlashError> {
+    if flash::flash_jedec_id(use_boot2) >> 16 != 0xef {
+        return Err(FlashError::Unsupported);
+    }
+    if !(1..=SECURITY_REGISTERS).contains(&n) || offset + len > SECURITY_REGISTER_SIZE {
+        return Err(FlashError::OutOfBounds);
+    }
+    Ok(((n as u32) << 12) | offset as u32)
+}
+
+fn cmd(opcode: u8, addr: u32) -> [u8; 4] {
+    [opcode, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8]
+}
+
+/// Read `out.len()` bytes from security register `n`, 1 to 3, starting at `offset`
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn security_register_read(
+    n: u8,
+    offset: usize,
+    out: &mut [u8],
+    use_boot2: bool,
+) -> Result<(), FlashError> {
+    let addr = address(n, offset, out.len(), use_boot2)?;
+    // 48 - read security register, 8 dummy cycles
+    flash::read_cmd(&cmd(0x48, addr), 1, out, use_boot2);
+    Ok(())
+}
+
+/// Program `data` into security register `n`, 1 to 3, starting at `offset`
+///
+/// Like normal flash, programming can only clear bits, so the register
+/// usually needs to be erased first.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn security_register_program(
+    n: u8,
+    offset: usize,
+    data: &[u8],
+    use_boot2: bool,
+) -> Result<(), FlashError> {
+    let addr = address(n, offset, data.len(), use_boot2)?;
+    trace!(
+        "program security register {} at {} len {}",
+        n,
+        offset,
+        data.len()
+    );
+    let mut tx = [0u8; 4 + SECURITY_REGISTER_SIZE];
+    // 42 - program security register
+    tx[..4].copy_from_slice(&cmd(0x42, addr));
+    tx[4..4 + data.len()].copy_from_slice(data);
+    flash::write_enabled_cmd(&tx[..4 + data.len()], Operation::PageProgram, use_boot2)
+}
+
+/// Erase security register `n`, 1 to 3
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn security_register_erase(n: u8, use_boot2: bool) -> Result<(), FlashError> {
+    let addr = address(n, 0, 0, use_boot2)?;
+    trace!("erase security register {}", n);
+    // 44 - erase security register
+    flash::write_enabled_cmd(&cmd(0x44, addr), Operation::SectorErase, use_boot2)
+}
@@ -0,0 +1,298 @@
+//! Serial Flash Discoverable Parameters (JESD216)
+//!
+//! Most flash chips describe their capabilities in SFDP tables, which
+//! can be read with [`flash::read_sfdp`](crate::flash::read_sfdp). This
+//! parses the JEDEC Basic Flash Parameter table, so chips which are not in
+//! the [`chip`](crate::chip) database can be used.
+
+#[cfg(target_os = "none")]
+use crate::flash;
+use core::time::Duration;
+
+/// Number of Basic Flash Parameter table dwords used by the parser
+#[cfg(target_os = "none")]
+const BFPT_DWORDS: usize = 16;
+
+/// Parameter ID of the Basic Flash Parameter table
+#[cfg(target_os = "none")]
+const BFPT_ID: u16 = 0xff00;
+
+/// SFDP header, found at offset 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SfdpHeader {
+    pub minor: u8,
+    pub major: u8,
+    /// Number of parameter headers
+    pub parameter_headers: u8,
+}
+
+impl SfdpHeader {
+    /// Parse the 8 byte header, returning `None` if the signature doesn't
+    /// match or the number of parameter headers is invalid
+    pub fn parse(bytes: &[u8; 8]) -> Option<Self> {
+        if &bytes[0..4] != b"SFDP" {
+            return None;
+        }
+        Some(SfdpHeader {
+            minor: bytes[4],
+            major: bytes[5],
+            parameter_headers: bytes[6].checked_add(1)?,
+        })
+    }
+}
+
+/// Header describing a parameter table, following the SFDP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterHeader {
+    /// Parameter ID, 0xff00 for the Basic Flash Parameter table
+    pub id: u16,
+    pub minor: u8,
+    pub major: u8,
+    /// Length of the table in dwords
+    pub length: u8,
+    /// SFDP offset of the table
+    pub pointer: u32,
+}
+
+impl ParameterHeader {
+    /// Parse an 8 byte parameter header
+    pub fn parse(bytes: &[u8; 8]) -> Self {
+        ParameterHeader {
+            id: u16::from_le_bytes([bytes[0], bytes[7]]),
+            minor: bytes[1],
+            major: bytes[2],
+            length: bytes[3],
+            pointer: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], 0]),
+        }
+    }
+}
+
+/// An erase command and the size it erases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseType {
+    pub size: u32,
+    pub opcode: u8,
+}
+
+/// A fast read command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FastRead {
+    pub opcode: u8,
+    /// Number of dummy clocks, including mode clocks
+    pub dummy_clocks: u8,
+}
+
+/// Number of address bytes supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressBytes {
+    Three,
+    ThreeOrFour,
+    Four,
+}
+
+/// Contents of the JEDEC Basic Flash Parameter table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicFlashParameters {
+    /// Capacity in bytes
+    pub size: u64,
+    pub address_bytes: AddressBytes,
+    /// Opcode of the 4 KiB erase command, if supported
+    pub erase_4k: Option<u8>,
+    /// Supported erase commands, ordered as in the table
+    pub erase_types: [Option<EraseType>; 4],
+    /// Page size, if given (JESD216A and later)
+    pub page_size: Option<u32>,
+    /// Maximum time of each erase type, if given (JESD216A and later)
+    pub erase_times: [Option<Duration>; 4],
+    /// Maximum page program time, if given (JESD216A and later)
+    pub page_program_time: Option<Duration>,
+    pub fast_read_1_1_2: Option<FastRead>,
+    pub fast_read_1_2_2: Option<FastRead>,
+    pub fast_read_1_1_4: Option<FastRead>,
+    pub fast_read_1_4_4: Option<FastRead>,
+}
+
+impl BasicFlashParameters {
+    /// Parse the table from its dwords
+    ///
+    /// Returns `None` if the table is shorter than the 9 dwords defined by
+    /// the original JESD216, or the density or an erase size is invalid.
+    pub fn parse(dwords: &[u32]) -> Option<Self> {
+        if dwords.len() < 9 {
+            return None;
+        }
+        let dw = |n: usize| dwords[n - 1];
+
+        let size = if dw(2) & (1 << 31) == 0 {
+            (dw(2) as u64 + 1) / 8
+        } else {
+            1u64.checked_shl(dw(2) & 0x7fff_ffff)? / 8
+        };
+        let address_bytes = match (dw(1) >> 17) & 0b11 {
+            0b00 => AddressBytes::Three,
+            0b01 => AddressBytes::ThreeOrFour,
+            0b10 => AddressBytes::Four,
+            _ => return None,
+        };
+        let fast_read = |supported: bool, bits: u32| {
+            supported.then_some(FastRead {
+                opcode: (bits >> 8) as u8,
+                dummy_clocks: (bits & 0x1f) as u8 + ((bits >> 5) & 0x7) as u8,
+            })
+        };
+        // `Some(None)` for unused entries, `None` for invalid sizes
+        let erase_type = |bits: u32| {
+            let size = bits & 0xff;
+            if size == 0 {
+                return Some(None);
+            }
+            Some(Some(EraseType {
+                size: 1u32.checked_shl(size)?,
+                opcode: (bits >> 8) as u8,
+            }))
+        };
+        let erase_types = [
+            erase_type(dw(8))?,
+            erase_type(dw(8) >> 16)?,
+            erase_type(dw(9))?,
+            erase_type(dw(9) >> 16)?,
+        ];
+        // Typical times, and a multiplier from typical to maximum time
+        let max_time =
+            |multiplier: u32, count: u32, unit: Duration| unit * (count + 1) * 2 * (multiplier + 1);
+        let mut erase_times = [None; 4];
+        let mut page_program_time = None;
+        if dwords.len() >= 11 {
+            const ERASE_UNITS: [u64; 4] = [1, 16, 128, 1000];
+            for (i, time) in erase_times.iter_mut().enumerate() {
+                let bits = dw(10) >> (4 + 7 * i);
+                let unit = Duration::from_millis(ERASE_UNITS[(bits >> 5) as usize & 0b11]);
+                *time = erase_types[i].map(|_| max_time(dw(10) & 0b11, bits & 0x1f, unit));
+            }
+            let unit = Duration::from_micros(if dw(11) & (1 << 13) == 0 { 8 } else { 64 });
+            page_program_time = Some(max_time(dw(11) & 0xf, (dw(11) >> 8) & 0x1f, unit));
+        }
+        Some(BasicFlashParameters {
+            size,
+            address_bytes,
+            erase_4k: (dw(1) & 0b11 == 0b01).then_some((dw(1) >> 8) as u8),
+            erase_types,
+            page_size: (dwords.len() >= 11).then(|| 1 << ((dw(11) >> 4) & 0xf)),
+            erase_times,
+            page_program_time,
+            fast_read_1_1_2: fast_read(dw(1) & (1 << 16) != 0, dw(4)),
+            fast_read_1_2_2: fast_read(dw(1) & (1 << 20) != 0, dw(4) >> 16),
+            fast_read_1_1_4: fast_read(dw(1) & (1 << 22) != 0, dw(3) >> 16),
+            fast_read_1_4_4: fast_read(dw(1) & (1 << 21) != 0, dw(3)),
+        })
+    }
+}
+
+/// Read and parse the Basic Flash Parameter table
+///
+/// Returns `None` if the chip doesn't support SFDP, or the table is invalid.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+#[cfg(target_os = "none")]
+pub unsafe fn read_basic_parameters(use_boot2: bool) -> Option<BasicFlashParameters> {
+    let mut bytes = [0u8; 16];
+    flash::read_sfdp(0, &mut bytes, use_boot2);
+    let Some(header) = SfdpHeader::parse(bytes[0..8].try_into().unwrap()) else {
+        debug!("no SFDP signature");
+        return None;
+    };
+    // The first parameter header always describes the Basic Flash Parameter table
+    let parameters = ParameterHeader::parse(bytes[8..16].try_into().unwrap());
+    if parameters.id != BFPT_ID {
+        warn!("unexpected SFDP parameter ID {:#x}", parameters.id);
+        return None;
+    }
+    trace!(
+        "SFDP {}.{}, basic parameters {}.{}",
+        header.major,
+        header.minor,
+        parameters.major,
+        parameters.minor
+    );
+    let len = (parameters.length as usize).min(BFPT_DWORDS);
+    let mut table = [0u8; BFPT_DWORDS * 4];
+    flash::read_sfdp(parameters.pointer, &mut table[..len * 4], use_boot2);
+    let mut dwords = [0u32; BFPT_DWORDS];
+    for (dword, bytes) in dwords.iter_mut().zip(table.chunks_exact(4)) {
+        *dword = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    BasicFlashParameters::parse(&dwords[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_header_count() {
+        assert_eq!(SfdpHeader::parse(b"SFDP\x06\x01\xff\xff"), None);
+        let header = SfdpHeader::parse(b"SFDP\x06\x01\x01\xff").unwrap();
+        assert_eq!(header.parameter_headers, 2);
+    }
+
+    #[test]
+    fn parses_erase_types() {
+        // 16 MiB, 4 KiB erase 0x20, 64 KiB erase 0xd8
+        let mut dwords = [0u32; 9];
+        dwords[0] = 0x2001;
+        dwords[1] = 0x07ff_ffff;
+        dwords[7] = 0xd810_200c;
+        let params = BasicFlashParameters::parse(&dwords).unwrap();
+        assert_eq!(params.size, 0x100_0000);
+        assert_eq!(params.erase_4k, Some(0x20));
+        assert_eq!(
+            params.erase_types[..2],
+            [
+                Some(EraseType {
+                    size: 0x1000,
+                    opcode: 0x20
+                }),
+                Some(EraseType {
+                    size: 0x10000,
+                    opcode: 0xd8
+                })
+            ]
+        );
+        assert_eq!(params.erase_types[2], None);
+        assert_eq!(params.erase_times, [None; 4]);
+        // Erase size of 2^32 bytes
+        dwords[8] = 0x0020;
+        assert_eq!(BasicFlashParameters::parse(&dwords), None);
+    }
+
+    #[test]
+    fn parses_times() {
+        let mut dwords = [0u32; 11];
+        dwords[0] = 0x2001;
+        dwords[1] = 0x07ff_ffff;
+        dwords[7] = 0xd810_200c;
+        // Multiplier 2 (max = 6 * typical), 4 KiB typically 2 * 16 ms,
+        // 64 KiB typically 2 * 128 ms
+        dwords[9] = 0x0002 | (1 << 4) | (0b01 << 9) | (1 << 11) | (0b10 << 16);
+        // Multiplier 1 (max = 4 * typical), typically 5 * 64 us, 256 byte pages
+        dwords[10] = 0x0001 | (8 << 4) | (4 << 8) | (1 << 13);
+        let params = BasicFlashParameters::parse(&dwords).unwrap();
+        assert_eq!(params.page_size, Some(256));
+        assert_eq!(
+            params.erase_times,
+            [
+                Some(Duration::from_millis(192)),
+                Some(Duration::from_millis(1536)),
+                None,
+                None
+            ]
+        );
+        assert_eq!(params.page_program_time, Some(Duration::from_micros(1280)));
+    }
+}
@@ -0,0 +1,420 @@
+//! mcumgr SMP image management handler
+//!
+//! Implements the image management commands of the Simple Management
+//! Protocol used by mcumgr: image upload, and reading, testing and
+//! confirming the image state. Uploaded images are written to the
+//! secondary slot, using the [`mcuboot`](crate::mcuboot) image format,
+//! so standard tools like the `mcumgr` CLI can update the device.
+//!
+//! The handler is transport-agnostic: it processes one complete SMP
+//! frame (header and CBOR payload) at a time and produces the response
+//! frame. Framing for the transport, like the base64 console framing of
+//! the serial transport, is left to the application.
+
+use crate::error::FlashError;
+use crate::mcuboot::{self, Flag, ImageHeader, Trailer};
+use crate::nor_flash::InternalFlash;
+use crate::partition::Partition;
+use core::fmt::Write;
+use embedded_storage::nor_flash::MultiwriteNorFlash;
+use embedded_storage::Storage;
+
+mod cbor;
+use cbor::{Decoder, Encoder, Value};
+
+const HEADER_LEN: usize = 8;
+
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 2;
+
+const GROUP_IMAGE: u16 = 1;
+const CMD_STATE: u8 = 0;
+const CMD_UPLOAD: u8 = 1;
+
+/// mcumgr result codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rc {
+    Unknown = 1,
+    Inval = 3,
+    BadState = 6,
+    MsgSize = 7,
+    NotSup = 8,
+}
+
+impl From<cbor::Error> for Rc {
+    fn from(_: cbor::Error) -> Self {
+        Rc::MsgSize
+    }
+}
+
+/// Frame-level errors, which prevent sending a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SmpError {
+    /// The request is shorter than its header claims
+    Truncated,
+    /// The response buffer is too small for even an error response
+    ResponseTooLarge,
+}
+
+/// Progress of an ongoing upload
+struct Upload {
+    len: u32,
+    off: u32,
+    sha: Option<[u8; 32]>,
+}
+
+/// State of an image, as reported to mcumgr
+struct ImageState {
+    slot: u8,
+    version: mcuboot::ImageVersion,
+    hash: Option<[u8; 32]>,
+    pending: bool,
+    confirmed: bool,
+    active: bool,
+    permanent: bool,
+}
+
+/// Handler for the SMP image management group
+pub struct ImageManager<'a, F = InternalFlash> {
+    primary: &'a mut Partition<F>,
+    secondary: &'a mut Partition<F>,
+    upload: Option<Upload>,
+}
+
+impl<'a, F: MultiwriteNorFlash<Error = FlashError>> ImageManager<'a, F> {
+    /// Create a handler for the running image in `primary`, writing uploads to `secondary`
+    pub fn new(primary: &'a mut Partition<F>, secondary: &'a mut Partition<F>) -> Self {
+        ImageManager {
+            primary,
+            secondary,
+            upload: None,
+        }
+    }
+
+    /// Process the SMP frame `request`, writing the response frame to `response`
+    ///
+    /// Returns the length of the response. Unsupported groups and commands
+    /// are answered with an error code, as expected by mcumgr.
+    ///
+    /// The first chunk of an upload erases the whole secondary slot, which
+    /// may take several seconds. A retransmitted first chunk, with the same
+    /// length and image hash as the ongoing upload, doesn't erase again.
+    pub fn process(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, SmpError> {
+        if request.len() < HEADER_LEN {
+            return Err(SmpError::Truncated);
+        }
+        if response.len() < HEADER_LEN {
+            return Err(SmpError::ResponseTooLarge);
+        }
+        let op = request[0] & 0x07;
+        let len = u16::from_be_bytes([request[2], request[3]]) as usize;
+        let group = u16::from_be_bytes([request[4], request[5]]);
+        let id = request[7];
+        let payload = request
+            .get(HEADER_LEN..HEADER_LEN + len)
+            .ok_or(SmpError::Truncated)?;
+
+        let (header, body) = response.split_at_mut(HEADER_LEN);
+        let mut enc = Encoder::new(body);
+        let result = match (group, id, op) {
+            (GROUP_IMAGE, CMD_STATE, OP_READ) => self.state_read(&mut enc),
+            (GROUP_IMAGE, CMD_STATE, OP_WRITE) => self.state_write(payload, &mut enc),
+            (GROUP_IMAGE, CMD_UPLOAD, OP_WRITE) => self.upload(payload, &mut enc),
+            _ => Err(Rc::NotSup),
+        };
+        if let Err(rc) = result {
+            debug!("SMP request failed with rc {}", rc as u8);
+            enc = Encoder::new(body);
+            enc.map(1)
+                .and_then(|e| e.text("rc"))
+                .and_then(|e| e.uint(rc as u64))
+                .map_err(|_| SmpError::ResponseTooLarge)?;
+        }
+        let len = enc.len();
+        // Response op is request op + 1, the version bits and other fields
+        // are echoed
+        header.copy_from_slice(&request[..HEADER_LEN]);
+        header[0] = (request[0] & 0x18) | (op + 1);
+        header[1] = 0;
+        header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(HEADER_LEN + len)
+    }
+
+    fn upload(&mut self, payload: &[u8], enc: &mut Encoder) -> Result<(), Rc> {
+        let (mut off, mut len, mut data, mut sha) = (None, None, None, None);
+        let mut dec = Decoder::new(payload);
+        for _ in 0..dec.map().map_err(|_| Rc::Inval)? {
+            let key = dec.value().map_err(|_| Rc::Inval)?;
+            let value = dec.value().map_err(|_| Rc::Inval)?;
+            match (key, value) {
+                (Value::Text("off"), Value::Uint(v)) => {
+                    off = Some(u32::try_from(v).map_err(|_| Rc::Inval)?)
+                }
+                (Value::Text("len"), Value::Uint(v)) => {
+                    len = Some(u32::try_from(v).map_err(|_| Rc::Inval)?)
+                }
+                (Value::Text("data"), Value::Bytes(v)) => data = Some(v),
+                (Value::Text("sha"), Value::Bytes(v)) => {
+                    sha = Some(v.try_into().map_err(|_| Rc::Inval)?)
+                }
+                (Value::Text("image"), Value::Uint(v)) if v != 0 => return Err(Rc::Inval),
+                _ => {}
+            }
+        }
+        let (off, data) = (off.ok_or(Rc::Inval)?, data.ok_or(Rc::Inval)?);
+
+        if off == 0 {
+            let len = len.ok_or(Rc::Inval)?;
+            if len > self.secondary.len() {
+                return Err(Rc::Inval);
+            }
+            // A retransmit of the first chunk continues the ongoing upload
+            let restart = match &self.upload {
+                Some(upload) => upload.len != len || upload.sha != sha,
+                None => true,
+            };
+            if restart {
+                // Erase the whole slot, including any stale trailer
+                let slot_len = self.secondary.len();
+                self.secondary.erase(0, slot_len).map_err(|_| Rc::Unknown)?;
+                self.upload = Some(Upload { len, off: 0, sha });
+            }
+        }
+        let upload = self.upload.as_mut().ok_or(Rc::BadState)?;
+        // On an unexpected offset, tell the client where to continue
+        if off == upload.off {
+            let end = u32::try_from(data.len())
+                .ok()
+                .and_then(|n| off.checked_add(n))
+                .ok_or(Rc::Inval)?;
+            if end > upload.len {
+                return Err(Rc::Inval);
+            }
+            self.secondary.write(off, data).map_err(|_| Rc::Unknown)?;
+            upload.off = end;
+        }
+        let next = upload.off;
+        if next == upload.len {
+            debug!("SMP upload of {} bytes complete", next);
+            self.upload = None;
+        }
+        enc.map(2)?
+            .text("rc")?
+            .uint(0)?
+            .text("off")?
+            .uint(next as u64)?;
+        Ok(())
+    }
+
+    fn state_write(&mut self, payload: &[u8], enc: &mut Encoder) -> Result<(), Rc> {
+        let (mut hash, mut confirm) = (None, false);
+        let mut dec = Decoder::new(payload);
+        for _ in 0..dec.map().map_err(|_| Rc::Inval)? {
+            let key = dec.value().map_err(|_| Rc::Inval)?;
+            let value = dec.value().map_err(|_| Rc::Inval)?;
+            match (key, value) {
+                (Value::Text("hash"), Value::Bytes(v)) => hash = Some(v),
+                (Value::Text("confirm"), Value::Bool(v)) => confirm = v,
+                _ => {}
+            }
+        }
+        let images = self.images().map_err(|_| Rc::Unknown)?;
+        let slot = match hash {
+            None => 0,
+            Some(hash) => {
+                images
+                    .iter()
+                    .flatten()
+                    .find(|image| image.hash.as_ref().map(|h| &h[..]) == Some(hash))
+                    .ok_or(Rc::Inval)?
+                    .slot
+            }
+        };
+        match (slot, confirm) {
+            (0, true) => mcuboot::set_confirmed(self.primary).map_err(|_| Rc::Unknown)?,
+            // Testing the running image is a no-op
+            (0, false) => {}
+            (_, permanent) => {
+                mcuboot::set_pending(self.secondary, permanent).map_err(|_| Rc::Unknown)?
+            }
+        }
+        self.state_read(enc)
+    }
+
+    fn state_read(&mut self, enc: &mut Encoder) -> Result<(), Rc> {
+        let images = self.images().map_err(|_| Rc::Unknown)?;
+        let count = images.iter().flatten().count();
+        enc.map(2)?.text("images")?.array(count as u64)?;
+        for image in images.iter().flatten() {
+            let mut version = VersionString::default();
+            let v = image.version;
+            let _ = write!(version, "{}.{}.{}", v.major, v.minor, v.revision);
+            if v.build_num != 0 {
+                let _ = write!(version, ".{}", v.build_num);
+            }
+            enc.map(7 + image.hash.is_some() as u64)?;
+            enc.text("slot")?.uint(image.slot as u64)?;
+            enc.text("version")?.text(version.as_str())?;
+            if let Some(hash) = &image.hash {
+                enc.text("hash")?.bytes(hash)?;
+            }
+            enc.text("bootable")?.bool(true)?;
+            enc.text("pending")?.bool(image.pending)?;
+            enc.text("confirmed")?.bool(image.confirmed)?;
+            enc.text("active")?.bool(image.active)?;
+            enc.text("permanent")?.bool(image.permanent)?;
+        }
+        enc.text("splitStatus")?.uint(0)?;
+        Ok(())
+    }
+
+    fn images(&mut self) -> Result<[Option<ImageState>; 2], FlashError> {
+        let mut images = [None, None];
+        for (slot, image) in images.iter_mut().enumerate() {
+            let partition = if slot == 0 {
+                &mut *self.primary
+            } else {
+                &mut *self.secondary
+            };
+            let Some(header) = ImageHeader::read(partition)? else {
+                continue;
+            };
+            let hash = mcuboot::image_hash(partition, &header)?;
+            let trailer = Trailer::read(partition)?;
+            let pending = slot == 1 && trailer.magic && trailer.copy_done != Flag::Set;
+            *image = Some(ImageState {
+                slot: slot as u8,
+                version: header.version,
+                hash,
+                pending,
+                confirmed: slot == 0 && (!trailer.magic || trailer.image_ok == Flag::Set),
+                active: slot == 0,
+                permanent: pending && trailer.image_ok == Flag::Set,
+            });
+        }
+        Ok(images)
+    }
+}
+
+/// Fixed-size buffer for formatting version numbers
+#[derive(Default)]
+struct VersionString {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl VersionString {
+    fn as_str(&self) -> &str {
+        // Only ever filled with ASCII digits and dots
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for VersionString {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+    use embedded_storage::ReadStorage;
+
+    fn request(buf: &mut [u8], op: u8, id: u8, fill: impl FnOnce(&mut Encoder)) -> usize {
+        let (header, body) = buf.split_at_mut(HEADER_LEN);
+        let mut enc = Encoder::new(body);
+        fill(&mut enc);
+        let len = enc.len();
+        header.copy_from_slice(&[0x08 | op, 0, 0, 0, 0, 1, 7, id]);
+        header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        HEADER_LEN + len
+    }
+
+    fn upload_chunk(
+        manager: &mut ImageManager<MockFlash>,
+        off: u64,
+        data: &[u8],
+        sha: &[u8; 32],
+    ) -> u64 {
+        let mut req = [0u8; 256];
+        let len = request(&mut req, OP_WRITE, CMD_UPLOAD, |enc| {
+            enc.map(if off == 0 { 4 } else { 2 })
+                .and_then(|e| e.text("off"))
+                .and_then(|e| e.uint(off))
+                .and_then(|e| e.text("data"))
+                .and_then(|e| e.bytes(data))
+                .unwrap();
+            if off == 0 {
+                enc.text("len")
+                    .and_then(|e| e.uint(0x1000))
+                    .and_then(|e| e.text("sha"))
+                    .and_then(|e| e.bytes(sha))
+                    .unwrap();
+            }
+        });
+        let mut resp = [0u8; 64];
+        let n = manager.process(&req[..len], &mut resp).unwrap();
+        let mut dec = Decoder::new(&resp[HEADER_LEN..n]);
+        let mut next = None;
+        for _ in 0..dec.map().unwrap() {
+            match (dec.value().unwrap(), dec.value().unwrap()) {
+                (Value::Text("rc"), Value::Uint(rc)) => assert_eq!(rc, 0),
+                (Value::Text("off"), Value::Uint(v)) => next = Some(v),
+                _ => {}
+            }
+        }
+        next.unwrap()
+    }
+
+    #[test]
+    fn keeps_version_bits() {
+        let mut primary = Partition::with_flash(MockFlash::new(0x2000), 0, 0x2000);
+        let mut secondary = Partition::with_flash(MockFlash::new(0x2000), 0, 0x2000);
+        let mut manager = ImageManager::new(&mut primary, &mut secondary);
+        let mut req = [0u8; 16];
+        let len = request(&mut req, OP_READ, 9, |enc| {
+            enc.map(0).unwrap();
+        });
+        let mut resp = [0u8; 32];
+        manager.process(&req[..len], &mut resp).unwrap();
+        assert_eq!(resp[0], 0x08 | (OP_READ + 1));
+        assert_eq!(resp[4..8], req[4..8]);
+        assert_eq!(
+            manager.process(&req[..len], &mut resp[..4]),
+            Err(SmpError::ResponseTooLarge)
+        );
+        assert_eq!(
+            manager.process(&req[..4], &mut resp),
+            Err(SmpError::Truncated)
+        );
+    }
+
+    #[test]
+    fn retransmitted_first_chunk_keeps_upload() {
+        let mut primary = Partition::with_flash(MockFlash::new(0x2000), 0, 0x2000);
+        let mut secondary = Partition::with_flash(MockFlash::new(0x2000), 0, 0x2000);
+        {
+            let mut manager = ImageManager::new(&mut primary, &mut secondary);
+            let sha = [0x5a; 32];
+            assert_eq!(upload_chunk(&mut manager, 0, &[1; 64], &sha), 64);
+            assert_eq!(upload_chunk(&mut manager, 64, &[2; 64], &sha), 128);
+            // Same image again: continue where the upload stopped
+            assert_eq!(upload_chunk(&mut manager, 0, &[1; 64], &sha), 128);
+            // A different image restarts the upload
+            assert_eq!(upload_chunk(&mut manager, 0, &[3; 64], &[0xa5; 32]), 64);
+        }
+        let mut buf = [0u8; 128];
+        secondary.read(0, &mut buf).unwrap();
+        assert!(buf[..64].iter().all(|&b| b == 3));
+        assert!(buf[64..].iter().all(|&b| b == 0xff));
+    }
+}
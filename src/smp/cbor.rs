@@ -0,0 +1,246 @@
+//! Minimal CBOR encoding and decoding, as needed for SMP messages
+//!
+//! Only definite-length items are supported.
+
+const UINT: u8 = 0;
+const NEGINT: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+
+/// Maximum nesting of arrays, maps and tags skipped by [`Decoder::value`]
+const MAX_DEPTH: u8 = 8;
+
+/// The message is not valid CBOR, or uses unsupported features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Error;
+
+/// A decoded value, with nested values left undecoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Uint(u64),
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    Bool(bool),
+    Other,
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let b = *self.buf.get(self.pos).ok_or(Error)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: u64) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(n as usize).ok_or(Error)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(Error)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Decode an item header, returning major type and argument
+    fn header(&mut self) -> Result<(u8, u64), Error> {
+        let initial = self.byte()?;
+        let major = initial >> 5;
+        let arg = match initial & 0x1f {
+            n @ 0..=23 => n as u64,
+            24 => self.byte()? as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(Error),
+        };
+        Ok((major, arg))
+    }
+
+    /// Start decoding a map, returning the number of entries
+    pub fn map(&mut self) -> Result<u64, Error> {
+        match self.header()? {
+            (MAP, n) => Ok(n),
+            _ => Err(Error),
+        }
+    }
+
+    /// Decode a value, skipping over nested arrays and maps
+    ///
+    /// Items nested deeper than [`MAX_DEPTH`] are rejected, bounding the
+    /// recursion.
+    pub fn value(&mut self) -> Result<Value<'a>, Error> {
+        self.value_at(0)
+    }
+
+    fn value_at(&mut self, depth: u8) -> Result<Value<'a>, Error> {
+        let (major, arg) = self.header()?;
+        if matches!(major, ARRAY | MAP | TAG) && depth >= MAX_DEPTH {
+            return Err(Error);
+        }
+        Ok(match major {
+            UINT => Value::Uint(arg),
+            BYTES => Value::Bytes(self.take(arg)?),
+            TEXT => Value::Text(core::str::from_utf8(self.take(arg)?).map_err(|_| Error)?),
+            SIMPLE if arg == FALSE as u64 => Value::Bool(false),
+            SIMPLE if arg == TRUE as u64 => Value::Bool(true),
+            NEGINT | SIMPLE => Value::Other,
+            ARRAY => {
+                for _ in 0..arg {
+                    self.value_at(depth + 1)?;
+                }
+                Value::Other
+            }
+            MAP => {
+                for _ in 0..arg.checked_mul(2).ok_or(Error)? {
+                    self.value_at(depth + 1)?;
+                }
+                Value::Other
+            }
+            TAG => {
+                self.value_at(depth + 1)?;
+                Value::Other
+            }
+            _ => return Err(Error),
+        })
+    }
+}
+
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Encoder { buf, pos: 0 }
+    }
+
+    /// Number of bytes written
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(Error)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn header(&mut self, major: u8, arg: u64) -> Result<(), Error> {
+        let major = major << 5;
+        match arg {
+            0..=23 => self.put(&[major | arg as u8]),
+            24..=0xff => self.put(&[major | 24, arg as u8]),
+            0x100..=0xffff => {
+                self.put(&[major | 25])?;
+                self.put(&(arg as u16).to_be_bytes())
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.put(&[major | 26])?;
+                self.put(&(arg as u32).to_be_bytes())
+            }
+            _ => {
+                self.put(&[major | 27])?;
+                self.put(&arg.to_be_bytes())
+            }
+        }
+    }
+
+    pub fn map(&mut self, entries: u64) -> Result<&mut Self, Error> {
+        self.header(MAP, entries)?;
+        Ok(self)
+    }
+
+    pub fn array(&mut self, len: u64) -> Result<&mut Self, Error> {
+        self.header(ARRAY, len)?;
+        Ok(self)
+    }
+
+    pub fn uint(&mut self, value: u64) -> Result<&mut Self, Error> {
+        self.header(UINT, value)?;
+        Ok(self)
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<&mut Self, Error> {
+        self.header(BYTES, value.len() as u64)?;
+        self.put(value)?;
+        Ok(self)
+    }
+
+    pub fn text(&mut self, value: &str) -> Result<&mut Self, Error> {
+        self.header(TEXT, value.len() as u64)?;
+        self.put(value.as_bytes())?;
+        Ok(self)
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<&mut Self, Error> {
+        self.put(&[(SIMPLE << 5) | if value { TRUE } else { FALSE }])?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_values() {
+        let mut buf = [0u8; 64];
+        let mut enc = Encoder::new(&mut buf);
+        enc.map(3)
+            .and_then(|e| e.text("off"))
+            .and_then(|e| e.uint(0x1_0000))
+            .and_then(|e| e.text("data"))
+            .and_then(|e| e.bytes(&[1, 2, 3]))
+            .and_then(|e| e.text("ok"))
+            .and_then(|e| e.bool(true))
+            .unwrap();
+        let len = enc.len();
+        let mut dec = Decoder::new(&buf[..len]);
+        assert_eq!(dec.map(), Ok(3));
+        assert_eq!(dec.value(), Ok(Value::Text("off")));
+        assert_eq!(dec.value(), Ok(Value::Uint(0x1_0000)));
+        assert_eq!(dec.value(), Ok(Value::Text("data")));
+        assert_eq!(dec.value(), Ok(Value::Bytes(&[1, 2, 3])));
+        assert_eq!(dec.value(), Ok(Value::Text("ok")));
+        assert_eq!(dec.value(), Ok(Value::Bool(true)));
+        assert_eq!(dec.value(), Err(Error));
+    }
+
+    #[test]
+    fn rejects_huge_map() {
+        // Map with 2^64 - 1 entries
+        let msg = [0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Decoder::new(&msg).value(), Err(Error));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        // Arrays of one element, nested
+        let mut msg = [0x81u8; 64];
+        msg[63] = 0;
+        assert_eq!(Decoder::new(&msg).value(), Err(Error));
+        let nested = &msg[64 - MAX_DEPTH as usize - 2..];
+        assert_eq!(Decoder::new(nested).value(), Err(Error));
+        let nested = &msg[64 - MAX_DEPTH as usize - 1..];
+        assert_eq!(Decoder::new(nested).value(), Ok(Value::Other));
+    }
+}
@@ -0,0 +1,149 @@
+//! Status register protection
+//!
+//! The status register protect bits decide whether the status register,
+//! and with it the block protection configuration, can be changed:
+//!
+//! | SRL (SRP1) | SRP (SRP0) | Mode                                          |
+//! |------------|------------|-----------------------------------------------|
+//! | 0          | 0          | [`Software`](StatusProtection::Software)      |
+//! | 0          | 1          | [`Hardware`](StatusProtection::Hardware)      |
+//! | 1          | 0          | [`PowerSupplyLock`](StatusProtection::PowerSupplyLock) |
+//! | 1          | 1          | [`OneTimeProgram`](StatusProtection::OneTimeProgram) |
+//!
+//! SRP is bit 7 of status register 1. SRL is bit 0 of status register 2,
+//! which only Winbond and GigaDevice chips implement. Other chips only
+//! support the first two modes.
+//!
+//! Note that hardware protection relies on the WP# pin, which is used as
+//! IO2 when the flash is operated in quad mode (QE=1). On a typical RP2040
+//! board, [`Hardware`](StatusProtection::Hardware) therefore offers no
+//! protection beyond [`Software`](StatusProtection::Software).
+
+use crate::error::FlashError;
+use crate::flash;
+
+const SRP: u8 = 1 << 7;
+const SRL: u8 = 1 << 0;
+
+/// Protection of the status register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusProtection {
+    /// The status register can be written after a write enable
+    Software,
+    /// The status register can be written while WP# is high
+    Hardware,
+    /// The status register can't be written until the next power cycle
+    PowerSupplyLock,
+    /// The status register can never be written again
+    OneTimeProgram,
+}
+
+impl StatusProtection {
+    fn from_bits(sr1: u8, sr2: u8) -> Self {
+        match (sr2 & SRL != 0, sr1 & SRP != 0) {
+            (false, false) => StatusProtection::Software,
+            (false, true) => StatusProtection::Hardware,
+            (true, false) => StatusProtection::PowerSupplyLock,
+            (true, true) => StatusProtection::OneTimeProgram,
+        }
+    }
+
+    fn bits(self) -> (u8, u8) {
+        match self {
+            StatusProtection::Software => (0, 0),
+            StatusProtection::Hardware => (SRP, 0),
+            StatusProtection::PowerSupplyLock => (0, SRL),
+            StatusProtection::OneTimeProgram => (SRP, SRL),
+        }
+    }
+}
+
+/// Whether the chip implements SRL in status register 2
+fn has_srl(jedec_id: u32) -> bool {
+    matches!(jedec_id >> 16, 0xef | 0xc8)
+}
+
+/// Read the current status register protection
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn status_protection(use_boot2: bool) -> StatusProtection {
+    let sr1 = flash::read_status_register(1, use_boot2);
+    let sr2 = if has_srl(flash::flash_jedec_id(use_boot2)) {
+        flash::read_status_register(2, use_boot2)
+    } else {
+        0
+    };
+    StatusProtection::from_bits(sr1, sr2)
+}
+
+/// Set the status register protection
+///
+/// [`OneTimeProgram`](StatusProtection::OneTimeProgram) is refused with
+/// [`FlashError::Unsupported`], use [`lock_status_permanently`] for it.
+/// Modes the chip doesn't implement are refused the same way.
+///
+/// The other status register bits, including QE, are preserved. The new
+/// configuration is read back and [`FlashError::StatusWriteFailed`] is
+/// returned if it didn't take effect, e.g. because the register is
+/// already locked.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+pub unsafe fn set_status_protection(
+    mode: StatusProtection,
+    use_boot2: bool,
+) -> Result<(), FlashError> {
+    if mode == StatusProtection::OneTimeProgram {
+        return Err(FlashError::Unsupported);
+    }
+    write_protection(mode, use_boot2)
+}
+
+/// Permanently lock the status register
+///
+/// After this, neither the status register protection nor the block
+/// protection can ever be changed again. Only supported on chips
+/// implementing SRL.
+///
+/// # Safety
+///
+/// Nothing must access flash while this is running.
+/// Usually this means:
+///   - interrupts must be disabled
+///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+///   - DMA must not access flash memory
+///
+/// This is irreversible. Make sure the block protection is configured as
+/// intended first.
+pub unsafe fn lock_status_permanently(use_boot2: bool) -> Result<(), FlashError> {
+    write_protection(StatusProtection::OneTimeProgram, use_boot2)
+}
+
+unsafe fn write_protection(mode: StatusProtection, use_boot2: bool) -> Result<(), FlashError> {
+    let (srp, srl) = mode.bits();
+    let sr1 = (flash::read_status_register(1, use_boot2) & !SRP) | srp;
+    if has_srl(flash::flash_jedec_id(use_boot2)) {
+        let sr2 = (flash::read_status_register(2, use_boot2) & !SRL) | srl;
+        flash::write_status(0x01, &[sr1, sr2], use_boot2)?;
+    } else if srl != 0 {
+        return Err(FlashError::Unsupported);
+    } else {
+        flash::write_status(0x01, &[sr1], use_boot2)?;
+    }
+    if status_protection(use_boot2) != mode {
+        debug!("status protection not applied");
+        return Err(FlashError::StatusWriteFailed);
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Typed values stored in a flash sector
+//!
+//! [`FlashSector`] reserves a 4096 byte sector in the flash image, holding
+//! a single value of type `T` protected by a checksum:
+//!
+//! ```ignore
+//! #[link_section = ".rodata"]
+//! static CONFIG: FlashSector<Config> = FlashSector::new();
+//!
+//! let config = CONFIG.read().unwrap_or_default();
+//! unsafe { CONFIG.write(&config, true) }?;
+//! ```
+//!
+//! The static must be placed in flash using `#[link_section = ".rodata"]`,
+//! as it would otherwise be copied to RAM like any static containing an
+//! `UnsafeCell`.
+//!
+//! [`Sector`] stores a value in the same format at a given offset of any
+//! `embedded-storage` flash, e.g. `mock::MockFlash` in tests.
+
+use crate::crc;
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::{Flash, InternalFlash};
+#[cfg(target_os = "none")]
+use crate::xip::XIP_BASE;
+#[cfg(target_os = "none")]
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Marks a sector written by [`FlashSector::write`]
+const MAGIC: u32 = 0x7e5c_4a01;
+
+/// Magic, payload length, checksum
+const HEADER_SIZE: usize = 12;
+
+/// Checksum algorithm protecting the contents of a [`FlashSector`]
+pub trait Checksum {
+    fn checksum(data: &[u8]) -> u32;
+}
+
+/// Plain data, which can be stored in flash and read back
+///
+/// Implemented for integers, floats and arrays of them. Implement it for
+/// `#[repr(C)]` structs made of such fields:
+///
+/// ```ignore
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Config {
+///     baud_rate: u32,
+///     address: [u8; 4],
+/// }
+///
+/// unsafe impl Pod for Config {}
+/// ```
+///
+/// # Safety
+///
+/// Every bit pattern of the size of the type must be a valid value, and
+/// the type must not contain padding bytes. `bool`, `char`, enums,
+/// references, pointers and `NonZero` integers don't qualify.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// CRC-32, see [`crc`](crate::crc)
+pub struct Crc32;
+
+impl Checksum for Crc32 {
+    fn checksum(data: &[u8]) -> u32 {
+        crc::crc32(data)
+    }
+}
+
+/// A value of type `T` stored in the sector at `offset` of `flash`
+///
+/// `T` must be plain data, see [`Pod`]. If the definition of `T`
+/// changes between firmware versions, its size should change as well, or
+/// the old value should be erased, as the stored data is reinterpreted
+/// as the new type.
+pub struct Sector<T, F, C: Checksum = Crc32> {
+    flash: F,
+    offset: u32,
+    phantom: PhantomData<(T, C)>,
+}
+
+impl<T: Pod, F: NorFlash<Error = FlashError>, C: Checksum> Sector<T, F, C> {
+    const FITS: () = assert!(
+        size_of::<T>() <= SECTOR_SIZE as usize - HEADER_SIZE,
+        "value doesn't fit into a flash sector"
+    );
+
+    /// Use the sector of `flash` at `offset`, which must be a multiple of 4096
+    pub fn with_flash(flash: F, offset: u32) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
+        assert!(offset & (SECTOR_SIZE - 1) == 0);
+        assert!(offset
+            .checked_add(SECTOR_SIZE)
+            .is_some_and(|end| end as usize <= flash.capacity()));
+        Sector {
+            flash,
+            offset,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Read the stored value
+    ///
+    /// Returns `None` if the sector is blank, or the checksum doesn't match.
+    pub fn read(&mut self) -> Result<Option<T>, FlashError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.flash.read(self.offset, &mut header)?;
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(4) as usize != size_of::<T>() {
+            return Ok(None);
+        }
+        let mut buf = [0u8; SECTOR_SIZE as usize - HEADER_SIZE];
+        let payload = &mut buf[..size_of::<T>()];
+        self.flash.read(self.offset + HEADER_SIZE as u32, payload)?;
+        if C::checksum(payload) != word(8) {
+            warn!("checksum mismatch in flash sector {:#x}", self.offset);
+            return Ok(None);
+        }
+        // Safety: T is valid for any bit pattern, see `Pod`
+        Ok(Some(unsafe {
+            core::ptr::read_unaligned(payload.as_ptr() as *const T)
+        }))
+    }
+
+    /// Store `value`, replacing the previous contents
+    pub fn write(&mut self, value: &T) -> Result<(), FlashError> {
+        let mut buf = [0xffu8; SECTOR_SIZE as usize];
+        let len = size_of::<T>();
+        // Safety: T has no padding bytes, see `Pod`
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                buf[HEADER_SIZE..].as_mut_ptr(),
+                len,
+            )
+        };
+        let checksum = C::checksum(&buf[HEADER_SIZE..HEADER_SIZE + len]);
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        buf[8..12].copy_from_slice(&checksum.to_le_bytes());
+        let used = (HEADER_SIZE + len).next_multiple_of(PAGE_SIZE as usize);
+        self.erase()?;
+        self.flash.write(self.offset, &buf[..used])
+    }
+
+    /// Erase the sector, so [`read`](Self::read) returns `None`
+    pub fn erase(&mut self) -> Result<(), FlashError> {
+        self.flash.erase(self.offset, self.offset + SECTOR_SIZE)
+    }
+}
+
+/// A flash sector holding a value of type `T`
+///
+/// `T` must be plain data, see [`Pod`]. If the definition of `T`
+/// changes between firmware versions, its size should change as well, or
+/// the old value should be erased, as the stored data is reinterpreted
+/// as the new type.
+#[cfg(target_os = "none")]
+#[repr(C, align(4096))]
+pub struct FlashSector<T, C: Checksum = Crc32> {
+    data: UnsafeCell<[u8; SECTOR_SIZE as usize]>,
+    phantom: PhantomData<(T, C)>,
+}
+
+// Safety: the contents are only modified through flash operations, which
+// require exclusive access to flash by their safety contract
+#[cfg(target_os = "none")]
+unsafe impl<T, C: Checksum> Sync for FlashSector<T, C> {}
+
+#[cfg(target_os = "none")]
+impl<T: Pod, C: Checksum> FlashSector<T, C> {
+    const FITS: () = assert!(
+        size_of::<T>() <= SECTOR_SIZE as usize - HEADER_SIZE,
+        "value doesn't fit into a flash sector"
+    );
+
+    /// An erased sector
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
+        FlashSector {
+            data: UnsafeCell::new([0xff; SECTOR_SIZE as usize]),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Offset of the sector, relative to the beginning of the flash area
+    pub fn offset(&self) -> u32 {
+        // Hide the origin of the pointer, so the compiler can't make
+        // assumptions about the contents of the static
+        let addr = core::hint::black_box(self.data.get() as u32);
+        assert!(addr & (SECTOR_SIZE - 1) == 0 && addr >= XIP_BASE);
+        addr - XIP_BASE
+    }
+
+    /// The sector, accessed through the internal flash
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`Flash::new`].
+    unsafe fn sector(&self, use_boot2: bool) -> Sector<T, InternalFlash, C> {
+        Sector::with_flash(Flash::new(use_boot2), self.offset())
+    }
+
+    /// Read the stored value
+    ///
+    /// Returns `None` if the sector is blank, or the checksum doesn't match.
+    pub fn read(&self) -> Option<T> {
+        // Safety: only reads, which can't interfere with other flash users.
+        // They can't fail either, as the sector is inside the flash window.
+        unsafe { self.sector(false) }.read().ok().flatten()
+    }
+
+    /// Store `value`, replacing the previous contents
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts are disabled on the current core during the operation.
+    /// The caller must make sure that the other core doesn't access flash
+    /// and that DMA doesn't access flash during the operation.
+    pub unsafe fn write(&self, value: &T, use_boot2: bool) -> Result<(), FlashError> {
+        self.sector(use_boot2).write(value)
+    }
+
+    /// Erase the sector, so [`read`](Self::read) returns `None`
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`write`](Self::write).
+    pub unsafe fn erase(&self, use_boot2: bool) -> Result<(), FlashError> {
+        self.sector(use_boot2).erase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    #[test]
+    fn reads_back_written_value() {
+        let mut sector = Sector::<[u16; 3], _>::with_flash(MockFlash::new(0x2000), 0x1000);
+        assert_eq!(sector.read(), Ok(None));
+        sector.write(&[1, 2, 3]).unwrap();
+        assert_eq!(sector.read(), Ok(Some([1, 2, 3])));
+        sector.write(&[4, 5, 6]).unwrap();
+        assert_eq!(sector.read(), Ok(Some([4, 5, 6])));
+        sector.erase().unwrap();
+        assert_eq!(sector.read(), Ok(None));
+    }
+
+    #[test]
+    fn rejects_other_size_and_corruption() {
+        let mut flash = MockFlash::new(0x1000);
+        Sector::<u32, _>::with_flash(&mut flash, 0)
+            .write(&7)
+            .unwrap();
+        assert_eq!(Sector::<u64, _>::with_flash(&mut flash, 0).read(), Ok(None));
+        let mut page = [0xffu8; 256];
+        page[HEADER_SIZE] = 0;
+        NorFlash::write(&mut flash, 0, &page).unwrap();
+        assert_eq!(Sector::<u32, _>::with_flash(&mut flash, 0).read(), Ok(None));
+    }
+}
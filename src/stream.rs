@@ -0,0 +1,152 @@
+//! Program data produced page by page
+//!
+//! [`flash::flash_range_erase_and_program`] needs the whole image in one
+//! slice, which doesn't fit into RAM for firmware images received over
+//! UART or USB. The functions in this module ask for one page at a time
+//! instead, so only a single 256 byte page buffer on the stack is needed:
+//!
+//! ```ignore
+//! let written = unsafe {
+//!     stream::program_from_fn(0x100000, 0x80000, true, |page| {
+//!         uart.read_full_blocking(page).is_ok() && !is_last_page(page)
+//!     })?
+//! };
+//! ```
+//!
+//! Sectors are erased when the first page is written to them. The page
+//! source of [`program_from_fn`] is called between flash operations, with
+//! XIP enabled and interrupts on, so it can be ordinary code located in
+//! flash, receive data using interrupt driven drivers, and take as long as
+//! it needs. Each sector erase and page program runs in its own critical
+//! section, leaving and re-entering XIP mode each time.
+//!
+//! [`program_from_ram_fn`] instead keeps XIP disabled for the whole range,
+//! avoiding the overhead of re-entering XIP for each page. Its page source
+//! must be located in RAM, e.g. copying pages from a DMA ring buffer or
+//! polling a UART:
+//!
+//! ```ignore
+//! rp2040_flash::ram! {
+//!     extern "C" fn next_page(page: &mut [u8; 256]) -> bool {
+//!         // Must not access flash, and runs with interrupts disabled
+//!         ...
+//!     }
+//! }
+//!
+//! let written = unsafe { stream::program_from_ram_fn(0x100000, 0x80000, true, next_page)? };
+//! ```
+
+use crate::chip;
+use crate::error::FlashError;
+use crate::flash::{self, PAGE_SIZE, SECTOR_SIZE};
+
+/// Erase and program pages provided by `fill`, starting at `addr`
+///
+/// `fill` is called with a page buffer set to 0xff, and returns `true`
+/// after writing the next page to it, or `false` when there is no more
+/// data. The page isn't programmed in that case, so a partial last page
+/// should be returned with `true`, leaving the rest of the buffer as 0xff.
+/// Writing stops after `max_len` bytes even if `fill` has more data.
+///
+/// `addr` is relative to the beginning of the flash area, and `addr` and
+/// `max_len` must be multiples of 4096. The whole range is checked with
+/// [`flash::validate_range`] before anything is written, but only the
+/// sectors receiving pages are erased. The rest of the last sector
+/// written reads as 0xff afterwards.
+///
+/// Returns the number of bytes programmed, a multiple of 256.
+///
+/// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+/// is used to re-initialize the XIP engine after flashing.
+///
+/// # Safety
+///
+/// Each erase and program disables interrupts on the current core. During
+/// these operations, the other core must not access flash and DMA must not
+/// access flash.
+pub unsafe fn program_from_fn(
+    addr: u32,
+    max_len: u32,
+    use_boot2: bool,
+    mut fill: impl FnMut(&mut [u8; PAGE_SIZE as usize]) -> bool,
+) -> Result<u32, FlashError> {
+    crate::cs::free(|| flash::validate_range(addr, max_len, SECTOR_SIZE, use_boot2))?;
+    let mut page = [0xffu8; PAGE_SIZE as usize];
+    let mut written = 0;
+    while written < max_len {
+        page.fill(0xff);
+        if !fill(&mut page) {
+            break;
+        }
+        let page_addr = addr + written;
+        if page_addr & (SECTOR_SIZE - 1) == 0 {
+            trace!("stream erase {:#x}", page_addr);
+            crate::cs::free(|| {
+                flash::flash_range_erase_checked(page_addr, SECTOR_SIZE, use_boot2)
+            })?;
+        }
+        crate::cs::free(|| flash::flash_range_program_checked(page_addr, &page, use_boot2))?;
+        written += PAGE_SIZE;
+    }
+    debug!("stream wrote {:#x} bytes at {:#x}", written, addr);
+    Ok(written)
+}
+
+/// Erase and program the pages yielded by `pages`, starting at `addr`
+///
+/// Like [`program_from_fn`], with the pages taken from an iterator.
+///
+/// # Safety
+///
+/// Same as for [`program_from_fn`].
+pub unsafe fn program_from_iter(
+    addr: u32,
+    max_len: u32,
+    use_boot2: bool,
+    pages: impl IntoIterator<Item = [u8; PAGE_SIZE as usize]>,
+) -> Result<u32, FlashError> {
+    let mut pages = pages.into_iter();
+    program_from_fn(addr, max_len, use_boot2, |page| match pages.next() {
+        Some(next) => {
+            *page = next;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Erase and program pages provided by `fill`, with XIP disabled throughout
+///
+/// Like [`program_from_fn`], but the whole range is written in a single
+/// critical section, without re-entering XIP mode between pages. `fill`
+/// is called while XIP is disabled and interrupts are off, so it must be
+/// located in RAM, e.g. using the [`ram!`](crate::ram!) macro, must not
+/// access flash, and can only get its data by polling or from memory.
+/// It may reload the watchdog.
+///
+/// Afterwards, the flash chip's failure flags are checked, see
+/// [`flash::flash_check_failure`].
+///
+/// # Safety
+///
+/// Disables interrupts on the current core for the whole operation. The
+/// other core must not access flash and DMA must not access flash.
+///
+/// # Panics
+///
+/// Panics if `fill` isn't located in RAM or ROM, see
+/// [`ram::assert_in_ram`](crate::ram::assert_in_ram).
+pub unsafe fn program_from_ram_fn(
+    addr: u32,
+    max_len: u32,
+    use_boot2: bool,
+    fill: extern "C" fn(&mut [u8; PAGE_SIZE as usize]) -> bool,
+) -> Result<u32, FlashError> {
+    crate::cs::free(|| {
+        flash::validate_range(addr, max_len, SECTOR_SIZE, use_boot2)?;
+        let written = flash::program_from_source(addr, max_len, use_boot2, fill);
+        debug!("stream wrote {:#x} bytes at {:#x}", written, addr);
+        flash::flash_check_failure(chip::Operation::PageProgram, use_boot2)?;
+        Ok(written)
+    })
+}
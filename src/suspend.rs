@@ -0,0 +1,149 @@
+//! Sector erase with suspend and resume
+//!
+//! A sector erase takes tens to hundreds of milliseconds, during which
+//! flash can't be read, so code running from flash and interrupt handlers
+//! located in flash are stalled. Many chips can suspend an erase in
+//! progress (0x75), serve reads, and resume it later (0x7A).
+//!
+//! [`SuspendableErase`] runs the erase in short slices. Each call to
+//! [`SuspendableErase::poll`] resumes the erase, lets it run for a while
+//! with interrupts disabled, and suspends it again, so the application
+//! can use flash and service interrupts in between:
+//!
+//! ```ignore
+//! let mut erase = unsafe { SuspendableErase::new(0x100000, 4096, true)? };
+//! while erase.poll().is_pending() {
+//!     // Flash is readable here, except for the sector being erased
+//! }
+//! ```
+//!
+//! While an erase is suspended, the chip doesn't accept program or erase
+//! commands, so no other flash writes must be started until it completed.
+//!
+//! Supported for Winbond and GigaDevice (SUS bit in status register 2)
+//! and Macronix (ESB bit in the security register) chips.
+
+use crate::chip;
+use crate::error::FlashError;
+use crate::flash::{self, SECTOR_SIZE};
+use core::task::Poll;
+
+/// How to tell if an erase is suspended
+#[derive(Debug, Clone, Copy)]
+struct SuspendStatus {
+    /// Command reading the register containing the suspend flag
+    cmd: u8,
+    /// Mask of the suspend flag
+    mask: u8,
+}
+
+impl SuspendStatus {
+    fn detect(jedec_id: u32) -> Option<SuspendStatus> {
+        match jedec_id >> 16 {
+            // Winbond, GigaDevice: SR2.SUS
+            0xef | 0xc8 => Some(SuspendStatus {
+                cmd: 0x35,
+                mask: 0x80,
+            }),
+            // Macronix: security register ESB
+            0xc2 => Some(SuspendStatus {
+                cmd: 0x2b,
+                mask: 0x08,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A sector erase which is suspended between calls to [`poll`](Self::poll)
+pub struct SuspendableErase {
+    addr: u32,
+    slice: u32,
+    use_boot2: bool,
+    status: SuspendStatus,
+    started: bool,
+    result: Option<Result<(), FlashError>>,
+}
+
+impl SuspendableErase {
+    /// Prepare erasing the sector at `addr`
+    ///
+    /// `addr` is relative to the beginning of the flash area, and must be
+    /// a multiple of 4096. Nothing is erased before the first call to
+    /// [`poll`](Self::poll).
+    ///
+    /// Each slice lets the erase run for the time it takes to clock
+    /// `slice` bytes over the SPI bus, e.g. about 1 ms for 4000 bytes at
+    /// a 31.25 MHz SPI clock. Chips need some time after a resume to make
+    /// progress, so very short slices may never complete the erase.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after each slice.
+    ///
+    /// Fails with [`FlashError::Unsupported`] if the chip isn't known to
+    /// support erase suspend, and with the errors of
+    /// [`flash::validate_range`] for invalid ranges.
+    ///
+    /// # Safety
+    ///
+    /// Each slice disables interrupts on the current core. During this
+    /// function and each call to [`poll`](Self::poll), the other core must
+    /// not access flash and DMA must not access flash.
+    ///
+    /// No other flash writes must be started until the erase completed.
+    pub unsafe fn new(addr: u32, slice: u32, use_boot2: bool) -> Result<Self, FlashError> {
+        let status = crate::cs::free(|| {
+            flash::validate_range(addr, SECTOR_SIZE, SECTOR_SIZE, use_boot2)?;
+            SuspendStatus::detect(flash::flash_jedec_id(use_boot2)).ok_or(FlashError::Unsupported)
+        })?;
+        Ok(SuspendableErase {
+            addr,
+            slice,
+            use_boot2,
+            status,
+            started: false,
+            result: None,
+        })
+    }
+
+    /// Let the erase run for one slice
+    ///
+    /// Starts or resumes the erase, and suspends it again after the
+    /// slice. Returns `Poll::Pending` while the erase is suspended, and
+    /// the result once it completed. Further calls return the same result
+    /// again.
+    pub fn poll(&mut self) -> Poll<Result<(), FlashError>> {
+        if let Some(result) = self.result {
+            return Poll::Ready(result);
+        }
+        let [_, a2, a1, a0] = self.addr.to_be_bytes();
+        let erase = [0x20, a2, a1, a0];
+        let start = (!self.started).then_some(&erase[..]);
+        let (slice, cmd, use_boot2) = (self.slice, self.status.cmd, self.use_boot2);
+        let status =
+            crate::cs::free(|| unsafe { flash::erase_slice(start, slice, cmd, use_boot2) });
+        self.started = true;
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                self.result = Some(Err(e));
+                return Poll::Ready(Err(e));
+            }
+        };
+        if status & self.status.mask != 0 {
+            trace!("erase of {:#x} suspended", self.addr);
+            return Poll::Pending;
+        }
+        debug!("erase of {:#x} completed", self.addr);
+        let result = crate::cs::free(|| unsafe {
+            flash::flash_check_failure(chip::Operation::SectorErase, use_boot2)
+        });
+        self.result = Some(result);
+        Poll::Ready(result)
+    }
+
+    /// Check if the erase was started and hasn't completed yet
+    pub fn is_suspended(&self) -> bool {
+        self.started && self.result.is_none()
+    }
+}
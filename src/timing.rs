@@ -0,0 +1,141 @@
+//! Measuring how long flash operations keep XIP disabled
+//!
+//! While a flash operation runs from RAM, code and data in flash can't be
+//! accessed, so interrupt handlers located in flash are delayed, and a
+//! watchdog isn't fed. [`measure`] runs a flash operation and returns its
+//! result alongside the durations of these sections:
+//!
+//! ```ignore
+//! let (result, measurement) = rp2040_flash::timing::measure(|| unsafe {
+//!     flash::flash_range_erase_checked(addr, 4096, true)
+//! });
+//! result?;
+//! info!("XIP disabled for {} us", measurement.max_micros);
+//! ```
+//!
+//! The time is taken between entering and leaving the RAM-resident code,
+//! excluding copying the 2nd stage boot loader if `use_boot2` is `true`,
+//! and the checks done before and after. It is read from the 1 MHz
+//! system timer, which runs from the watchdog tick, so the resolution is
+//! one microsecond.
+//!
+//! Only available with the `timing` feature.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// TIMER TIMERAWH, the high word of the 1 MHz system timer
+#[cfg(not(feature = "rp235x"))]
+const TIMERAWH: *const u32 = 0x4005_4024 as _;
+/// TIMER TIMERAWL, the low word of the 1 MHz system timer
+#[cfg(not(feature = "rp235x"))]
+const TIMERAWL: *const u32 = 0x4005_4028 as _;
+/// TIMER0 TIMERAWH, the high word of the 1 MHz system timer
+#[cfg(feature = "rp235x")]
+const TIMERAWH: *const u32 = 0x400b_0024 as _;
+/// TIMER0 TIMERAWL, the low word of the 1 MHz system timer
+#[cfg(feature = "rp235x")]
+const TIMERAWL: *const u32 = 0x400b_0028 as _;
+
+/// SIO CPUID, the number of the core reading it
+const SIO_CPUID: *const u32 = 0xd000_0000_u32 as _;
+
+/// Sections recorded per core, so measurements on both cores don't mix
+static COUNT: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+static TOTAL_LOW: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+static TOTAL_HIGH: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+static MAX: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Durations of the XIP-disabled sections of a flash operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement {
+    /// Number of sections
+    pub count: u32,
+    /// Sum of all sections in microseconds
+    pub total_micros: u64,
+    /// Longest section in microseconds
+    pub max_micros: u32,
+}
+
+/// Run the flash operation `f` and measure it
+///
+/// Returns the result of `f` and the durations of the sections it ran
+/// with XIP disabled. `f` runs in a critical section, so flash operations
+/// of interrupt handlers aren't counted, and those of the other core are
+/// recorded separately. A nested call measures its own operation, which
+/// is counted by the enclosing call as well.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Measurement) {
+    crate::cs::free(|| {
+        let core = core_index();
+        let outer = load(core);
+        store(core, &Measurement::default());
+        let result = f();
+        let inner = load(core);
+        store(
+            core,
+            &Measurement {
+                count: outer.count + inner.count,
+                total_micros: outer.total_micros + inner.total_micros,
+                max_micros: outer.max_micros.max(inner.max_micros),
+            },
+        );
+        (result, inner)
+    })
+}
+
+/// Only load and store, as the RP2040 lacks atomic read-modify-write
+///
+/// Flash operations and [`measure`] run with interrupts disabled, so
+/// nothing else accesses the measurement of the same core meanwhile.
+fn load(core: usize) -> Measurement {
+    Measurement {
+        count: COUNT[core].load(Ordering::Relaxed),
+        total_micros: (TOTAL_HIGH[core].load(Ordering::Relaxed) as u64) << 32
+            | TOTAL_LOW[core].load(Ordering::Relaxed) as u64,
+        max_micros: MAX[core].load(Ordering::Relaxed),
+    }
+}
+
+fn store(core: usize, measurement: &Measurement) {
+    COUNT[core].store(measurement.count, Ordering::Relaxed);
+    TOTAL_LOW[core].store(measurement.total_micros as u32, Ordering::Relaxed);
+    TOTAL_HIGH[core].store((measurement.total_micros >> 32) as u32, Ordering::Relaxed);
+    MAX[core].store(measurement.max_micros, Ordering::Relaxed);
+}
+
+/// Run `f`, which runs code from RAM with XIP disabled, and record its duration
+pub(crate) fn section<R>(f: impl FnOnce() -> R) -> R {
+    let start = now();
+    let result = f();
+    let micros = now().wrapping_sub(start);
+    record(core_index(), u32::try_from(micros).unwrap_or(u32::MAX));
+    result
+}
+
+fn record(core: usize, micros: u32) {
+    let mut measurement = load(core);
+    measurement.count += 1;
+    measurement.total_micros += micros as u64;
+    measurement.max_micros = measurement.max_micros.max(micros);
+    store(core, &measurement);
+}
+
+fn core_index() -> usize {
+    // Safety: reading CPUID has no side effects
+    (unsafe { SIO_CPUID.read_volatile() } & 1) as usize
+}
+
+/// The 64 bit system timer, in microseconds
+fn now() -> u64 {
+    // Safety: reading the raw timer registers has no side effects
+    unsafe {
+        loop {
+            let high = TIMERAWH.read_volatile();
+            let low = TIMERAWL.read_volatile();
+            // Retry if the low word wrapped between the reads
+            if TIMERAWH.read_volatile() == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
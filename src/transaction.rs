@@ -0,0 +1,169 @@
+//! Several flash operations in one go
+//!
+//! Each call to a function in [`flash`] connects to the flash, exits XIP,
+//! performs the operation, flushes the cache and re-enters XIP. With
+//! `use_boot2`, the 2nd stage boot loader is copied to the stack each
+//! time, too. [`FlashTransaction`] queues erase and program operations and
+//! runs all of them within a single XIP-disabled window:
+//!
+//! ```ignore
+//! let mut tx = FlashTransaction::<4>::new(true);
+//! tx.erase_and_program(0x100000, &sector_a)
+//!     .erase_and_program(0x180000, &sector_b)
+//!     .program(0x1ff000, &page);
+//! unsafe { tx.commit()? };
+//! ```
+//!
+//! Operations run in the order they were queued. As flash can't be read
+//! while they run, the data to program must be located in RAM.
+
+use crate::chip;
+use crate::error::FlashError;
+use crate::flash::{self, BatchOp, PAGE_SIZE, SECTOR_SIZE};
+use core::marker::PhantomData;
+
+/// Erase and program operations to run together, up to `N` of them
+pub struct FlashTransaction<'a, const N: usize> {
+    ops: [BatchOp; N],
+    count: usize,
+    use_boot2: bool,
+    phantom: PhantomData<&'a [u8]>,
+}
+
+impl<'a, const N: usize> FlashTransaction<'a, N> {
+    /// An empty transaction
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after the operations.
+    pub fn new(use_boot2: bool) -> Self {
+        FlashTransaction {
+            ops: [BatchOp {
+                addr: 0,
+                len: 0,
+                data: core::ptr::null(),
+                erase: 0,
+            }; N],
+            count: 0,
+            use_boot2,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Number of queued operations
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns `true` if no operations are queued
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Queue erasing `len` bytes at `addr`
+    ///
+    /// `addr` is relative to the beginning of the flash area. `addr` and
+    /// `len` must be multiples of 4096, which is checked by
+    /// [`commit`](Self::commit).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` operations are queued already.
+    pub fn erase(&mut self, addr: u32, len: u32) -> &mut Self {
+        self.push(addr, len, None, true)
+    }
+
+    /// Queue programming `data` at `addr`
+    ///
+    /// `addr` and the length of `data` must be multiples of 256, and the
+    /// range must have been erased before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` operations are queued already, or if `data` isn't
+    /// located in RAM.
+    pub fn program(&mut self, addr: u32, data: &'a [u8]) -> &mut Self {
+        self.push(addr, data.len() as u32, Some(data), false)
+    }
+
+    /// Queue erasing and programming `data` at `addr`
+    ///
+    /// `addr` and the length of `data` must be multiples of 4096.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` operations are queued already, or if `data` isn't
+    /// located in RAM.
+    pub fn erase_and_program(&mut self, addr: u32, data: &'a [u8]) -> &mut Self {
+        self.push(addr, data.len() as u32, Some(data), true)
+    }
+
+    fn push(&mut self, addr: u32, len: u32, data: Option<&'a [u8]>, erase: bool) -> &mut Self {
+        assert!(self.count < N, "transaction is full");
+        if let Some(data) = data {
+            // Check both ends, the data may extend from RAM into flash
+            let last = data.as_ptr().wrapping_add(data.len().saturating_sub(1));
+            assert!(
+                crate::ram::is_flash_independent(data.as_ptr() as *const ())
+                    && crate::ram::is_flash_independent(last as *const ()),
+                "data is not located in RAM"
+            );
+        }
+        self.ops[self.count] = BatchOp {
+            addr,
+            len,
+            data: data.map_or(core::ptr::null(), |d| d.as_ptr()),
+            erase: erase as u32,
+        };
+        self.count += 1;
+        self
+    }
+
+    /// Run all queued operations
+    ///
+    /// All ranges are checked with [`flash::validate_range`] first, and
+    /// nothing is written if one of them is invalid. After each operation,
+    /// the status register is read, and the remaining operations are
+    /// skipped if the chip didn't perform it, e.g. because the range is
+    /// write protected. Afterwards, the flash chip's failure flags are
+    /// checked, see [`flash::flash_check_failure`].
+    ///
+    /// # Safety
+    ///
+    /// Interrupts are disabled on the current core while the operations
+    /// run. The other core must not access flash and DMA must not access
+    /// flash during this call.
+    pub unsafe fn commit(self) -> Result<(), FlashError> {
+        let ops = &self.ops[..self.count];
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let use_boot2 = self.use_boot2;
+        crate::cs::free(|| {
+            for op in ops {
+                let align = if op.erase != 0 {
+                    SECTOR_SIZE
+                } else {
+                    PAGE_SIZE
+                };
+                flash::validate_range(op.addr, op.len, align, use_boot2)?;
+            }
+            let done = flash::run_batch(ops, use_boot2);
+            let last = &ops[ops.len() - 1];
+            let last = if !last.data.is_null() {
+                chip::Operation::PageProgram
+            } else if last.len >= 0x10000 {
+                chip::Operation::BlockErase64K
+            } else if last.len >= 0x8000 {
+                chip::Operation::BlockErase32K
+            } else {
+                chip::Operation::SectorErase
+            };
+            // Also clears the write enable latch left set by a failed operation
+            flash::flash_check_failure(last, use_boot2)?;
+            if done < ops.len() {
+                return Err(FlashError::ProgramFailed);
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,134 @@
+//! Unique ID of the flash chip
+//!
+//! Most flash chips have a factory programmed unique ID, read with command
+//! 0x4B. Its length depends on the manufacturer: Winbond parts return 8
+//! bytes, while Zetta, GigaDevice and ISSI parts return 16 bytes. The
+//! 16 byte IDs of some parts are *not* unique in their first 8 bytes.
+//!
+//! [`FlashUid`] reads the JEDEC ID first to select the length, and derives
+//! identifiers like serial numbers and MAC addresses from the ID.
+//!
+//! The IDs are relatively predictable and should be salted and hashed
+//! before use if that is an issue.
+
+use crate::crc::crc32;
+use crate::error::FlashError;
+use crate::flash;
+
+/// Maximum length of a unique ID
+pub const MAX_UID_LEN: usize = 16;
+
+/// Length of the unique ID of chips by manufacturer
+fn uid_len(jedec_id: u32) -> Option<usize> {
+    match jedec_id >> 16 {
+        // Winbond
+        0xef => Some(8),
+        // Zetta, GigaDevice, ISSI
+        0xba | 0xc8 | 0x9d => Some(16),
+        _ => None,
+    }
+}
+
+/// Unique ID of the flash chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashUid {
+    bytes: [u8; MAX_UID_LEN],
+    len: usize,
+}
+
+impl FlashUid {
+    /// Read the unique ID
+    ///
+    /// Fails with [`FlashError::Unsupported`] if the chip isn't known to
+    /// have a unique ID, e.g. Macronix and Spansion parts.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must access flash while this is running.
+    /// Usually this means:
+    ///   - interrupts must be disabled
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    pub unsafe fn read(use_boot2: bool) -> Result<Self, FlashError> {
+        let len = uid_len(flash::flash_jedec_id(use_boot2)).ok_or(FlashError::Unsupported)?;
+        let mut bytes = [0u8; MAX_UID_LEN];
+        flash::flash_unique_id(&mut bytes[..len], use_boot2);
+        Ok(FlashUid { bytes, len })
+    }
+
+    /// Create from raw ID bytes, e.g. read earlier with
+    /// [`flash_unique_id`](crate::flash::flash_unique_id)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than [`MAX_UID_LEN`].
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut uid = FlashUid {
+            bytes: [0; MAX_UID_LEN],
+            len: bytes.len(),
+        };
+        uid.bytes[..bytes.len()].copy_from_slice(bytes);
+        uid
+    }
+
+    /// The raw ID, 8 or 16 bytes long
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The ID as a 64 bit number
+    ///
+    /// 8 byte IDs are interpreted as big endian. For 16 byte IDs, both
+    /// halves are combined with XOR, so all bytes contribute.
+    pub fn as_u64(&self) -> u64 {
+        self.as_bytes()
+            .chunks(8)
+            .map(|chunk| {
+                let mut buf = [0u8; 8];
+                buf[8 - chunk.len()..].copy_from_slice(chunk);
+                u64::from_be_bytes(buf)
+            })
+            .fold(0, |acc, half| acc ^ half)
+    }
+
+    /// A MAC address (EUI-48) using `oui` as the first three bytes
+    ///
+    /// The remaining three bytes are taken from the CRC-32 of the ID, so
+    /// all bytes of the ID contribute. With only 24 bits derived from the
+    /// ID, collisions between devices are possible.
+    pub fn to_eui48(&self, oui: [u8; 3]) -> [u8; 6] {
+        let [_, a, b, c] = crc32(self.as_bytes()).to_be_bytes();
+        [oui[0], oui[1], oui[2], a, b, c]
+    }
+
+    /// The last `N` hex digits of the ID, e.g. as USB serial number
+    ///
+    /// Uses upper case digits. If `N` is larger than twice the length of
+    /// the ID, the result is padded with leading zeros.
+    pub fn to_hex_serial<const N: usize>(&self) -> HexSerial<N> {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        let mut out = [b'0'; N];
+        let nibbles = self.as_bytes().iter().rev().flat_map(|b| [b & 0xf, b >> 4]);
+        for (digit, nibble) in out.iter_mut().rev().zip(nibbles) {
+            *digit = DIGITS[nibble as usize];
+        }
+        HexSerial(out)
+    }
+}
+
+/// Hex digits of a [`FlashUid`], see [`FlashUid::to_hex_serial`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexSerial<const N: usize>([u8; N]);
+
+impl<const N: usize> HexSerial<N> {
+    /// The digits as string
+    pub fn as_str(&self) -> &str {
+        // Safety: only contains ASCII hex digits
+        unsafe { core::str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// The digits as ASCII bytes
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
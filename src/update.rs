@@ -0,0 +1,294 @@
+//! A/B firmware updates without a boot loader
+//!
+//! A new image is written to a staging slot while the old one keeps
+//! running, e.g. with [`stream::program_from_fn`](crate::stream::program_from_fn).
+//! [`Updater::stage`] checks its length and CRC and records them in a
+//! control sector. [`Updater::activate`] then swaps the staging slot with
+//! the active slot, running entirely from RAM and ROM, and resets the
+//! chip using the watchdog:
+//!
+//! ```ignore
+//! // Running image in the lower, staging slot in the upper MiB of a 2 MiB flash
+//! let mut updater = unsafe { Updater::new(0, 0x100000, 0xff000, 0x1ff000, true) };
+//! match unsafe { updater.boot_check()? } {
+//!     State::Testing => { /* run self tests, then */ updater.confirm()?; }
+//!     _ => {}
+//! }
+//! // ... receive an image into the staging slot ...
+//! updater.stage(len, crc)?;
+//! unsafe { updater.activate()? };
+//! ```
+//!
+//! # Rollback
+//!
+//! As the slots are swapped, the previous image is kept in the staging
+//! slot. Markers in the control sector track the state of an update. The
+//! new image must call [`Updater::boot_check`] early after each start,
+//! and [`Updater::confirm`] once it works. If it is restarted before
+//! confirming, e.g. by a watchdog reset after a crash, `boot_check`
+//! swaps the slots back and resets the chip.
+//!
+//! There is no boot loader completing an interrupted swap, so a power
+//! loss or reset during [`Updater::activate`] leaves an unbootable image.
+//!
+//! # Control sector
+//!
+//! | Offset | Content                                         |
+//! |--------|-------------------------------------------------|
+//! | 0      | magic                                           |
+//! | 4      | image length                                    |
+//! | 8      | image CRC-32, see [`crc`](crate::crc)           |
+//! | 16     | activated marker, written before the swap       |
+//! | 20     | booted marker, written at the first start       |
+//! | 24     | confirmed marker                                |
+//! | 28     | reverted marker, written before swapping back   |
+//!
+//! Markers are 0 when set. They are only ever programmed, not erased,
+//! until the next image is staged.
+
+use crate::crc;
+use crate::error::FlashError;
+use crate::flash::{self, PAGE_SIZE, SECTOR_SIZE};
+use crate::xip;
+use core::convert::Infallible;
+
+const MAGIC: u32 = 0x5550_4454;
+
+const ACTIVATED: u32 = 16;
+const BOOTED: u32 = 20;
+const CONFIRMED: u32 = 24;
+const REVERTED: u32 = 28;
+
+/// Errors reported by the updater
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A flash operation failed
+    Flash(FlashError),
+    /// The image length is 0 or exceeds the slot
+    InvalidLength,
+    /// The CRC of the staged image doesn't match
+    CrcMismatch,
+    /// No image is staged, or it was activated already
+    NotStaged,
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// State of an update, as recorded in the control sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    /// No image is staged
+    Empty,
+    /// An image of `len` bytes is staged and ready to be activated
+    Staged { len: u32 },
+    /// The staged image was activated, but hasn't started yet
+    Activated,
+    /// The new image is running and not confirmed yet
+    Testing,
+    /// The new image was confirmed
+    Confirmed,
+    /// The new image wasn't confirmed, and the previous one was restored
+    Reverted,
+}
+
+/// Manages an active and a staging slot
+pub struct Updater {
+    active: u32,
+    staging: u32,
+    len: u32,
+    control: u32,
+    use_boot2: bool,
+}
+
+impl Updater {
+    /// Use slots of `len` bytes at `active` and `staging`, and the control
+    /// sector at `control`
+    ///
+    /// All offsets are relative to the beginning of the flash area. `active`
+    /// is usually 0, i.e. the running program including its 2nd stage
+    /// boot loader. Offsets and `len` must be multiples of 4096.
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The staging slot and control sector must not be used for anything
+    /// else, in particular not contain code or data of the running program.
+    ///
+    /// Each write disables interrupts on the current core while it
+    /// accesses flash. The caller must make sure that the other core
+    /// doesn't access flash and that DMA doesn't access flash during writes.
+    pub unsafe fn new(active: u32, staging: u32, len: u32, control: u32, use_boot2: bool) -> Self {
+        for offset in [active, staging, len, control] {
+            assert!(offset & (SECTOR_SIZE - 1) == 0);
+        }
+        assert!(active.max(staging) + len <= 0x1000000 && control < 0x1000000);
+        assert!(active + len <= staging || staging + len <= active);
+        for slot in [active, staging] {
+            assert!(control + SECTOR_SIZE <= slot || slot + len <= control);
+        }
+        Updater {
+            active,
+            staging,
+            len,
+            control,
+            use_boot2,
+        }
+    }
+
+    /// Offset of the staging slot, relative to the beginning of the flash area
+    pub fn staging(&self) -> u32 {
+        self.staging
+    }
+
+    /// Length of each slot in bytes
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Returns `true` if the slots have a length of 0
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The state recorded in the control sector
+    pub fn state(&self) -> State {
+        if self.word(0) != MAGIC {
+            State::Empty
+        } else if self.is_set(REVERTED) {
+            State::Reverted
+        } else if self.is_set(CONFIRMED) {
+            State::Confirmed
+        } else if self.is_set(BOOTED) {
+            State::Testing
+        } else if self.is_set(ACTIVATED) {
+            State::Activated
+        } else {
+            State::Staged { len: self.word(4) }
+        }
+    }
+
+    /// Record the image of `len` bytes in the staging slot as ready
+    ///
+    /// Fails with [`Error::CrcMismatch`] if the CRC-32 of the image isn't
+    /// `crc`. Replaces any previous update state.
+    pub fn stage(&mut self, len: u32, crc: u32) -> Result<(), Error> {
+        self.verify(len, crc)?;
+        let mut page = [0xffu8; PAGE_SIZE as usize];
+        for (i, word) in [MAGIC, len, crc].into_iter().enumerate() {
+            page[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        let (control, use_boot2) = (self.control, self.use_boot2);
+        crate::cs::free(|| unsafe {
+            flash::flash_range_erase_checked(control, SECTOR_SIZE, use_boot2)?;
+            flash::flash_range_program_checked(control, &page, use_boot2)
+        })?;
+        debug!("staged image, len {:#x} crc {:#x}", len, crc);
+        Ok(())
+    }
+
+    /// Activate the staged image and reset the chip
+    ///
+    /// The CRC is checked again, then the staged and active slots are
+    /// swapped, for the length of the image rounded up to full sectors.
+    /// Only returns if the image can't be activated.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts are disabled and nothing but this function may access
+    /// flash until the chip is reset:
+    ///   - 2nd core must be running code from RAM or ROM with interrupts disabled
+    ///   - DMA must not access flash memory
+    ///   - the watchdog must be disabled, or its timeout must be longer
+    ///     than the swap, which erases and programs two sectors per 4 KiB
+    ///     of image
+    pub unsafe fn activate(&mut self) -> Result<Infallible, Error> {
+        let State::Staged { len } = self.state() else {
+            return Err(Error::NotStaged);
+        };
+        self.verify(len, self.word(8))?;
+        self.set(ACTIVATED)?;
+        Ok(self.swap(len)?)
+    }
+
+    /// Update the state after a reset, call early after each start
+    ///
+    /// At the first start of a newly activated image, records that it
+    /// booted and returns [`State::Testing`]. If the image was started
+    /// before and not confirmed, the previous image is restored and the
+    /// chip is reset, see the [module documentation](self). Otherwise, the
+    /// state is returned unchanged.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`activate`](Self::activate), in case the previous
+    /// image is restored.
+    pub unsafe fn boot_check(&mut self) -> Result<State, Error> {
+        match self.state() {
+            State::Activated => {
+                self.set(BOOTED)?;
+                Ok(State::Testing)
+            }
+            State::Testing => {
+                warn!("update wasn't confirmed, reverting");
+                self.set(REVERTED)?;
+                match self.swap(self.word(4))? {}
+            }
+            state => Ok(state),
+        }
+    }
+
+    /// Mark the running image as good
+    ///
+    /// Does nothing unless the state is [`State::Testing`].
+    pub fn confirm(&mut self) -> Result<(), Error> {
+        if self.state() == State::Testing {
+            self.set(CONFIRMED)?;
+            debug!("update confirmed");
+        }
+        Ok(())
+    }
+
+    /// Check the length and CRC of the staged image
+    fn verify(&self, len: u32, crc: u32) -> Result<(), Error> {
+        if len == 0 || len > self.len {
+            return Err(Error::InvalidLength);
+        }
+        // Safety: the staging slot is inside the XIP window, which is always readable
+        let image = unsafe {
+            core::slice::from_raw_parts((xip::XIP_BASE + self.staging) as *const u8, len as usize)
+        };
+        if crc::crc32(image) != crc {
+            warn!("staged image CRC mismatch");
+            return Err(Error::CrcMismatch);
+        }
+        Ok(())
+    }
+
+    unsafe fn swap(&mut self, len: u32) -> Result<Infallible, FlashError> {
+        let len = len.next_multiple_of(SECTOR_SIZE);
+        flash::swap_and_reboot(self.active, self.staging, len, self.use_boot2)
+    }
+
+    fn word(&self, offset: u32) -> u32 {
+        let mut buf = [0u8; 4];
+        xip::read_uncached(self.control + offset, &mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn is_set(&self, marker: u32) -> bool {
+        self.word(marker) == 0
+    }
+
+    fn set(&mut self, marker: u32) -> Result<(), FlashError> {
+        let (addr, use_boot2) = (self.control + marker, self.use_boot2);
+        crate::cs::free(|| unsafe { flash::flash_write_unaligned(addr, &[0; 4], use_boot2) })
+    }
+}
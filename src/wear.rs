@@ -0,0 +1,382 @@
+//! Erase cycle statistics
+//!
+//! NOR flash sectors endure a limited number of erase cycles, typically
+//! 100k. [`WearTracker`] counts the erases of each sector of a region, so
+//! products logging to flash can estimate how much endurance is left:
+//!
+//! ```ignore
+//! // 64 sectors of log at 1 MiB, counters in the two sectors after them
+//! let mut wear = unsafe { WearTracker::<64>::new(0x100000, 0x140000, 16, true)? };
+//! wear.erase(sector)?;
+//! let (worst, cycles) = wear.most_erased();
+//! let left = wear.remaining(100_000);
+//! ```
+//!
+//! Counters are kept in RAM and written to a pair of dedicated metadata
+//! sectors every `flush_every` erases, or by [`WearTracker::flush`]. Each
+//! write appends a snapshot of all counters with a sequence number to one
+//! of them. Once it is full, the other sector is erased and receives the
+//! next snapshot, so the newest snapshot survives a power failure during
+//! the erase, and tracking adds little wear of its own. Erases since the
+//! last snapshot are lost on a reset, so the counters may fall slightly
+//! behind.
+//!
+//! Snapshot layout, padded to a multiple of 256 bytes:
+//!
+//! | Offset   | Contents                                      |
+//! |----------|-----------------------------------------------|
+//! | 0        | magic, 4 bytes                                |
+//! | 4        | sequence number, 4 bytes                      |
+//! | 8        | erase count of the metadata sectors, 4 bytes  |
+//! | 12       | erase count of each sector, 4 bytes each      |
+//! | 12 + 4 N | CRC-32 of the preceding bytes, 4 bytes        |
+
+use crate::crc::{seq_newer, Crc32};
+use crate::error::FlashError;
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+#[cfg(target_os = "none")]
+use crate::nor_flash::Flash;
+use crate::nor_flash::InternalFlash;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Marks a snapshot of the counters
+const MAGIC: u32 = 0x5745_4152;
+
+/// Erase counters for a region of `N` sectors
+///
+/// `F` is the flash holding the region and the metadata sector, the
+/// internal flash unless given to [`with_flash`](Self::with_flash).
+pub struct WearTracker<const N: usize, F = InternalFlash> {
+    flash: F,
+    offset: u32,
+    meta: u32,
+    flush_every: u32,
+    counts: [u32; N],
+    meta_erases: u32,
+    pending: u32,
+    /// Metadata sector receiving snapshots, 0 or 1
+    active: u32,
+    /// Sequence number of the newest snapshot
+    seq: u32,
+    /// Offset of the next snapshot in the active metadata sector
+    write_pos: u32,
+}
+
+#[cfg(target_os = "none")]
+impl<const N: usize> WearTracker<N> {
+    /// Track the `N` sectors at `offset`, with counters stored in the
+    /// two sectors at `meta`
+    ///
+    /// Both offsets are relative to the beginning of the flash area and
+    /// must be multiples of 4096. The counters are loaded from the newest
+    /// valid snapshot in the metadata sectors, or start at 0. They are
+    /// written every `flush_every` erases; 0 only writes them on
+    /// [`flush`](Self::flush).
+    ///
+    /// If `use_boot2` is `true`, a copy of the 2nd stage boot loader
+    /// is used to re-initialize the XIP engine after flashing.
+    ///
+    /// # Safety
+    ///
+    /// The region and the metadata sectors must not contain code or data
+    /// of the running program, and the metadata sectors must not be used
+    /// for anything else.
+    ///
+    /// Each flash operation disables interrupts on the current core. The
+    /// caller must make sure that the other core doesn't access flash and
+    /// that DMA doesn't access flash during writes.
+    pub unsafe fn new(
+        offset: u32,
+        meta: u32,
+        flush_every: u32,
+        use_boot2: bool,
+    ) -> Result<Self, FlashError> {
+        Self::with_flash(Flash::new(use_boot2), offset, meta, flush_every)
+    }
+}
+
+impl<const N: usize, F: NorFlash<Error = FlashError>> WearTracker<N, F> {
+    const WORDS: u32 = N as u32 + 4;
+    const SNAPSHOT_SIZE: u32 = (Self::WORDS * 4).next_multiple_of(PAGE_SIZE);
+    const FITS: () = assert!(
+        N > 0 && N as u32 + 4 <= SECTOR_SIZE / 4,
+        "N must be between 1 and 1020"
+    );
+
+    /// Track the `N` sectors of `flash` at `offset`, with counters stored
+    /// in the two sectors at `meta`
+    ///
+    /// See [`WearTracker::new`] for the requirements.
+    pub fn with_flash(
+        flash: F,
+        offset: u32,
+        meta: u32,
+        flush_every: u32,
+    ) -> Result<Self, FlashError> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
+        let len = N as u32 * SECTOR_SIZE;
+        let capacity = flash.capacity() as u64;
+        assert!(offset & (SECTOR_SIZE - 1) == 0 && meta & (SECTOR_SIZE - 1) == 0);
+        assert!(offset as u64 + len as u64 <= capacity);
+        assert!(meta as u64 + 2 * SECTOR_SIZE as u64 <= capacity);
+        assert!(meta + 2 * SECTOR_SIZE <= offset || offset + len <= meta);
+        let mut tracker = WearTracker {
+            flash,
+            offset,
+            meta,
+            flush_every,
+            counts: [0; N],
+            meta_erases: 0,
+            pending: 0,
+            active: 0,
+            seq: 0,
+            write_pos: SECTOR_SIZE,
+        };
+        tracker.load()?;
+        Ok(tracker)
+    }
+
+    /// Find the newest valid snapshot and the first free slot after it
+    fn load(&mut self) -> Result<(), FlashError> {
+        let mut newest: Option<(u32, u32)> = None;
+        let mut ends = [0; 2];
+        for (sector, end) in (0..2).zip(&mut ends) {
+            let mut pos = 0;
+            while pos + Self::SNAPSHOT_SIZE <= SECTOR_SIZE {
+                let magic = self.word(sector, pos, 0)?;
+                if magic == 0xffff_ffff {
+                    break;
+                }
+                // Snapshots torn by a reset fail the CRC and are skipped
+                if magic == MAGIC
+                    && self.crc(sector, pos)? == self.word(sector, pos, Self::WORDS - 1)?
+                {
+                    let seq = self.word(sector, pos, 1)?;
+                    if newest.is_none_or(|(_, newest)| seq_newer(seq, newest)) {
+                        newest = Some((sector, seq));
+                        self.meta_erases = self.word(sector, pos, 2)?;
+                        for i in 0..N {
+                            self.counts[i] = self.word(sector, pos, 3 + i as u32)?;
+                        }
+                    }
+                }
+                pos += Self::SNAPSHOT_SIZE;
+            }
+            *end = pos;
+        }
+        let (active, seq) = newest.unwrap_or((0, 0));
+        self.active = active;
+        self.seq = seq;
+        self.write_pos = ends[active as usize];
+        debug!(
+            "wear counters loaded, {} erases in total",
+            self.total_erases()
+        );
+        Ok(())
+    }
+
+    /// Word `index` of the snapshot at `pos` of metadata sector `sector`
+    fn word(&mut self, sector: u32, pos: u32, index: u32) -> Result<u32, FlashError> {
+        let mut buf = [0u8; 4];
+        let addr = self.meta + sector * SECTOR_SIZE + pos + index * 4;
+        self.flash.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// CRC-32 of the snapshot at `pos`, excluding the CRC itself
+    fn crc(&mut self, sector: u32, pos: u32) -> Result<u32, FlashError> {
+        let mut crc = Crc32::new();
+        for index in 0..Self::WORDS - 1 {
+            crc.update(&self.word(sector, pos, index)?.to_le_bytes());
+        }
+        Ok(crc.finish())
+    }
+
+    /// Word `index` of a snapshot of the current counters, without the CRC
+    fn current(&self, index: u32) -> u32 {
+        match index {
+            0 => MAGIC,
+            1 => self.seq,
+            2 => self.meta_erases,
+            i => self.counts[i as usize - 3],
+        }
+    }
+
+    /// Number of times `sector` was erased
+    ///
+    /// `sector` is the index of the sector inside the region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector` isn't less than `N`.
+    pub fn erase_count(&self, sector: usize) -> u32 {
+        self.counts[sector]
+    }
+
+    /// Number of times the metadata sectors were erased, in total
+    pub fn meta_erase_count(&self) -> u32 {
+        self.meta_erases
+    }
+
+    /// The index and erase count of the most erased sector
+    ///
+    /// Returns the first one if several sectors have the same count.
+    pub fn most_erased(&self) -> (usize, u32) {
+        self.counts
+            .iter()
+            .copied()
+            .enumerate()
+            .fold(
+                (0, 0),
+                |max, (i, count)| {
+                    if count > max.1 {
+                        (i, count)
+                    } else {
+                        max
+                    }
+                },
+            )
+    }
+
+    /// Sum of the erase counts of all sectors of the region
+    pub fn total_erases(&self) -> u64 {
+        self.counts.iter().map(|&count| count as u64).sum()
+    }
+
+    /// Erase cycles left for the most erased sector, out of
+    /// `rated_cycles`, the endurance given in the data sheet of the flash
+    pub fn remaining(&self, rated_cycles: u32) -> u32 {
+        rated_cycles.saturating_sub(self.most_erased().1)
+    }
+
+    /// Erase `sector` and count it
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector` isn't less than `N`.
+    pub fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+        assert!(sector < N);
+        let addr = self.offset + sector as u32 * SECTOR_SIZE;
+        self.flash.erase(addr, addr + SECTOR_SIZE)?;
+        self.record_erase(sector)
+    }
+
+    /// Count an erase of `sector` done by other means
+    ///
+    /// Use this if the region is erased by another module, e.g. by
+    /// [`nor_flash::Flash`](crate::nor_flash::Flash).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector` isn't less than `N`.
+    pub fn record_erase(&mut self, sector: usize) -> Result<(), FlashError> {
+        self.counts[sector] = self.counts[sector].saturating_add(1);
+        self.pending += 1;
+        if self.flush_every != 0 && self.pending >= self.flush_every {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the counters to the metadata sectors now
+    ///
+    /// Does nothing if no erases were counted since the last write. Call
+    /// this e.g. before a planned shutdown.
+    pub fn flush(&mut self) -> Result<(), FlashError> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        if self.write_pos + Self::SNAPSHOT_SIZE > SECTOR_SIZE {
+            // The other sector only holds older snapshots, while the newest
+            // one stays intact until the next switch
+            let other = 1 - self.active;
+            let addr = self.meta + other * SECTOR_SIZE;
+            self.flash.erase(addr, addr + SECTOR_SIZE)?;
+            self.meta_erases = self.meta_erases.saturating_add(1);
+            self.active = other;
+            self.write_pos = 0;
+        }
+        self.seq = self.seq.wrapping_add(1);
+        let mut crc = Crc32::new();
+        for index in 0..Self::WORDS - 1 {
+            crc.update(&self.current(index).to_le_bytes());
+        }
+        let crc = crc.finish();
+        let addr = self.meta + self.active * SECTOR_SIZE + self.write_pos;
+        trace!("writing wear counters at {:#x}", addr);
+        self.write_pos += Self::SNAPSHOT_SIZE;
+        // Program page by page, starting with the magic, so a torn
+        // snapshot never looks like a free slot
+        let mut page = [0xffu8; PAGE_SIZE as usize];
+        for first in (0..Self::SNAPSHOT_SIZE / 4).step_by(PAGE_SIZE as usize / 4) {
+            for (i, bytes) in page.chunks_exact_mut(4).enumerate() {
+                let index = first + i as u32;
+                let word = match index {
+                    i if i < Self::WORDS - 1 => self.current(i),
+                    i if i == Self::WORDS - 1 => crc,
+                    _ => 0xffff_ffff,
+                };
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+            self.flash.write(addr + first * 4, &page)?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    #[test]
+    fn counts_erases_and_reloads_them() {
+        let mut wear =
+            WearTracker::<4, _>::with_flash(MockFlash::new(0x6000), 0, 0x4000, 2).unwrap();
+        for sector in [0, 1, 1, 3, 1] {
+            wear.erase(sector).unwrap();
+        }
+        assert_eq!(wear.most_erased(), (1, 3));
+        assert_eq!(wear.total_erases(), 5);
+        assert_eq!(wear.remaining(10), 7);
+        wear.flush().unwrap();
+        assert_eq!(wear.flash.erase_count(0x1000), 3);
+        let wear = WearTracker::<4, _>::with_flash(wear.flash, 0, 0x4000, 2).unwrap();
+        assert_eq!(wear.erase_count(1), 3);
+        assert_eq!(wear.erase_count(3), 1);
+    }
+
+    #[test]
+    fn alternates_between_metadata_sectors() {
+        let mut wear =
+            WearTracker::<1, _>::with_flash(MockFlash::new(0x3000), 0, 0x1000, 1).unwrap();
+        // 16 snapshots fit into a sector
+        for _ in 0..40 {
+            wear.erase(0).unwrap();
+        }
+        assert_eq!(wear.meta_erase_count(), 2);
+        assert_eq!(wear.flash.erase_count(0x1000), 1);
+        assert_eq!(wear.flash.erase_count(0x2000), 1);
+        let wear = WearTracker::<1, _>::with_flash(wear.flash, 0, 0x1000, 1).unwrap();
+        assert_eq!(wear.erase_count(0), 40);
+    }
+
+    #[test]
+    fn falls_back_to_previous_snapshot() {
+        let mut wear =
+            WearTracker::<1, _>::with_flash(MockFlash::new(0x3000), 0, 0x1000, 1).unwrap();
+        for _ in 0..33 {
+            wear.erase(0).unwrap();
+        }
+        // The newest snapshot is the only one in the first metadata sector,
+        // tear it as if power failed while it was written
+        assert_eq!((wear.active, wear.write_pos), (0, 256));
+        let mut flash = wear.flash;
+        NorFlash::write(&mut flash, 0x1000, &[0; 256]).unwrap();
+        let wear = WearTracker::<1, _>::with_flash(flash, 0, 0x1000, 1).unwrap();
+        assert_eq!(wear.erase_count(0), 32);
+        assert_eq!(wear.active, 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! Access to flash through the XIP address window
+//!
+//! Flash is mapped into the address space at several aliases, which differ
+//! in how they interact with the XIP cache (RP2040 datasheet 2.6.3.1).
+
+use crate::address::XipAddress;
+use crate::error::FlashError;
+
+/// Cached, allocating alias, used for normal code and data accesses
+pub const XIP_BASE: u32 = 0x1000_0000;
+/// Alias bypassing the cache, without allocating cache lines
+#[cfg(not(feature = "rp235x"))]
+pub const XIP_NOCACHE_NOALLOC_BASE: u32 = 0x1300_0000;
+/// Alias bypassing the cache, without allocating cache lines
+#[cfg(feature = "rp235x")]
+pub const XIP_NOCACHE_NOALLOC_BASE: u32 = 0x1400_0000;
+
+/// Size of the flash address space reachable through XIP
+pub(crate) const XIP_SIZE: u32 = 0x0100_0000;
+
+/// Size of an XIP cache line
+const CACHE_LINE: u32 = 8;
+
+/// CTRL register of XIP_CTRL
+#[cfg(not(feature = "rp235x"))]
+const XIP_CTRL_CTRL: *const u32 = 0x1400_0000 as *const u32;
+/// FLUSH register of XIP_CTRL
+#[cfg(not(feature = "rp235x"))]
+const XIP_CTRL_FLUSH: *mut u32 = 0x1400_0004 as *mut u32;
+
+/// Cache maintenance alias, writes perform the operation in bits 2:0
+#[cfg(feature = "rp235x")]
+const XIP_MAINTENANCE_BASE: u32 = 0x1800_0000;
+/// Size of the XIP cache
+#[cfg(feature = "rp235x")]
+const CACHE_SIZE: u32 = 0x4000;
+#[cfg(feature = "rp235x")]
+const OP_INVALIDATE_BY_SET_WAY: u32 = 0;
+#[cfg(feature = "rp235x")]
+const OP_CLEAN_BY_SET_WAY: u32 = 1;
+#[cfg(feature = "rp235x")]
+const OP_INVALIDATE_BY_ADDRESS: u32 = 2;
+
+/// Read flash contents at `offset` into `buf`, bypassing the XIP cache
+///
+/// `offset` is relative to the beginning of the flash area. Reads go
+/// through the uncached alias [`XIP_NOCACHE_NOALLOC_BASE`], so the result can't be
+/// affected by stale cache lines, e.g. when verifying data just
+/// programmed. The cache contents are not modified.
+///
+/// This is slower than cached reads, as each access is a separate
+/// flash transaction.
+pub fn read_uncached(offset: u32, buf: &mut [u8]) {
+    assert!(offset as usize + buf.len() <= XIP_SIZE as usize);
+    let base = (XIP_NOCACHE_NOALLOC_BASE + offset) as *const u8;
+    for (i, b) in buf.iter_mut().enumerate() {
+        // Safety: the address is inside the XIP window, which is always readable
+        *b = unsafe { core::ptr::read_volatile(base.add(i)) };
+    }
+}
+
+/// Flush the whole XIP cache
+///
+/// Afterwards, reads through [`XIP_BASE`] fetch current flash contents,
+/// e.g. after modifying flash with custom SSI commands. Unlike the ROM
+/// function `flash_flush_cache`, this can be called while XIP is active
+/// and doesn't touch the chip select.
+///
+/// On the RP2040, this uses the FLUSH register of XIP_CTRL. While the
+/// cache is disabled, its memory may be used as SRAM, which a flush would
+/// clear, so nothing is done then. On the RP2350, all lines are cleaned,
+/// writing dirty lines, e.g. of PSRAM, back, then invalidated using the
+/// cache maintenance alias.
+pub fn xip_cache_flush() {
+    // Safety: XIP_CTRL is always accessible, and reading FLUSH stalls
+    // until the flush completes
+    #[cfg(not(feature = "rp235x"))]
+    unsafe {
+        // CTRL.EN
+        if core::ptr::read_volatile(XIP_CTRL_CTRL) & 0x1 == 0 {
+            return;
+        }
+        core::ptr::write_volatile(XIP_CTRL_FLUSH, 1);
+        core::ptr::read_volatile(XIP_CTRL_FLUSH);
+    }
+    #[cfg(feature = "rp235x")]
+    for op in [OP_CLEAN_BY_SET_WAY, OP_INVALIDATE_BY_SET_WAY] {
+        for line in (0..CACHE_SIZE).step_by(CACHE_LINE as usize) {
+            // Safety: writes to the maintenance alias only write back dirty
+            // lines, they don't modify flash contents otherwise
+            unsafe { core::ptr::write_volatile((XIP_MAINTENANCE_BASE + line + op) as *mut u8, 0) };
+        }
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Invalidate the XIP cache lines covering `len` bytes at flash offset `offset`
+pub(crate) fn cache_invalidate_range(offset: u32, len: u32) {
+    assert!(offset as usize + len as usize <= XIP_SIZE as usize);
+    let start = offset & !(CACHE_LINE - 1);
+    let end = offset + len;
+    let invalidate = || {
+        for line in (start..end).step_by(CACHE_LINE as usize) {
+            // Safety: writes to the XIP window and its maintenance alias
+            // don't modify flash contents
+            #[cfg(not(feature = "rp235x"))]
+            unsafe {
+                core::ptr::write_volatile((XIP_BASE + line) as *mut u32, 0)
+            };
+            #[cfg(feature = "rp235x")]
+            unsafe {
+                core::ptr::write_volatile(
+                    (XIP_MAINTENANCE_BASE + line + OP_INVALIDATE_BY_ADDRESS) as *mut u8,
+                    0,
+                )
+            };
+        }
+    };
+    #[cfg(feature = "mpu-guard")]
+    crate::mpu_guard::unguarded(invalidate);
+    #[cfg(not(feature = "mpu-guard"))]
+    invalidate();
+}
+
+/// Invalidate the XIP cache lines covering `len` bytes at `addr`
+///
+/// The address is in the XIP window, e.g. of a `static` in flash. On the
+/// RP2040, a write to the cached, allocating alias deallocates the cache
+/// line on a tag match, without affecting flash contents (RP2040 datasheet
+/// 2.6.3.2). On the RP2350, the cache maintenance alias is used. Other
+/// cache lines are kept. Fails with [`FlashError::OutOfBounds`] if the
+/// range isn't inside the XIP window.
+pub fn xip_cache_invalidate_range(addr: XipAddress, len: u32) -> Result<(), FlashError> {
+    let offset = addr.to_offset().ok_or(FlashError::OutOfBounds)?.0;
+    if offset as usize + len as usize > XIP_SIZE as usize {
+        return Err(FlashError::OutOfBounds);
+    }
+    cache_invalidate_range(offset, len);
+    Ok(())
+}